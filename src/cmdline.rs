@@ -1,48 +1,315 @@
 use std::env;
 use std::error::Error;
+use std::fmt;
+use std::path::PathBuf;
 
-use super::reset::ResetSequence;
+use super::config::{load_settings, reset_profiles, ResetProfile, Settings};
+use super::reset::{parse_duration_ms, parse_reset_steps, GpioChipLines, ResetBackend, ResetSequence};
+
+/// What the tool does; `--info-only` / `--erase-only` of the flag form map to `Info` / `Erase`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Command {
+    Flash,
+    Info,
+    Erase,
+    Start,
+    Reset,
+    Monitor,
+    Ports,
+    Crc,
+    Decode,
+    Help,
+    Version,
+}
+
+const CONNECT_OPTIONS: &[&str] = &["-p", "--port", "-s", "--speed", "-si", "--init-speed", "-sm", "--main-speed", "--speed-cache"];
+const RESET_OPTIONS: &[&str] = &[
+    "-r", "--reset", "--reset-seq", "--reset-profile", "--reset-backend", "--reset-after", "--reset-after-always", "--gpiochip",
+];
+const SESSION_OPTIONS: &[&str] = &["--retries", "--info-timeout", "--report"];
+const MONITOR_OPTIONS: &[&str] = &["--monitor", "--monitor-baud", "--monitor-timeout"];
+const LOG_OPTIONS: &[&str] = &["--device-log", "--trace"];
+const REPLAY_OPTIONS: &[&str] = &["--replay", "--replay-fast"];
+/// Accepted by every subcommand.
+const GLOBAL_OPTIONS: &[&str] = &["-h", "--help", "--version", "--print-config", "--list-reset-profiles"];
+
+impl Command {
+    pub const NAMES: &'static [&'static str] = &["flash", "info", "erase", "start", "reset", "monitor", "ports", "crc", "decode", "help", "version"];
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "flash" => Some(Command::Flash),
+            "info" => Some(Command::Info),
+            "erase" => Some(Command::Erase),
+            "start" => Some(Command::Start),
+            "reset" => Some(Command::Reset),
+            "monitor" => Some(Command::Monitor),
+            "ports" => Some(Command::Ports),
+            "crc" => Some(Command::Crc),
+            "decode" => Some(Command::Decode),
+            "help" => Some(Command::Help),
+            "version" => Some(Command::Version),
+            _ => None,
+        }
+    }
+
+    /// Option groups of the subcommand form, besides `GLOBAL_OPTIONS`.
+    fn options(self) -> &'static [&'static [&'static str]] {
+        match self {
+            Command::Flash => &[CONNECT_OPTIONS, RESET_OPTIONS, SESSION_OPTIONS, MONITOR_OPTIONS, LOG_OPTIONS, REPLAY_OPTIONS, &["--no-prewrite"]],
+            Command::Start => &[CONNECT_OPTIONS, RESET_OPTIONS, SESSION_OPTIONS, MONITOR_OPTIONS, LOG_OPTIONS, REPLAY_OPTIONS],
+            Command::Info | Command::Erase => &[CONNECT_OPTIONS, RESET_OPTIONS, SESSION_OPTIONS, LOG_OPTIONS, REPLAY_OPTIONS],
+            Command::Reset => &[&["-p", "--port", "--gpio-read"], RESET_OPTIONS],
+            Command::Monitor => &[&["-p", "--port", "-s", "--speed", "--monitor-timeout"], RESET_OPTIONS, LOG_OPTIONS],
+            Command::Ports | Command::Crc | Command::Decode | Command::Help | Command::Version => &[],
+        }
+    }
+
+    fn accepts(self, opt: &str) -> bool {
+        GLOBAL_OPTIONS.contains(&opt) || self.options().iter().any(|group| group.contains(&opt))
+    }
+
+    /// Takes a firmware file (positional or `firmware` setting).
+    fn uses_firmware(self) -> bool {
+        matches!(self, Command::Flash | Command::Start | Command::Crc)
+    }
+
+    fn needs_port(self) -> bool {
+        !matches!(self, Command::Ports | Command::Crc | Command::Decode | Command::Help | Command::Version)
+    }
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Command::Flash => "flash",
+            Command::Info => "info",
+            Command::Erase => "erase",
+            Command::Start => "start",
+            Command::Reset => "reset",
+            Command::Monitor => "monitor",
+            Command::Ports => "ports",
+            Command::Crc => "crc",
+            Command::Decode => "decode",
+            Command::Help => "help",
+            Command::Version => "version",
+        };
+        f.write_str(name)
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct CmdConfig {
+    pub command: Command,
     pub port: String,
     pub baud_init: u32,
     pub baud_main: u32,
+    /// `--init-speed auto`: send SFU_CMD_INFO at each of `AUTO_BAUD_RATES` until
+    /// one is answered; `baud_init` is the first candidate.
+    pub init_speed_auto: bool,
+    /// `--main-speed max`: step up with SFU_CMD_SPEED while INFO bursts stay clean
+    /// (see `maxspeed.rs`); `baud_main` is `baud_init` until then.
+    pub main_speed_max: bool,
+    /// Remember the `max` result per USB serial number and start from it next time.
+    pub speed_cache: bool,
     pub firmware_path: Option<String>,
+    /// Capture files for `decode`.
+    pub captures: Vec<String>,
+
+    pub gpio_read: bool,
+    pub list_reset_profiles: bool,
+    /// Print the effective settings and where each one came from, then exit.
+    pub print_config: bool,
+    /// Configuration files the settings were read from.
+    pub config_files: Vec<PathBuf>,
+    /// Profiles of `config_files`, read for `--reset-profile` and `--list-reset-profiles`.
+    pub reset_profiles: Vec<ResetProfile>,
 
-    pub info_only: bool,
-    pub erase_only: bool,
-    
     pub no_prewrite: bool,
 
     pub reset: Option<ResetSequence>,
+    pub gpiochip: Option<GpioChipLines>,
+    pub reset_backend: ResetBackend,
+    /// Name of the reset profile the sequence came from, if any.
+    pub reset_profile: Option<String>,
+    /// Sequence run after a successful SFU_CMD_START, e.g. to release BOOT.
+    pub reset_after: Option<ResetSequence>,
+    /// Run `reset_after` even when the session failed.
+    pub reset_after_always: bool,
+
+    /// Extra attempts (re-reset + INFO) when the bootloader does not answer.
+    pub retries: u32,
+    /// How long to wait for the SFU_CMD_INFO answer per attempt, `None` = until the host timeout.
+    pub info_timeout_ms: Option<u32>,
+
+    /// Keep printing the device log after a successful SFU_CMD_START.
+    pub monitor: bool,
+    /// Application baud rate to switch to for monitoring, `None` = keep the current one.
+    pub monitor_baud: Option<u32>,
+    /// Stop monitoring after this long, `None` = until Ctrl-C.
+    pub monitor_timeout_ms: Option<u32>,
+    /// File receiving the device log lines with host timestamps.
+    pub device_log: Option<String>,
+    /// File receiving the raw wire trace (see `trace.rs`).
+    pub trace: Option<String>,
+    /// File receiving the session report as JSON (see `report.rs`).
+    pub report: Option<String>,
+    /// Run the session against this recorded trace instead of a device.
+    pub replay: Option<String>,
+    /// Replay device answers without the recorded delays.
+    pub replay_fast: bool,
+
+    /// Setting key (as in `sfu.toml`) -> where its value came from; missing = default.
+    pub origin: Vec<(&'static str, String)>,
+}
+
+impl CmdConfig {
+    /// `--init-speed auto` without a main speed: stay at the rate the bootloader answered at.
+    pub fn main_speed_follows_init(&self) -> bool {
+        self.init_speed_auto && !self.origin.iter().any(|(key, _)| *key == "main-speed")
+    }
+
+    /// Nothing to run: help text was printed, or only the version is wanted.
+    fn exit_with(command: Command) -> Self {
+        CmdConfig {
+            command,
+            port: String::new(),
+            baud_init: DEFAULT_BAUD,
+            baud_main: DEFAULT_BAUD,
+            init_speed_auto: false,
+            main_speed_max: false,
+            speed_cache: false,
+            firmware_path: None,
+            captures: Vec::new(),
+            gpio_read: false,
+            list_reset_profiles: false,
+            print_config: false,
+            config_files: Vec::new(),
+            reset_profiles: Vec::new(),
+            no_prewrite: false,
+            reset: None,
+            gpiochip: None,
+            reset_backend: ResetBackend::Auto,
+            reset_profile: None,
+            reset_after: None,
+            reset_after_always: false,
+            retries: 0,
+            info_timeout_ms: None,
+            monitor: false,
+            monitor_baud: None,
+            monitor_timeout_ms: None,
+            device_log: None,
+            trace: None,
+            report: None,
+            replay: None,
+            replay_fast: false,
+            origin: Vec::new(),
+        }
+    }
 }
 
 const DEFAULT_BAUD: u32 = 921600;
+/// Tried in this order by `--init-speed auto`: the common bootloader rates first.
+pub const AUTO_BAUD_RATES: &[u32] = &[921600, 1000000, 115200, 2000000, 460800, 230400, 500000, 57600, 1500000, 3000000, 38400, 19200, 9600];
+/// `--init-speed auto` while parsing; no real rate is 0.
+const AUTO_SPEED: u32 = 0;
+/// `--main-speed max` while parsing.
+const MAX_SPEED: u32 = u32::MAX;
+/// INFO wait per attempt when `--retries` is given without `--info-timeout`.
+const DEFAULT_INFO_TIMEOUT_MS: u32 = 3000;
 
+/// Read the config files and `SFU_*` variables, then parse the process arguments.
 pub fn parse_cmdline_from_env() -> Option<CmdConfig> {
     let args: Vec<String> = env::args().collect();
-    parse_cmdline(&args)
+    let settings = match load_settings() {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            return None;
+        }
+    };
+    parse_cmdline_with_settings(&args, &settings)
 }
 
+/// Parse `args` alone, as without configuration files and `SFU_*` variables.
+#[cfg(test)]
 pub fn parse_cmdline(args: &[String]) -> Option<CmdConfig> {
+    parse_cmdline_with_settings(args, &Settings::default())
+}
+
+/// Parse `args`; whatever the command line leaves open is taken from `settings`.
+/// Reads no files: config files and their profiles come in with `settings`.
+pub fn parse_cmdline_with_settings(args: &[String], settings: &Settings) -> Option<CmdConfig> {
+    // `sfu-cli-uploader <command> [options]`, anything else is the flag form.
+    let subcommand = args.get(1).and_then(|a| Command::parse(a));
+    if subcommand == Some(Command::Help) {
+        match args.get(2).map(|a| (a, Command::parse(a))) {
+            None => print_usage(),
+            Some((_, Some(topic))) => print_command_usage(topic),
+            Some((a, None)) => {
+                eprintln!("Error: unknown command '{a}', expected one of {}", Command::NAMES.join(", "));
+                return None;
+            }
+        }
+        return Some(CmdConfig::exit_with(Command::Help));
+    }
+
     let mut port: Option<String> = None;
     let mut baud_init: Option<u32> = None;
     let mut baud_main: Option<u32> = None;
     let mut firmware_path: Option<String> = None;
+    let mut captures: Vec<String> = Vec::new();
 
     let mut info_only = false;
     let mut erase_only = false;
+    let mut gpio_read = false;
+    let mut list_reset_profiles = false;
+    let mut print_config = false;
     let mut no_prewrite = false;
+    let mut speed_cache = false;
 
     let mut reset: Option<ResetSequence> = None;
+    let mut gpiochip: Option<GpioChipLines> = None;
+    let mut reset_backend = ResetBackend::Auto;
+    let mut cli_backend: Option<ResetBackend> = None;
+    let mut reset_profile: Option<String> = None;
+    let mut reset_steps: Option<String> = None;
+    let mut reset_after: Option<ResetSequence> = None;
+    let mut reset_after_steps: Option<String> = None;
+    let mut reset_after_always = false;
+    let mut retries: Option<u32> = None;
+    let mut info_timeout_ms: Option<u32> = None;
+    let mut monitor = false;
+    let mut monitor_baud: Option<u32> = None;
+    let mut monitor_timeout_ms: Option<u32> = None;
+    let mut device_log: Option<String> = None;
+    let mut trace: Option<String> = None;
+    let mut report: Option<String> = None;
+    let mut replay: Option<String> = None;
+    let mut replay_fast = false;
 
-    let mut i = 1; // skip program name
+    let mut i = if subcommand.is_some() { 2 } else { 1 }; // skip program name (and command)
 
     while i < args.len() {
         let arg = &args[i];
 
-        if arg == "-p" || arg == "--port" {
+        if let Some(cmd) = subcommand
+            && arg.starts_with('-')
+            && !cmd.accepts(arg)
+        {
+            eprintln!("Error: option '{arg}' is not valid for '{cmd}' (see 'sfu-cli-uploader {cmd} --help')");
+            return None;
+        }
+
+        if arg == "-h" || arg == "--help" {
+            match subcommand {
+                Some(cmd) => print_command_usage(cmd),
+                None => print_usage(),
+            }
+            return Some(CmdConfig::exit_with(Command::Help));
+        } else if arg == "--version" {
+            return Some(CmdConfig::exit_with(Command::Version));
+        } else if arg == "-p" || arg == "--port" {
             i += 1;
             if i >= args.len() {
                 eprintln!("Error: -p/--port requires an argument");
@@ -58,13 +325,13 @@ pub fn parse_cmdline(args: &[String]) -> Option<CmdConfig> {
                 print_usage();
                 return None;
             }
-            match args[i].parse::<u32>() {
+            match parse_baud(&args[i]) {
                 Ok(v) => {
                     baud_init = Some(v);
                     baud_main = Some(v);
                 }                    
                 Err(e) => {
-                    eprintln!("Error: invalid baud rate '{}': {e}", args[i]);
+                    eprintln!("Error: -s/--speed '{}': {e}", args[i]);
                     print_usage();
                     return None;
                 }
@@ -76,10 +343,10 @@ pub fn parse_cmdline(args: &[String]) -> Option<CmdConfig> {
                 print_usage();
                 return None;
             }
-            match args[i].parse::<u32>() {
+            match parse_init_speed(&args[i]) {
                 Ok(v) => baud_init = Some(v),
                 Err(e) => {
-                    eprintln!("Error: invalid baud rate '{}': {e}", args[i]);
+                    eprintln!("Error: -si/--init-speed '{}': {e}", args[i]);
                     print_usage();
                     return None;
                 }
@@ -91,10 +358,10 @@ pub fn parse_cmdline(args: &[String]) -> Option<CmdConfig> {
                 print_usage();
                 return None;
             }
-            match args[i].parse::<u32>() {
+            match parse_main_speed(&args[i]) {
                 Ok(v) => baud_main = Some(v),
                 Err(e) => {
-                    eprintln!("Error: invalid baud rate '{}': {e}", args[i]);
+                    eprintln!("Error: -sm/--main-speed '{}': {e}", args[i]);
                     print_usage();
                     return None;
                 }
@@ -103,8 +370,153 @@ pub fn parse_cmdline(args: &[String]) -> Option<CmdConfig> {
             info_only = true;
         } else if arg == "--erase-only" {
             erase_only = true;
+        } else if arg == "--gpio-read" {
+            gpio_read = true;
+        } else if arg == "--list-reset-profiles" {
+            list_reset_profiles = true;
+        } else if arg == "--print-config" {
+            print_config = true;
+        } else if arg == "--reset-profile" {
+            i += 1;
+            if i >= args.len() {
+                eprintln!("Error: --reset-profile requires a profile name");
+                print_usage();
+                return None;
+            }
+            reset_profile = Some(args[i].clone());
+        } else if arg == "--retries" {
+            i += 1;
+            if i >= args.len() {
+                eprintln!("Error: --retries requires a number");
+                print_usage();
+                return None;
+            }
+            match args[i].parse::<u32>() {
+                Ok(v) => retries = Some(v),
+                Err(e) => {
+                    eprintln!("Error: invalid retry count '{}': {e}", args[i]);
+                    print_usage();
+                    return None;
+                }
+            }
+        } else if arg == "--info-timeout" {
+            i += 1;
+            if i >= args.len() {
+                eprintln!("Error: --info-timeout requires a time, e.g. 2000 or 2s");
+                print_usage();
+                return None;
+            }
+            match parse_duration_ms(&args[i]) {
+                Ok(0) => {
+                    eprintln!("Error: --info-timeout must be greater than 0");
+                    print_usage();
+                    return None;
+                }
+                Ok(v) => info_timeout_ms = Some(v),
+                Err(e) => {
+                    eprintln!("Error: {e}");
+                    print_usage();
+                    return None;
+                }
+            }
+        } else if arg == "--device-log" {
+            i += 1;
+            if i >= args.len() {
+                eprintln!("Error: --device-log requires a file name");
+                print_usage();
+                return None;
+            }
+            device_log = Some(args[i].clone());
+        } else if arg == "--trace" {
+            i += 1;
+            if i >= args.len() {
+                eprintln!("Error: --trace requires a file name");
+                print_usage();
+                return None;
+            }
+            trace = Some(args[i].clone());
+        } else if arg == "--report" {
+            i += 1;
+            if i >= args.len() {
+                eprintln!("Error: --report requires a file name");
+                print_usage();
+                return None;
+            }
+            report = Some(args[i].clone());
+        } else if arg == "--replay" {
+            i += 1;
+            if i >= args.len() {
+                eprintln!("Error: --replay requires a trace file (recorded with --trace)");
+                print_usage();
+                return None;
+            }
+            replay = Some(args[i].clone());
+        } else if arg == "--replay-fast" {
+            replay_fast = true;
+        } else if arg == "--monitor" {
+            monitor = true;
+        } else if arg == "--monitor-baud" {
+            i += 1;
+            if i >= args.len() {
+                eprintln!("Error: --monitor-baud requires an argument");
+                print_usage();
+                return None;
+            }
+            match args[i].parse::<u32>() {
+                Ok(v) => monitor_baud = Some(v),
+                Err(e) => {
+                    eprintln!("Error: invalid baud rate '{}': {e}", args[i]);
+                    print_usage();
+                    return None;
+                }
+            }
+        } else if arg == "--monitor-timeout" {
+            i += 1;
+            if i >= args.len() {
+                eprintln!("Error: --monitor-timeout requires a time, e.g. 10s");
+                print_usage();
+                return None;
+            }
+            match parse_duration_ms(&args[i]) {
+                Ok(v) => monitor_timeout_ms = Some(v),
+                Err(e) => {
+                    eprintln!("Error: {e}");
+                    print_usage();
+                    return None;
+                }
+            }
+        } else if arg == "--reset-after" {
+            i += 1;
+            if i >= args.len() {
+                eprintln!("Error: --reset-after requires a step list, e.g. \"GPIO0=1; GPIO1=0 5ms; GPIO1=1\"");
+                print_usage();
+                return None;
+            }
+            reset_after_steps = Some(args[i].clone());
+        } else if arg == "--reset-after-always" {
+            reset_after_always = true;
+        } else if arg == "--reset-backend" {
+            i += 1;
+            match args.get(i).and_then(|s| ResetBackend::parse(s)) {
+                Some(b) => cli_backend = Some(b),
+                None => {
+                    eprintln!("Error: --reset-backend requires one of {}", ResetBackend::NAMES);
+                    print_usage();
+                    return None;
+                }
+            }
+        } else if arg == "--reset-seq" {
+            i += 1;
+            if i >= args.len() {
+                eprintln!("Error: --reset-seq requires a step list, e.g. \"GPIO0=0; GPIO1=0 5ms; GPIO1=1\"");
+                print_usage();
+                return None;
+            }
+            reset_steps = Some(args[i].clone());
         } else if arg == "--no-prewrite" {
             no_prewrite = true;
+        } else if arg == "--speed-cache" {
+            speed_cache = true;
         } else if arg == "-r" || arg == "--reset" {
             if reset.is_some() {
                 eprintln!("Error: reset sequence specified more than once");
@@ -190,20 +602,43 @@ pub fn parse_cmdline(args: &[String]) -> Option<CmdConfig> {
                 return None;
             }
 
-            reset = Some(ResetSequence {
-                quantum_ms,
-                mask,
-                values,
-            });
+            reset = Some(ResetSequence::from_values(quantum_ms, mask, &values));
 
             // continue loop without i += 1 here, т.к. мы его уже сдвигали внутри
             continue;
+        } else if arg == "--gpiochip" {
+            if i + 2 >= args.len() {
+                eprintln!("Error: --gpiochip requires a chip and a list of line offsets");
+                print_usage();
+                return None;
+            }
+            match parse_offsets(&args[i + 2]).and_then(|offsets| GpioChipLines::new(&args[i + 1], offsets)) {
+                Ok(lines) => gpiochip = Some(lines),
+                Err(e) => {
+                    eprintln!("Error: --gpiochip: {e}");
+                    print_usage();
+                    return None;
+                }
+            }
+            i += 2;
         } else if arg.starts_with('-') {
             eprintln!("Error: unknown option '{}'", arg);
             print_usage();
             return None;
         } else {
-            // Positional argument: firmware file path
+            // Positional argument: firmware file path (capture files for `decode`)
+            if subcommand == Some(Command::Decode) {
+                captures.push(arg.clone());
+                i += 1;
+                continue;
+            }
+            if let Some(cmd) = subcommand
+                && !cmd.uses_firmware()
+            {
+                eprintln!("Error: '{cmd}' takes no firmware file ('{arg}')");
+                print_command_usage(cmd);
+                return None;
+            }
             if firmware_path.is_some() {
                 eprintln!("Error: multiple firmware file paths specified ('{}' and '{}')",
                           firmware_path.as_ref().unwrap(), arg);
@@ -216,37 +651,382 @@ pub fn parse_cmdline(args: &[String]) -> Option<CmdConfig> {
         i += 1;
     }
 
+    let command = match subcommand {
+        Some(cmd) => cmd,
+        None if info_only => Command::Info,
+        None if erase_only => Command::Erase,
+        None => Command::Flash,
+    };
+
+    // Settings from sfu.toml / SFU_* variables fill in what the command line left open.
+    let special_mode = !command.uses_firmware() || gpio_read;
+    let mut origin: Vec<(&'static str, String)> = Vec::new();
+    let mut no_prewrite = no_prewrite.then_some(true);
+    let mut reset_after_always = reset_after_always.then_some(true);
+    let mut monitor = monitor.then_some(true);
+    let mut speed_cache = speed_cache.then_some(true);
+    if reset.is_some() || reset_steps.is_some() {
+        note_origin(&mut origin, "reset-seq", "command line".into());
+    }
+    if reset_profile.is_some() {
+        note_origin(&mut origin, "reset-profile", "command line".into());
+    }
+    // A reset given on the command line replaces the configured one as a whole.
+    let cli_reset = reset.is_some() || reset_steps.is_some() || reset_profile.is_some();
+
+    let merged = merge(&mut port, "port", settings, &mut origin, |v| Ok(normalize_port(v)))
+        && merge(&mut baud_init, "init-speed", settings, &mut origin, parse_init_speed)
+        && merge(&mut baud_main, "main-speed", settings, &mut origin, parse_main_speed)
+        && merge(&mut speed_cache, "speed-cache", settings, &mut origin, parse_bool)
+        && (special_mode || merge(&mut firmware_path, "firmware", settings, &mut origin, |v| Ok(v.to_string())))
+        && merge(&mut no_prewrite, "no-prewrite", settings, &mut origin, parse_bool)
+        && (cli_reset || merge(&mut reset_profile, "reset-profile", settings, &mut origin, |v| Ok(v.to_string())))
+        && (cli_reset || merge(&mut reset_steps, "reset-seq", settings, &mut origin, |v| Ok(v.to_string())))
+        && merge(&mut cli_backend, "reset-backend", settings, &mut origin, |v| {
+            ResetBackend::parse(v).ok_or_else(|| format!("expected one of {}", ResetBackend::NAMES))
+        })
+        && merge(&mut reset_after_steps, "reset-after", settings, &mut origin, |v| Ok(v.to_string()))
+        && merge(&mut reset_after_always, "reset-after-always", settings, &mut origin, parse_bool)
+        && merge(&mut retries, "retries", settings, &mut origin, |v| v.parse::<u32>().map_err(|e| e.to_string()))
+        && merge(&mut info_timeout_ms, "info-timeout", settings, &mut origin, |v| match parse_duration_ms(v)? {
+            0 => Err("must be greater than 0".into()),
+            ms => Ok(ms),
+        })
+        && merge(&mut device_log, "device-log", settings, &mut origin, |v| Ok(v.to_string()))
+        && merge(&mut trace, "trace", settings, &mut origin, |v| Ok(v.to_string()))
+        && merge(&mut report, "report", settings, &mut origin, |v| Ok(v.to_string()))
+        && merge(&mut monitor, "monitor", settings, &mut origin, parse_bool)
+        && merge(&mut monitor_baud, "monitor-baud", settings, &mut origin, parse_baud)
+        && merge(&mut monitor_timeout_ms, "monitor-timeout", settings, &mut origin, parse_duration_ms)
+        && merge(&mut gpiochip, "gpiochip", settings, &mut origin, |v| {
+            let lines = settings.get("lines").ok_or("requires 'lines' (line offsets)")?;
+            GpioChipLines::new(v, parse_offsets(&lines.value)?)
+        });
+    if !merged {
+        return None;
+    }
+    // 'speed' sets both rates, 'init-speed' / 'main-speed' win over it.
+    if let Some(speed) = settings.get("speed") {
+        let v = match parse_baud(&speed.value) {
+            Ok(v) => v,
+            Err(e) => {
+                eprintln!("Error: {}: speed: {e}", speed.source);
+                return None;
+            }
+        };
+        for (key, baud) in [("init-speed", &mut baud_init), ("main-speed", &mut baud_main)] {
+            if baud.is_none() {
+                *baud = Some(v);
+                note_origin(&mut origin, key, speed.source.clone());
+            }
+        }
+    }
+    let no_prewrite = no_prewrite.unwrap_or(false);
+    let reset_after_always = reset_after_always.unwrap_or(false);
+    let retries = retries.unwrap_or(0);
+    // Only flash / start have a START to monitor after; the monitor command always monitors.
+    let monitor = monitor.unwrap_or(false) && matches!(command, Command::Flash | Command::Start);
+    let speed_cache = speed_cache.unwrap_or(false);
+    if speed_cache && baud_main != Some(MAX_SPEED) {
+        eprintln!("Error: --speed-cache requires --main-speed max");
+        print_usage();
+        return None;
+    }
+    if monitor_baud.is_some() && !monitor {
+        // A configured rate only applies to sessions that monitor.
+        if origin.iter().any(|(key, source)| *key == "monitor-baud" && source == "command line") {
+            eprintln!("Error: --monitor-baud requires --monitor");
+            print_usage();
+            return None;
+        }
+        origin.retain(|(key, _)| *key != "monitor-baud");
+        monitor_baud = None;
+    }
+
+    if let Some(b) = cli_backend
+        && gpiochip.is_some()
+        && b != ResetBackend::GpioChip
+        && b != ResetBackend::Auto
+    {
+        eprintln!("Error: --gpiochip cannot be combined with --reset-backend {b}");
+        print_usage();
+        return None;
+    }
+
+    if reset.is_some() && reset_steps.is_some() {
+        eprintln!("Error: --reset and --reset-seq are mutually exclusive");
+        print_usage();
+        return None;
+    }
+
+    // Listed from the same files the settings came from.
+    let profiles = if reset_profile.is_some() || list_reset_profiles {
+        match reset_profiles(&settings.files) {
+            Ok(p) => p,
+            Err(e) => {
+                eprintln!("Error: {e}");
+                return None;
+            }
+        }
+    } else {
+        Vec::new()
+    };
+
+    if let Some(name) = &reset_profile {
+        if reset.is_some() {
+            eprintln!("Error: --reset and --reset-profile are mutually exclusive");
+            print_usage();
+            return None;
+        }
+        let profile = match profiles.iter().find(|p| &p.name == name).cloned() {
+            Some(p) => p,
+            None => {
+                eprintln!("Error: reset profile '{name}' not found (see --list-reset-profiles)");
+                return None;
+            }
+        };
+        // Only what no setting overrides below is noted as coming from the profile.
+        let from = format!("reset profile {name}");
+        note_origin(&mut origin, "reset-seq", from.clone());
+        if profile.after.is_some() {
+            note_origin(&mut origin, "reset-after", from.clone());
+        }
+        if profile.backend != ResetBackend::Auto {
+            note_origin(&mut origin, "reset-backend", from.clone());
+        }
+        if profile.gpiochip.is_some() {
+            note_origin(&mut origin, "gpiochip", from);
+        }
+        reset = Some(profile.sequence);
+        reset_after = profile.after;
+        reset_backend = profile.backend;
+        if gpiochip.is_none() {
+            gpiochip = profile.gpiochip;
+        }
+    }
+
+    // Step list on the command line; pin labels come from the profile, if any.
+    if let Some(text) = &reset_steps {
+        let labels = reset.take().map(|seq| seq.labels).unwrap_or_default();
+        match parse_reset_steps(text, &labels) {
+            Ok(steps) => reset = Some(ResetSequence { steps, labels, classic: false }),
+            Err(e) => {
+                eprintln!("Error: invalid --reset-seq: {e}");
+                print_usage();
+                return None;
+            }
+        }
+    }
+
+    if let Some(text) = &reset_after_steps {
+        let labels = reset.as_ref().map(|seq| seq.labels.clone()).unwrap_or_default();
+        match parse_reset_steps(text, &labels) {
+            Ok(steps) => reset_after = Some(ResetSequence { steps, labels, classic: false }),
+            Err(e) => {
+                eprintln!("Error: invalid --reset-after: {e}");
+                print_usage();
+                return None;
+            }
+        }
+    }
+    if reset_after_always && reset_after.is_none() {
+        eprintln!("Error: --reset-after-always requires --reset-after (or a profile with 'after')");
+        print_usage();
+        return None;
+    }
+
+    // Command line backend wins over the profile's.
+    if let Some(b) = cli_backend {
+        reset_backend = b;
+    }
+
+    // Explicit host GPIO lines select the gpiochip backend.
+    if gpiochip.is_some() && reset_backend == ResetBackend::Auto {
+        reset_backend = ResetBackend::GpioChip;
+    }
+    if reset_backend == ResetBackend::GpioChip && gpiochip.is_none() {
+        eprintln!("Error: reset backend gpiochip requires --gpiochip <CHIP> <OFFSETS>");
+        print_usage();
+        return None;
+    }
+
+    // --print-config and --list-reset-profiles only print, nothing else is needed.
+    let listing = print_config || list_reset_profiles;
+
     // Check mandatory firmware file depending on context
-    let special_mode = info_only || erase_only;
-    if firmware_path.is_none() && !special_mode {
-        eprintln!("Error: firmware file is required unless --info-only/--erase-only is specified");
+    let needs_firmware = matches!(command, Command::Flash | Command::Crc) && !gpio_read;
+    if firmware_path.is_none() && needs_firmware && !listing {
+        match subcommand {
+            Some(cmd) => {
+                eprintln!("Error: '{cmd}' requires a firmware file");
+                print_command_usage(cmd);
+            }
+            None => {
+                eprintln!("Error: firmware file is required unless --info-only/--erase-only/--gpio-read is specified");
+                print_usage();
+            }
+        }
+        return None;
+    }
+
+    if command == Command::Decode && captures.is_empty() {
+        eprintln!("Error: 'decode' requires at least one capture file");
+        print_command_usage(command);
+        return None;
+    }
+
+    if command == Command::Reset && reset.is_none() && !gpio_read && !listing {
+        eprintln!("Error: 'reset' requires -r/--reset, --reset-seq or --reset-profile");
+        print_command_usage(command);
+        return None;
+    }
+
+    if replay_fast && replay.is_none() {
+        eprintln!("Error: --replay-fast requires --replay");
         print_usage();
         return None;
     }
+    if replay.as_deref().is_some_and(|path| path.to_ascii_lowercase().ends_with(".pcapng")) {
+        eprintln!("Error: --replay needs a text trace, pcapng files are for Wireshark only");
+        return None;
+    }
+    if replay.is_some() && trace.is_some() {
+        // A configured trace is meant for real sessions, a replay just goes without it.
+        if origin.iter().any(|(key, source)| *key == "trace" && source == "command line") {
+            eprintln!("Error: --trace records a real port, it cannot be used with --replay");
+            return None;
+        }
+        origin.retain(|(key, _)| *key != "trace");
+        trace = None;
+    }
 
     // Check mandatory firmware file depending on context
-    if port.is_none() {
+    if port.is_none() && command.needs_port() && replay.is_none() && !listing {
         eprintln!("Error: serial port is required (with -p/--port)");
-        print_usage();
+        match subcommand {
+            Some(cmd) => print_command_usage(cmd),
+            None => print_usage(),
+        }
         return None;
     }
 
-    let port = port.unwrap();
-    let baud_init = baud_init.unwrap_or(DEFAULT_BAUD);
-    let baud_main = baud_main.unwrap_or(baud_init);
+    let port = port.unwrap_or_default();
+    let init_speed_auto = baud_init == Some(AUTO_SPEED);
+    let baud_init = if init_speed_auto { AUTO_BAUD_RATES[0] } else { baud_init.unwrap_or(DEFAULT_BAUD) };
+    let main_speed_max = baud_main == Some(MAX_SPEED);
+    let baud_main = if main_speed_max { baud_init } else { baud_main.unwrap_or(baud_init) };
 
     Some(CmdConfig {
+        command,
         port,
         baud_init,
         baud_main,
+        init_speed_auto,
+        main_speed_max,
+        speed_cache,
         firmware_path,
-        info_only,
-        erase_only,
+        captures,
+        gpio_read,
+        list_reset_profiles,
+        print_config,
+        config_files: settings.files.iter().map(|file| file.path.clone()).collect(),
+        reset_profiles: profiles,
         no_prewrite,
         reset,
+        gpiochip,
+        reset_backend,
+        reset_profile,
+        reset_after,
+        reset_after_always,
+        retries,
+        info_timeout_ms: info_timeout_ms.or(if retries > 0 { Some(DEFAULT_INFO_TIMEOUT_MS) } else { None }),
+        monitor,
+        monitor_baud,
+        // 0 = no timeout, as when the option is missing.
+        monitor_timeout_ms: monitor_timeout_ms.filter(|&ms| ms > 0),
+        device_log,
+        trace,
+        report,
+        replay,
+        replay_fast,
+        origin,
     })
 }
 
+/// Note where `key` came from, unless a source that wins over `source` is noted already.
+fn note_origin(origin: &mut Vec<(&'static str, String)>, key: &'static str, source: String) {
+    if !origin.iter().any(|(k, _)| *k == key) {
+        origin.push((key, source));
+    }
+}
+
+/// Take `key` from `settings` if the command line did not set `value`, and note where it came from.
+/// Prints the error and returns false if the setting does not parse.
+fn merge<T>(
+    value: &mut Option<T>,
+    key: &'static str,
+    settings: &Settings,
+    origin: &mut Vec<(&'static str, String)>,
+    parse: impl FnOnce(&str) -> Result<T, String>,
+) -> bool {
+    if value.is_some() {
+        note_origin(origin, key, "command line".into());
+        return true;
+    }
+    let Some(setting) = settings.get(key) else {
+        return true;
+    };
+    match parse(&setting.value) {
+        Ok(v) => {
+            *value = Some(v);
+            note_origin(origin, key, setting.source.clone());
+            true
+        }
+        Err(e) => {
+            eprintln!("Error: {}: invalid {key} '{}': {e}", setting.source, setting.value);
+            false
+        }
+    }
+}
+
+fn parse_baud(s: &str) -> Result<u32, String> {
+    match s.parse::<u32>() {
+        Ok(0) => Err("invalid baud rate: must be greater than 0".into()),
+        Ok(v) => Ok(v),
+        Err(e) => Err(format!("invalid baud rate: {e}")),
+    }
+}
+
+/// A baud rate or `auto` (kept as `AUTO_SPEED` until the end of parsing).
+fn parse_init_speed(s: &str) -> Result<u32, String> {
+    if s.eq_ignore_ascii_case("auto") { Ok(AUTO_SPEED) } else { parse_baud(s) }
+}
+
+/// A baud rate or `max` (kept as `MAX_SPEED` until the end of parsing).
+fn parse_main_speed(s: &str) -> Result<u32, String> {
+    if s.eq_ignore_ascii_case("max") { Ok(MAX_SPEED) } else { parse_baud(s) }
+}
+
+fn parse_bool(s: &str) -> Result<bool, String> {
+    match s.to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Ok(true),
+        "0" | "false" | "no" | "off" => Ok(false),
+        _ => Err("expected true or false".into()),
+    }
+}
+
+/// "17,27" -> GPIO line offsets, at most 16 (one per reset mask bit).
+fn parse_offsets(s: &str) -> Result<Vec<u32>, String> {
+    let mut offsets = Vec::new();
+    for part in s.split(',') {
+        match part.trim().parse::<u32>() {
+            Ok(v) => offsets.push(v),
+            Err(e) => return Err(format!("invalid GPIO line offset '{part}': {e}")),
+        }
+    }
+    Ok(offsets)
+}
+
 fn parse_bin_or_hex(s: &str) -> Result<u32, Box<dyn Error>> {
     let s = s.trim();
 
@@ -310,37 +1090,400 @@ fn normalize_port(raw: &str) -> String {
     }
 }
 
-fn print_usage() {
-    const GIT_HASH: &str = env!("GIT_HASH");
-    const BUILD_TIME: &str = env!("BUILD_TIME");
-    const BUILD_PROFILE: &str = env!("BUILD_PROFILE");
+const GIT_HASH: &str = env!("GIT_HASH");
+const BUILD_TIME: &str = env!("BUILD_TIME");
+const BUILD_PROFILE: &str = env!("BUILD_PROFILE");
+
+pub fn print_version() {
+    println!("sfu-cli-uploader {} (commit {GIT_HASH}, built {BUILD_TIME}, {BUILD_PROFILE})", env!("CARGO_PKG_VERSION"));
+}
+
+/// Help for one subcommand: what it does, its arguments and which option groups it takes.
+fn print_command_usage(cmd: Command) {
+    let (args, about) = match cmd {
+        Command::Flash => ("[options] <firmware_file>", "Reset the board, erase, upload <firmware_file> and start it"),
+        Command::Info => ("[options]", "Query the bootloader's SFU_CMD_INFO (device ID, flash size, SFU version)"),
+        Command::Erase => ("[options]", "Erase the whole application flash"),
+        Command::Start => (
+            "[options] [firmware_file]",
+            "Run the firmware already in flash; with a file its CRC32 is sent for the bootloader to compare",
+        ),
+        Command::Reset => ("[options]", "Only run the reset sequence (and --reset-after), no bootloader session"),
+        Command::Monitor => ("[options]", "Run the reset (and --reset-after) if given, then print the device log until interrupted"),
+        Command::Ports => ("", "List serial ports (with USB VID:PID, product and serial number)"),
+        Command::Crc => ("<firmware_file>", "Print size and CRC32_SFU of a firmware file as the device computes it"),
+        Command::Decode => (
+            "<capture>...",
+            "Decode raw UART byte streams or logic analyzer CSV exports: frames of both directions and log text",
+        ),
+        Command::Help => ("[command]", "Show help for a command"),
+        Command::Version => ("", "Print the tool version"),
+    };
+    let mut text = format!("Usage:\n  sfu-cli-uploader {cmd} {args}\n\n  {about}\n");
+    let groups = cmd.options();
+    if groups.contains(&CONNECT_OPTIONS) {
+        text += "
+Connection:
+  -p, --port <PORT>        Serial port name (e.g. COM5, /dev/ttyUSB0)
+  -s, --speed <BAUD>       Baud rate for both speeds";
+        text += &format!(", default {DEFAULT_BAUD}\n");
+        text += "  -si, --init-speed <BAUD> Baud rate for initialization, 'auto' tries the common rates
+  -sm, --main-speed <BAUD> Baud rate for uploading (switched with SFU_CMD_SPEED),
+                           'max' steps up while the link stays clean
+  --speed-cache            Remember the 'max' result per USB serial number
+";
+    } else if cmd.accepts("-s") {
+        text += &format!("\nConnection:\n  -p, --port <PORT>        Serial port name\n  -s, --speed <BAUD>       Baud rate, default {DEFAULT_BAUD}\n");
+    } else if cmd.accepts("-p") {
+        text += "\nConnection:\n  -p, --port <PORT>        Serial port name\n";
+    }
+    if cmd.accepts("--gpio-read") {
+        text += "  --gpio-read              Print the CP210x GPIO latch state instead\n";
+    }
+    if groups.contains(&RESET_OPTIONS) {
+        text += &format!(
+            "
+Reset:
+  -r, --reset <T> <MASK> <VAL> [VAL ...]
+  --reset-seq \"<STEPS>\"    e.g. \"GPIO0=0; GPIO1=0 5ms; GPIO1=1 200ms; GPIO0=1\"
+  --reset-profile <NAME>   Named profile from sfu.toml
+  --reset-backend <NAME>   {}, default auto
+  --reset-after \"<STEPS>\"  Sequence run after a successful start
+  --reset-after-always     Run --reset-after also after a failure
+  --gpiochip <CHIP> <OFFSETS>
+",
+            ResetBackend::NAMES
+        );
+    }
+    if groups.contains(&SESSION_OPTIONS) {
+        text += &format!(
+            "
+Session:
+  --retries <N>            Re-run the reset if SFU_CMD_INFO gets no answer (default 0)
+  --info-timeout <TIME>    Wait per attempt for SFU_CMD_INFO (default {DEFAULT_INFO_TIMEOUT_MS} ms with --retries)
+  --report <FILE>          Write the session report (phase timing, counters) to FILE as JSON
+"
+        );
+    }
+    if cmd.accepts("--monitor") {
+        text += "
+Monitor:
+  --monitor                Keep printing the device log after the firmware started
+  --monitor-baud <BAUD>    Switch to the application's baud rate for monitoring
+  --monitor-timeout <TIME> Stop monitoring after TIME (default: until Ctrl-C)
+";
+    } else if cmd.accepts("--monitor-timeout") {
+        text += "  --monitor-timeout <TIME> Stop after TIME (default: until Ctrl-C)\n";
+    }
+    if cmd.accepts("--device-log") {
+        text += "  --device-log <FILE>      Also write the device log lines to FILE\n";
+        text += "  --trace <FILE>           Record every byte sent and received to FILE (FILE.pcapng: decoded frames)\n";
+    }
+    if cmd.accepts("--replay") {
+        text += "  --replay <TRACE>         Play a --trace recording back instead of talking to a device\n";
+        text += "  --replay-fast            Replay without the recorded delays\n";
+    }
+    if cmd.accepts("--no-prewrite") {
+        text += "  --no-prewrite            Do not send data while the erase is in progress\n";
+    }
+    eprintln!("{text}\n  --print-config           Print the effective settings and exit\n  -h, --help               This help");
+}
 
+fn print_usage() {
     eprintln!(
         r#"Usage:
-  sfu-cli-uploader [options] <firmware_file>
+  sfu-cli-uploader <command> [options]
+  sfu-cli-uploader [options] <firmware_file>     (flag form, same as 'flash')
+
+Commands:
+  flash     Reset, erase, upload and start a firmware file
+  info      Query bootloader info
+  erase     Erase the application flash
+  start     Run the firmware already in flash
+  reset     Only run the reset sequence
+  monitor   Print the device log
+  ports     List serial ports
+  crc       Print CRC32_SFU of a firmware file
+  decode    Decode UART captures (raw bytes or CSV) offline
+  help      'help <command>' or '<command> --help' shows the command's options
+  version   Print the tool version
 
 Options:
   -p, --port <PORT>        Serial port name (e.g. COM5, /dev/ttyUSB0)
   -s, --speed <BAUD>       Baud rate (decimal) for booth speeds I/M, default {DEFAULT_BAUD} bod
   -si, --init-speed <BAUD> Baud rate (decimal) for Initialization,  default {DEFAULT_BAUD} bod
+                           'auto': send SFU_CMD_INFO at common rates until the bootloader answers
   -sm, --main-speed <BAUD> Baud rate (decimal) for Main uploading, default {DEFAULT_BAUD} bod
+                           'max': find the fastest clean rate with SFU_CMD_SPEED and INFO bursts
+  --speed-cache            Keep the 'max' result per USB serial number for the next run
 
   --info-only             Query device info only, no firmware file required
   --erase-only            Erase only, no firmware file required
   --no-prewrite           Disabling sending data for writing while erasing is in progress
+  --gpio-read             Print current CP210x GPIO latch state and exit (no firmware file required)
 
   -r, --reset <T> <MASK> <VAL> [VAL ...]
       T       - GPIO quantum time, decimal (e.g. 50 = 50 ms)
       MASK    - GPIO mask, binary (0b...) or hex (0x... or plain hex, default hex)
       VAL...  - at least two GPIO values, each in binary or hex (same rules)      
 
+  --reset-seq "<STEPS>"
+      Reset sequence with per-step timing, steps separated by ';':
+      PIN=0|1 ... [TIME]  - PIN is DTR, RTS, BREAK, GPIO0..GPIO15 or a profile pin label
+      TIME                - hold time after the step, e.g. 5ms or 2s (default 0)
+      wait TIME           - only wait
+      e.g. "GPIO0=0; GPIO1=0 5ms; GPIO1=1 200ms; GPIO0=1"
+
+  --retries <N>           If the bootloader does not answer SFU_CMD_INFO, re-run the reset sequence
+                          and try again up to N more times (default 0)
+  --info-timeout <TIME>   Wait for the SFU_CMD_INFO answer per attempt, e.g. 2000 or 2s
+                          (default {DEFAULT_INFO_TIMEOUT_MS} ms with --retries, otherwise until the host timeout)
+  --report <FILE>         Write the session report (phase timing, throughput, counters) to FILE as JSON
+
+  --device-log <FILE>     Write the device log lines (with host timestamps) to FILE
+  --trace <FILE>          Record every byte sent and received (with us timestamps and baud changes) to FILE;
+                          a FILE ending in .pcapng gets one packet per SFU frame and log run for Wireshark
+  --replay <TRACE>        Run the session against a --trace recording instead of a device: the device side
+                          is played back, host frames are checked against the recording (exit code 16 if not)
+  --replay-fast           Replay without the recorded delays
+
+  --monitor               After a successful start keep the port open and print the device log
+                          until Ctrl-C or --monitor-timeout
+  --monitor-baud <BAUD>   Switch the port to the application's baud rate for --monitor
+  --monitor-timeout <TIME> Stop monitoring after TIME, e.g. 30s (default: until Ctrl-C)
+
+  --reset-after "<STEPS>" Sequence (same syntax) run after a successful start, e.g. to release BOOT
+  --reset-after-always    Run the --reset-after sequence also when the upload failed
+
+  --reset-backend <NAME>  Reset backend: {backends}, default auto
+                          auto   - CP210x latch / FTDI CBUS if the adapter has one, else DTR/RTS
+                          others - use only that backend, fail with exit code 4 if it is not usable
+                          The adapter behind the port is found through sysfs on Linux; on other
+                          Unix systems (macOS) a latch is only used with a single CP210x/FTDI attached
+
+  --reset-profile <NAME>  Use a named reset profile (backend, sequence, pin labels)
+                          from sfu.toml in the current directory or its parents, or the user config
+  --list-reset-profiles   List reset profiles found in the configuration files and exit
+  --print-config          Print the effective settings and where each one comes from, then exit
+  --version               Print the tool version and exit
+  -h, --help              Print this help and exit
+
+  Every option above can also be set in sfu.toml (root keys named like the long options, e.g.
+  port = "COM5", main-speed = 2000000, firmware = "build/app.bin") or in SFU_* environment
+  variables (SFU_PORT, SFU_MAIN_SPEED, SFU_FIRMWARE, ...). Command line > environment > sfu.toml.
+
+  --gpiochip <CHIP> <OFFSETS>
+      Run the reset sequence on host GPIO lines (Linux /dev/gpiochipN) instead of the adapter
+      CHIP    - gpiochip device, e.g. /dev/gpiochip0, gpiochip0 or 0
+      OFFSETS - comma-separated line offsets, N-th offset is driven by MASK bit N
+
 Examples:
+  sfu-cli-uploader flash -p COM5 -s 1000000 firmware.bin
+  sfu-cli-uploader info -p /dev/ttyUSB0 --reset-profile rp2040-cp2102n
+  sfu-cli-uploader crc firmware.bin
   sfu-cli-uploader -p COM5 -s 1000000 firmware.bin
   sfu-cli-uploader --port /dev/ttyUSB0 --info-only
   sfu-cli-uploader -p COM3 -r 50 0x0003 0b01 0b10 0b00 --erase-only
+  sfu-cli-uploader -p /dev/ttyUSB0 --gpio-read
+  sfu-cli-uploader -p COM5 --reset-profile rp2040-cp2102n firmware.bin
+  sfu-cli-uploader -p /dev/ttyUSB0 --reset-seq "DTR=0 RTS=1 10ms; RTS=0 BREAK=1 50ms; BREAK=0" firmware.bin
+  sfu-cli-uploader -p COM5 -r 1 3 2 0 2 --reset-after "GPIO0=1; GPIO1=0 5ms; GPIO1=1" firmware.bin
+  sfu-cli-uploader -p /dev/ttyAMA0 -r 50 3 0b01 0b00 0b01 0b11 --gpiochip 0 17,27 firmware.bin
   
   commit: {}
   build:  {} ({})
-"#, GIT_HASH, BUILD_TIME, BUILD_PROFILE
+"#, GIT_HASH, BUILD_TIME, BUILD_PROFILE, backends = ResetBackend::NAMES
     );
 }
+
+// ---- Unit tests ----
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ConfigFile;
+    use crate::testutil::args;
+    use std::path::{Path, PathBuf};
+
+    fn parse(line: &str) -> Option<CmdConfig> {
+        parse_cmdline(&args(&line.split_whitespace().collect::<Vec<_>>()))
+    }
+
+    #[test]
+    fn reset_backend_selection() {
+        let cfg = parse("-p ttyUSB0 --info-only -r 1 3 2 0 2").unwrap();
+        assert_eq!(cfg.reset_backend, ResetBackend::Auto);
+        let cfg = parse("-p ttyUSB0 --info-only -r 1 3 2 0 2 --reset-backend dtr-rts").unwrap();
+        assert_eq!(cfg.reset_backend, ResetBackend::DtrRts);
+        let cfg = parse("-p ttyUSB0 --info-only -r 1 3 2 0 2 --gpiochip 0 17,27").unwrap();
+        assert_eq!(cfg.reset_backend, ResetBackend::GpioChip);
+
+        assert!(parse("-p ttyUSB0 --info-only --reset-backend usb").is_none());
+        assert!(parse("-p ttyUSB0 --info-only --reset-backend gpiochip").is_none());
+        assert!(parse("-p ttyUSB0 --info-only --reset-backend cp210x --gpiochip 0 17").is_none());
+    }
+
+    #[test]
+    fn reset_after_sequence() {
+        let cfg = parse("-p ttyUSB0 fw.bin --reset-after GPIO0=1;GPIO1=0;GPIO1=1 --reset-after-always").unwrap();
+        assert_eq!(cfg.reset_after.unwrap().steps.len(), 3);
+        assert!(cfg.reset_after_always);
+        assert!(parse("-p ttyUSB0 fw.bin --reset-after-always").is_none());
+    }
+
+    #[test]
+    fn init_speed_auto() {
+        let cfg = parse("-p ttyUSB0 fw.bin -si auto").unwrap();
+        assert!(cfg.init_speed_auto && cfg.main_speed_follows_init());
+        assert_eq!(cfg.baud_init, AUTO_BAUD_RATES[0]);
+        let cfg = parse("-p ttyUSB0 fw.bin -si AUTO -sm 2000000").unwrap();
+        assert!(cfg.init_speed_auto && !cfg.main_speed_follows_init());
+        assert_eq!(cfg.baud_main, 2000000);
+        assert!(!parse("-p ttyUSB0 fw.bin -si 115200").unwrap().init_speed_auto);
+        assert!(parse("-p ttyUSB0 fw.bin -s 0").is_none());
+        assert!(parse("-p ttyUSB0 fw.bin -s auto").is_none());
+    }
+
+    #[test]
+    fn main_speed_max() {
+        let cfg = parse("-p ttyUSB0 fw.bin -si 115200 -sm max --speed-cache").unwrap();
+        assert!(cfg.main_speed_max && cfg.speed_cache);
+        assert_eq!((cfg.baud_init, cfg.baud_main), (115200, 115200));
+        assert!(!parse("-p ttyUSB0 fw.bin -sm 2000000").unwrap().main_speed_max);
+        assert!(parse("-p ttyUSB0 fw.bin -sm 2000000 --speed-cache").is_none());
+        assert!(parse("-p ttyUSB0 fw.bin -s max").is_none());
+    }
+
+    #[test]
+    fn retries_and_info_timeout() {
+        let cfg = parse("-p ttyUSB0 fw.bin").unwrap();
+        assert_eq!((cfg.retries, cfg.info_timeout_ms), (0, None));
+        let cfg = parse("-p ttyUSB0 fw.bin --retries 3").unwrap();
+        assert_eq!((cfg.retries, cfg.info_timeout_ms), (3, Some(DEFAULT_INFO_TIMEOUT_MS)));
+        let cfg = parse("-p ttyUSB0 fw.bin --info-timeout 2s").unwrap();
+        assert_eq!((cfg.retries, cfg.info_timeout_ms), (0, Some(2000)));
+        assert!(parse("-p ttyUSB0 fw.bin --info-timeout 0").is_none());
+    }
+
+    #[test]
+    fn settings_fill_in_missing_options() {
+        let file = ConfigFile::parse(
+            Path::new("/work/sfu.toml"),
+            "port = \"ttyUSB1\"\nspeed = 115200\nmain-speed = 2000000\nretries = 2\nreset-seq = \"DTR=1 5ms; DTR=0\"",
+        )
+        .unwrap();
+        let settings = Settings::build(vec![file], |name| (name == "SFU_RETRIES").then(|| "4".to_string())).unwrap();
+
+        let cfg = parse_cmdline_with_settings(&args(&["--info-only"]), &settings).unwrap();
+        assert!(cfg.port.ends_with("ttyUSB1"));
+        assert_eq!((cfg.baud_init, cfg.baud_main, cfg.retries), (115200, 2000000, 4));
+        assert_eq!(cfg.reset.unwrap().steps.len(), 2);
+        assert!(cfg.origin.contains(&("retries", "env SFU_RETRIES".to_string())));
+        assert!(cfg.origin.contains(&("init-speed", "/work/sfu.toml:2".to_string())));
+
+        let cfg = parse_cmdline_with_settings(&args(&["--info-only", "-p", "ttyUSB2", "-s", "9600", "--retries", "0", "-r", "1", "3", "2", "0"]), &settings).unwrap();
+        assert!(cfg.port.ends_with("ttyUSB2"));
+        assert_eq!((cfg.baud_init, cfg.baud_main, cfg.retries), (9600, 9600, 0));
+        assert_eq!(cfg.reset.unwrap().gpio_mask(), 3);
+        assert!(cfg.origin.contains(&("port", "command line".to_string())));
+
+        let bad = Settings::build(Vec::new(), |name| (name == "SFU_SPEED").then(|| "fast".to_string())).unwrap();
+        assert!(parse_cmdline_with_settings(&args(&["--info-only", "-p", "ttyUSB0"]), &bad).is_none());
+    }
+
+    #[test]
+    fn subcommands_and_flag_form() {
+        assert_eq!(parse("-p ttyUSB0 fw.bin").unwrap().command, Command::Flash);
+        assert_eq!(parse("-p ttyUSB0 --info-only").unwrap().command, Command::Info);
+        assert_eq!(parse("--erase-only -p ttyUSB0").unwrap().command, Command::Erase);
+
+        let cfg = parse("flash -p ttyUSB0 --no-prewrite fw.bin").unwrap();
+        assert_eq!((cfg.command, cfg.firmware_path.as_deref(), cfg.no_prewrite), (Command::Flash, Some("fw.bin"), true));
+        assert_eq!(parse("info -p ttyUSB0 --retries 2").unwrap().command, Command::Info);
+        assert_eq!(parse("start -p ttyUSB0").unwrap().command, Command::Start);
+        assert_eq!(parse("reset -p ttyUSB0 --reset-seq DTR=1;DTR=0").unwrap().command, Command::Reset);
+        assert_eq!(parse("ports").unwrap().command, Command::Ports);
+        assert_eq!(parse("crc fw.bin").unwrap().firmware_path.as_deref(), Some("fw.bin"));
+        assert_eq!(parse("decode tx.bin rx.csv").unwrap().captures, ["tx.bin", "rx.csv"]);
+        assert_eq!(parse("--version").unwrap().command, Command::Version);
+        assert_eq!(parse("info --help").unwrap().command, Command::Help);
+
+        assert!(parse("flash -p ttyUSB0").is_none());
+        assert!(parse("info -p ttyUSB0 fw.bin").is_none());
+        assert!(parse("info -p ttyUSB0 --no-prewrite").is_none());
+        assert!(parse("erase -p ttyUSB0 --info-only").is_none());
+        assert!(parse("reset -p ttyUSB0").is_none());
+        assert!(parse("crc").is_none());
+        assert!(parse("decode").is_none());
+        assert_eq!(parse("erase -p ttyUSB0 --report out.json").unwrap().report.as_deref(), Some("out.json"));
+        assert!(parse("monitor -p ttyUSB0 --report out.json").is_none());
+        assert!(parse("decode -p ttyUSB0 cap.csv").is_none());
+        assert!(parse("help bogus").is_none());
+    }
+
+    #[test]
+    fn monitor_options() {
+        let cfg = parse("-p ttyUSB0 fw.bin --monitor --monitor-baud 115200 --monitor-timeout 10s").unwrap();
+        assert_eq!((cfg.monitor, cfg.monitor_baud, cfg.monitor_timeout_ms), (true, Some(115200), Some(10000)));
+        let cfg = parse("monitor -p ttyUSB0 -s 115200 --monitor-timeout 0 --device-log dev.log --trace wire.txt").unwrap();
+        assert_eq!(cfg.trace.as_deref(), Some("wire.txt"));
+        assert_eq!((cfg.command, cfg.baud_init, cfg.monitor_timeout_ms), (Command::Monitor, 115200, None));
+        assert_eq!(cfg.device_log.as_deref(), Some("dev.log"));
+        assert!(!parse("-p ttyUSB0 --info-only --monitor").unwrap().monitor);
+        assert!(parse("-p ttyUSB0 fw.bin --monitor-baud 115200").is_none());
+        assert!(parse("info -p ttyUSB0 --monitor").is_none());
+    }
+
+    #[test]
+    fn profile_origin_and_listing_follow_the_merge() {
+        let file = ConfigFile::parse(
+            Path::new("/work/sfu.toml"),
+            "reset-profile = \"a\"\nreset-seq = \"DTR=1 5ms; DTR=0\"\nreset-after = \"RTS=1\"\n\
+             [reset-profiles.a]\nbackend = \"dtr-rts\"\nsequence = \"RTS=1 10ms; RTS=0\"\nafter = \"DTR=0\"",
+        )
+        .unwrap();
+        let config = Settings::build(vec![file], |_| None).unwrap();
+        let cfg = parse_cmdline_with_settings(&args(&["info", "-p", "ttyUSB0"]), &config).unwrap();
+        for key in ["reset-profile", "reset-seq", "reset-after", "reset-backend"] {
+            assert_eq!(cfg.origin.iter().filter(|(k, _)| *k == key).count(), 1, "{key}");
+        }
+        let source = |key: &str| cfg.origin.iter().find(|(k, _)| *k == key).map(|(_, s)| s.as_str());
+        assert_eq!(source("reset-seq"), Some("/work/sfu.toml:2"));
+        assert_eq!(source("reset-backend"), Some("reset profile a"));
+        assert_eq!(cfg.reset.unwrap().to_string(), "DTR=1 5ms; DTR=0");
+
+        let cfg = parse_cmdline_with_settings(&args(&["--list-reset-profiles"]), &config).unwrap();
+        assert_eq!(cfg.config_files, [PathBuf::from("/work/sfu.toml")]);
+        assert_eq!(cfg.reset_profiles.iter().map(|p| p.name.as_str()).collect::<Vec<_>>(), ["a"]);
+        assert_eq!(cfg.reset_profile.as_deref(), Some("a"));
+    }
+
+    #[test]
+    fn configured_monitor_baud_without_monitor_is_ignored() {
+        let env = Settings::build(Vec::new(), |name| (name == "SFU_MONITOR_BAUD").then(|| "115200".to_string())).unwrap();
+        let cfg = parse_cmdline_with_settings(&args(&["info", "-p", "ttyUSB0"]), &env).unwrap();
+        assert_eq!(cfg.monitor_baud, None);
+        assert!(!cfg.origin.iter().any(|(key, _)| *key == "monitor-baud"));
+        let cfg = parse_cmdline_with_settings(&args(&["-p", "ttyUSB0", "fw.bin", "--monitor"]), &env).unwrap();
+        assert_eq!(cfg.monitor_baud, Some(115200));
+
+        let file = ConfigFile::parse(Path::new("/work/sfu.toml"), "monitor = true\nmonitor-baud = 115200").unwrap();
+        let config = Settings::build(vec![file], |_| None).unwrap();
+        assert_eq!(parse_cmdline_with_settings(&args(&["erase", "-p", "ttyUSB0"]), &config).unwrap().monitor_baud, None);
+        let cfg = parse_cmdline_with_settings(&args(&["flash", "-p", "ttyUSB0", "fw.bin"]), &config).unwrap();
+        assert_eq!((cfg.monitor, cfg.monitor_baud), (true, Some(115200)));
+    }
+
+    #[test]
+    fn replay_needs_no_port() {
+        let cfg = parse("flash --replay wire.txt --replay-fast fw.bin").unwrap();
+        assert_eq!((cfg.replay.as_deref(), cfg.replay_fast), (Some("wire.txt"), true));
+        assert!(parse("info --replay wire.txt").unwrap().port.is_empty());
+        assert!(parse("flash -p ttyUSB0 --replay-fast fw.bin").is_none());
+        assert!(parse("monitor --replay wire.txt").is_none());
+        assert!(parse("info --replay wire.pcapng").is_none());
+        assert!(parse("flash --replay wire.txt --trace again.txt fw.bin").is_none());
+        let settings = Settings::build(Vec::new(), |name| (name == "SFU_TRACE").then(|| "wire.txt".to_string())).unwrap();
+        assert_eq!(parse_cmdline_with_settings(&args(&["info", "--replay", "old.txt"]), &settings).unwrap().trace, None);
+    }
+}
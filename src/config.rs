@@ -0,0 +1,518 @@
+//! Configuration files (`sfu.toml`).
+//!
+//! Files are looked up in the user config directory and in the current
+//! directory and its parents; the nearest project file wins over the user file.
+//! Root keys mirror the command line options (see `SETTING_KEYS`), `SFU_*`
+//! environment variables override the files, command line flags override both.
+//! Unknown keys are errors, a typo must not be silently ignored.
+//!
+//! ```toml
+//! port = "/dev/ttyUSB0"
+//! main-speed = 2000000
+//! firmware = "build/app.bin"      # relative to this file
+//! reset-profile = "rp2040-cp2102n"
+//!
+//! [reset-profiles.rp2040-cp2102n]
+//! description = "RP2040 board, CP2102N GPIO0 = BOOT, GPIO1 = RUN"
+//! backend = "cp210x"
+//! quantum = 1
+//! mask = 0x03
+//! values = [0x02, 0x00, 0x02]
+//! labels = ["BOOT", "RUN"]
+//!
+//! [reset-profiles.stm32-dtr-rts]
+//! backend = "dtr-rts"
+//! sequence = "DTR=1 RTS=1 10ms; RTS=0 100ms; DTR=0"
+//! after = "DTR=0 RTS=1 10ms; RTS=0"
+//! ```
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::de::{self, Deserializer, MapAccess, Visitor};
+use serde::Deserialize;
+use toml::Spanned;
+
+use super::reset::{parse_reset_steps, GpioChipLines, ResetBackend, ResetSequence};
+
+pub const CONFIG_FILE_NAME: &str = "sfu.toml";
+const APP_DIR_NAME: &str = "sfu-cli-uploader";
+const SPEED_CACHE_FILE_NAME: &str = "speed-cache";
+
+/// One root key with the line it is on, for `--print-config`.
+#[derive(Debug, Clone)]
+pub struct ConfigEntry {
+    pub key: String,
+    pub value: toml::Value,
+    pub line: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct ConfigFile {
+    pub path: PathBuf,
+    /// Root keys, all of them in `SETTING_KEYS`.
+    pub settings: Vec<ConfigEntry>,
+    /// `[reset-profiles.NAME]` tables, by name.
+    pub profiles: BTreeMap<String, ProfileTable>,
+}
+
+impl ConfigFile {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {e}", path.display()))?;
+        Self::parse(path, &text).map_err(|e| format!("{}: {e}", path.display()))
+    }
+
+    /// Parse `text` as the file at `path`; errors start with `line N:`.
+    pub fn parse(path: &Path, text: &str) -> Result<Self, String> {
+        let line_of = |offset: usize| text[..offset].matches('\n').count() + 1;
+        let doc: ConfigDoc = toml::from_str(text).map_err(|e| match e.span() {
+            Some(span) => format!("line {}: {}", line_of(span.start), e.message()),
+            None => e.message().to_string(),
+        })?;
+        let settings = doc
+            .settings
+            .into_iter()
+            .map(|(key, value)| ConfigEntry {
+                key,
+                line: line_of(value.span().start),
+                value: value.into_inner(),
+            })
+            .collect();
+        Ok(ConfigFile {
+            path: path.to_path_buf(),
+            settings,
+            profiles: doc.profiles,
+        })
+    }
+
+    pub fn get(&self, key: &str) -> Option<&ConfigEntry> {
+        self.settings.iter().find(|e| e.key == key)
+    }
+}
+
+/// The document root: known setting keys with their spans, and the profile tables.
+/// Spelled out by hand because `#[serde(flatten)]` would drop the spans.
+struct ConfigDoc {
+    settings: Vec<(String, Spanned<toml::Value>)>,
+    profiles: BTreeMap<String, ProfileTable>,
+}
+
+impl<'de> Deserialize<'de> for ConfigDoc {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct RootVisitor;
+
+        impl<'de> Visitor<'de> for RootVisitor {
+            type Value = ConfigDoc;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a table of settings")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<ConfigDoc, A::Error> {
+                let mut doc = ConfigDoc {
+                    settings: Vec::new(),
+                    profiles: BTreeMap::new(),
+                };
+                while let Some(key) = map.next_key::<String>()? {
+                    if key == RESET_PROFILE_TABLE {
+                        doc.profiles = map.next_value()?;
+                    } else if SETTING_KEYS.iter().any(|(k, _)| *k == key) {
+                        let value = map.next_value()?;
+                        doc.settings.push((key, value));
+                    } else {
+                        return Err(de::Error::custom(format!("unknown key '{key}'")));
+                    }
+                }
+                Ok(doc)
+            }
+        }
+
+        deserializer.deserialize_map(RootVisitor)
+    }
+}
+
+/// Existing config files, lowest priority first (user file, then project files
+/// from the outermost directory down to the current one).
+pub fn config_file_paths() -> Vec<PathBuf> {
+    let mut out = Vec::new();
+
+    if let Some(dir) = user_config_dir() {
+        let path = dir.join(APP_DIR_NAME).join(CONFIG_FILE_NAME);
+        if path.is_file() {
+            out.push(path);
+        }
+    }
+
+    if let Ok(cwd) = env::current_dir() {
+        let mut project: Vec<PathBuf> = cwd
+            .ancestors()
+            .map(|dir| dir.join(CONFIG_FILE_NAME))
+            .filter(|path| path.is_file())
+            .collect();
+        project.reverse();
+        for path in project {
+            if !out.contains(&path) {
+                out.push(path);
+            }
+        }
+    }
+
+    out
+}
+
+/// Where `--speed-cache` keeps the `--main-speed max` results.
+pub fn speed_cache_path() -> Option<PathBuf> {
+    user_config_dir().map(|dir| dir.join(APP_DIR_NAME).join(SPEED_CACHE_FILE_NAME))
+}
+
+fn user_config_dir() -> Option<PathBuf> {
+    #[cfg(windows)]
+    {
+        env::var_os("APPDATA").map(PathBuf::from)
+    }
+    #[cfg(unix)]
+    {
+        env::var_os("XDG_CONFIG_HOME")
+            .filter(|v| !v.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+    }
+}
+
+pub fn load_config_files() -> Result<Vec<ConfigFile>, String> {
+    config_file_paths().iter().map(|p| ConfigFile::load(p)).collect()
+}
+
+// ---- Reset profiles ----
+
+pub const RESET_PROFILE_TABLE: &str = "reset-profiles";
+
+/// One `[reset-profiles.NAME]` table as written; checked by `ResetProfile::from_table`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ProfileTable {
+    #[serde(default)]
+    description: String,
+    backend: Option<String>,
+    quantum: Option<u32>,
+    mask: Option<u16>,
+    values: Option<Vec<u16>>,
+    sequence: Option<String>,
+    after: Option<String>,
+    #[serde(default)]
+    labels: Vec<String>,
+    gpiochip: Option<String>,
+    lines: Option<Vec<u32>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ResetProfile {
+    pub name: String,
+    pub description: String,
+    pub backend: ResetBackend,
+    pub sequence: ResetSequence,
+    /// Sequence run after a successful start (`after`), e.g. to release BOOT.
+    pub after: Option<ResetSequence>,
+    pub gpiochip: Option<GpioChipLines>,
+    /// File the profile was read from.
+    pub source: PathBuf,
+}
+
+impl ResetProfile {
+    fn from_table(name: &str, table: &ProfileTable, source: &Path) -> Result<Self, String> {
+        let backend = match &table.backend {
+            Some(s) => ResetBackend::parse(s).ok_or_else(|| format!("unknown backend '{s}' (expected {})", ResetBackend::NAMES))?,
+            None => ResetBackend::Auto,
+        };
+        let labels = table.labels.clone();
+        if labels.len() > 16 {
+            return Err("'labels' names at most 16 pins".into());
+        }
+
+        let after = match &table.after {
+            Some(text) => {
+                let steps = parse_reset_steps(text, &labels).map_err(|e| format!("'after': {e}"))?;
+                Some(ResetSequence { steps, labels: labels.clone(), classic: false })
+            }
+            None => None,
+        };
+
+        let sequence = match &table.sequence {
+            Some(text) => {
+                if table.quantum.is_some() || table.mask.is_some() || table.values.is_some() {
+                    return Err("'sequence' replaces 'quantum', 'mask' and 'values', use one form".into());
+                }
+                let steps = parse_reset_steps(text, &labels).map_err(|e| format!("'sequence': {e}"))?;
+                ResetSequence { steps, labels, classic: false }
+            }
+            None => {
+                let quantum_ms = table.quantum.ok_or("'quantum' (ms) or 'sequence' is required")?;
+                let mask = table.mask.ok_or("'mask' is required")?;
+                let values = table.values.as_deref().ok_or("'values' is required")?;
+                if values.len() < 2 {
+                    return Err("'values' requires at least two GPIO values".into());
+                }
+                ResetSequence {
+                    labels,
+                    ..ResetSequence::from_values(quantum_ms, mask, values)
+                }
+            }
+        };
+
+        let gpiochip = match &table.gpiochip {
+            Some(chip) => {
+                let offsets = table.lines.clone().ok_or("'lines' is required with 'gpiochip'")?;
+                Some(GpioChipLines::new(chip, offsets).map_err(|e| format!("'lines': {e}"))?)
+            }
+            None => None,
+        };
+        if backend == ResetBackend::GpioChip && gpiochip.is_none() {
+            return Err("backend \"gpiochip\" requires 'gpiochip' and 'lines'".into());
+        }
+
+        Ok(ResetProfile {
+            name: name.to_string(),
+            description: table.description.clone(),
+            backend,
+            sequence,
+            after,
+            gpiochip,
+            source: source.to_path_buf(),
+        })
+    }
+}
+
+/// All `[reset-profiles.NAME]` tables; a project profile replaces a user profile of the same name.
+pub fn reset_profiles(files: &[ConfigFile]) -> Result<Vec<ResetProfile>, String> {
+    let mut out: Vec<ResetProfile> = Vec::new();
+    for file in files {
+        for (name, table) in &file.profiles {
+            let profile = ResetProfile::from_table(name, table, &file.path)
+                .map_err(|e| format!("{}: [{RESET_PROFILE_TABLE}.{name}]: {e}", file.path.display()))?;
+            out.retain(|p| p.name != profile.name);
+            out.push(profile);
+        }
+    }
+    Ok(out)
+}
+
+// ---- Uploader settings ----
+
+/// Root keys of `sfu.toml` and the environment variables overriding them.
+/// Values use the syntax of the matching command line option.
+pub const SETTING_KEYS: &[(&str, &str)] = &[
+    ("port", "SFU_PORT"),
+    ("speed", "SFU_SPEED"),
+    ("init-speed", "SFU_INIT_SPEED"),
+    ("main-speed", "SFU_MAIN_SPEED"),
+    ("speed-cache", "SFU_SPEED_CACHE"),
+    ("firmware", "SFU_FIRMWARE"),
+    ("no-prewrite", "SFU_NO_PREWRITE"),
+    ("reset-profile", "SFU_RESET_PROFILE"),
+    ("reset-seq", "SFU_RESET_SEQ"),
+    ("reset-backend", "SFU_RESET_BACKEND"),
+    ("reset-after", "SFU_RESET_AFTER"),
+    ("reset-after-always", "SFU_RESET_AFTER_ALWAYS"),
+    ("retries", "SFU_RETRIES"),
+    ("info-timeout", "SFU_INFO_TIMEOUT"),
+    ("device-log", "SFU_DEVICE_LOG"),
+    ("trace", "SFU_TRACE"),
+    ("report", "SFU_REPORT"),
+    ("monitor", "SFU_MONITOR"),
+    ("monitor-baud", "SFU_MONITOR_BAUD"),
+    ("monitor-timeout", "SFU_MONITOR_TIMEOUT"),
+    ("gpiochip", "SFU_GPIOCHIP"),
+    ("lines", "SFU_GPIOCHIP_LINES"),
+];
+
+/// One setting as text, with the file or variable it came from.
+#[derive(Debug, Clone)]
+pub struct Setting {
+    pub value: String,
+    pub source: String,
+}
+
+/// Settings from config files and environment, below the command line.
+#[derive(Debug, Clone, Default)]
+pub struct Settings {
+    pub files: Vec<ConfigFile>,
+    values: Vec<(&'static str, Setting)>,
+}
+
+impl Settings {
+    pub fn get(&self, key: &str) -> Option<&Setting> {
+        self.values.iter().find(|(k, _)| *k == key).map(|(_, s)| s)
+    }
+
+    fn set(&mut self, key: &'static str, setting: Setting) {
+        self.values.retain(|(k, _)| *k != key);
+        self.values.push((key, setting));
+    }
+
+    /// Merge `files` (lowest priority first), then variables from `var`.
+    pub fn build(files: Vec<ConfigFile>, var: impl Fn(&str) -> Option<String>) -> Result<Self, String> {
+        let mut settings = Settings::default();
+
+        for file in &files {
+            for &(key, _) in SETTING_KEYS {
+                let Some(entry) = file.get(key) else {
+                    continue;
+                };
+                let mut value = setting_text(entry).map_err(|e| format!("{}: {e}", file.path.display()))?;
+                // A project file names files relative to itself, not to the shell's cwd.
+                if matches!(key, "firmware" | "device-log" | "trace" | "report")
+                    && Path::new(&value).is_relative()
+                    && let Some(dir) = file.path.parent()
+                {
+                    value = dir.join(&value).to_string_lossy().into_owned();
+                }
+                let source = format!("{}:{}", file.path.display(), entry.line);
+                settings.set(key, Setting { value, source });
+            }
+        }
+
+        for &(key, var_name) in SETTING_KEYS {
+            if let Some(value) = var(var_name).filter(|v| !v.is_empty()) {
+                settings.set(key, Setting { value, source: format!("env {var_name}") });
+            }
+        }
+
+        settings.files = files;
+        Ok(settings)
+    }
+}
+
+/// TOML value -> command line syntax: integers in decimal, arrays comma-separated.
+fn setting_text(entry: &ConfigEntry) -> Result<String, String> {
+    let expected = |got: &toml::Value| {
+        format!("line {}: '{}' must be a string, integer, boolean or list, got {}", entry.line, entry.key, got.type_str())
+    };
+    match &entry.value {
+        toml::Value::String(s) => Ok(s.clone()),
+        toml::Value::Integer(v) => Ok(v.to_string()),
+        toml::Value::Boolean(b) => Ok(b.to_string()),
+        toml::Value::Array(items) => {
+            let parts: Result<Vec<String>, String> = items
+                .iter()
+                .map(|v| match v {
+                    toml::Value::Integer(v) => Ok(v.to_string()),
+                    toml::Value::String(s) => Ok(s.clone()),
+                    other => Err(expected(other)),
+                })
+                .collect();
+            Ok(parts?.join(","))
+        }
+        other => Err(expected(other)),
+    }
+}
+
+pub fn load_settings() -> Result<Settings, String> {
+    Settings::build(load_config_files()?, |name| env::var(name).ok())
+}
+
+// ---- Unit tests ----
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(path: &str, text: &str) -> ConfigFile {
+        ConfigFile::parse(Path::new(path), text).unwrap()
+    }
+
+    #[test]
+    fn parse_settings_profiles_and_lines() {
+        let text = r#"
+            # top comment
+            port = "COM5"   # trailing
+            speed = 1_000_000
+
+            [reset-profiles."board.a"]
+            mask = 0x03
+            values = [0b10, 0,
+                      2]   # multi-line
+            labels = ['BOOT', "RUN # not a comment"]
+        "#;
+        let file = parse("sfu.toml", text);
+        let port = file.get("port").unwrap();
+        assert_eq!((&port.value, port.line), (&toml::Value::String("COM5".into()), 3));
+        assert_eq!(file.get("speed").unwrap().value, toml::Value::Integer(1_000_000));
+
+        let t = &file.profiles["board.a"];
+        assert_eq!(t.mask, Some(3));
+        assert_eq!(t.values.as_deref(), Some(&[2, 0, 2][..]));
+        assert_eq!(t.labels, ["BOOT", "RUN # not a comment"]);
+    }
+
+    #[test]
+    fn parse_errors_carry_line_numbers() {
+        let err = |text: &str| ConfigFile::parse(Path::new("sfu.toml"), text).unwrap_err();
+        assert!(err("a = 1\na = 2").starts_with("line 2:"));
+        assert!(err("port = [1, 2").starts_with("line 1:"));
+        assert!(err("[reset-profiles.a]\nmask = 1.5").starts_with("line 2:"));
+        assert!(err("[reset-profiles.a]\nmask = 0x10000").starts_with("line 2:"));
+        assert!(err("[reset-profiles.a]\n[reset-profiles.a]").starts_with("line 2:"));
+        assert!(err("retries = 1\nprot = \"COM1\"").contains("unknown key 'prot'"));
+        assert!(err("[reset-profiles.b]\nquantum = 1\nvalue = [1, 0]").contains("unknown field `value`"));
+    }
+
+    #[test]
+    fn settings_layering() {
+        let user = parse("/home/u/.config/sfu-cli-uploader/sfu.toml", "port = \"COM1\"\nspeed = 115200\nretries = 1");
+        let project = parse("/work/board/sfu.toml", "port = \"/dev/ttyUSB0\"\nfirmware = \"build/app.bin\"\nlines = [17, 27]");
+        let settings = Settings::build(vec![user, project], |name| {
+            (name == "SFU_RETRIES").then(|| "3".to_string())
+        })
+        .unwrap();
+
+        let port = settings.get("port").unwrap();
+        assert_eq!((port.value.as_str(), port.source.as_str()), ("/dev/ttyUSB0", "/work/board/sfu.toml:1"));
+        assert_eq!(settings.get("speed").unwrap().value, "115200");
+        assert_eq!(settings.get("retries").unwrap().source, "env SFU_RETRIES");
+        assert_eq!(settings.get("retries").unwrap().value, "3");
+        assert_eq!(settings.get("lines").unwrap().value, "17,27");
+        assert_eq!(
+            PathBuf::from(&settings.get("firmware").unwrap().value),
+            Path::new("/work/board").join("build/app.bin")
+        );
+
+        let bad = parse("sfu.toml", "port = 1.5");
+        assert!(Settings::build(vec![bad], |_| None).unwrap_err().contains("'port' must be a string"));
+    }
+
+    #[test]
+    fn reset_profiles_override_by_name() {
+        let user = parse("user/sfu.toml", "[reset-profiles.a]\nquantum = 5\nmask = 1\nvalues = [1, 0]");
+        let project = parse(
+            "project/sfu.toml",
+            "[reset-profiles.a]\nbackend = \"dtr-rts\"\nquantum = 1\nmask = 3\nvalues = [2, 0, 2]\nlabels = [\"BOOT\", \"RUN\"]",
+        );
+        let profiles = reset_profiles(&[user, project]).unwrap();
+        assert_eq!(profiles.len(), 1);
+        let p = &profiles[0];
+        assert_eq!(p.backend, ResetBackend::DtrRts);
+        assert_eq!(p.sequence.to_string(), "GPIO0=0 GPIO1=1 1ms; GPIO0=0 GPIO1=0 1ms; GPIO0=0 GPIO1=1 1ms");
+        assert_eq!(p.sequence.labels, vec!["BOOT".to_string(), "RUN".to_string()]);
+        assert_eq!(p.source, PathBuf::from("project/sfu.toml"));
+
+        let bad = parse("sfu.toml", "[reset-profiles.b]\nquantum = 1\nmask = 1");
+        assert_eq!(reset_profiles(&[bad]).unwrap_err(), "sfu.toml: [reset-profiles.b]: 'values' is required");
+
+        let steps = parse("sfu.toml", "[reset-profiles.c]\nlabels = [\"BOOT\"]\nsequence = \"BOOT=0 5ms; BREAK=1 20ms; BOOT=1\"");
+        let p = &reset_profiles(&[steps]).unwrap()[0];
+        assert_eq!(p.sequence.steps.len(), 3);
+        assert_eq!(p.sequence.steps[1].brk, Some(true));
+
+        let chip = |lines: &str| {
+            let text = format!("[reset-profiles.d]\nbackend = \"gpiochip\"\ngpiochip = \"0\"\n{lines}\nsequence = \"GPIO0=1\"");
+            reset_profiles(&[parse("sfu.toml", &text)])
+        };
+        let lines = chip("lines = [17, 27]").unwrap()[0].gpiochip.clone().unwrap();
+        assert_eq!((lines.chip.as_str(), lines.offsets), ("/dev/gpiochip0", vec![17, 27]));
+        assert!(chip("lines = []").unwrap_err().contains("no GPIO line offsets"));
+        assert!(chip("").unwrap_err().contains("'lines' is required"));
+    }
+}
@@ -0,0 +1,353 @@
+//! Offline decoder for UART captures (`decode` subcommand).
+//!
+//! A capture is either a raw byte stream (one UART line, any file not ending
+//! in `.csv`) or a logic analyzer CSV export with a header row: the columns
+//! whose names contain `time` and `data` (or `value`) are used, a `name` or
+//! `channel` column splits the rows into one stream per line, and rows whose
+//! `type` is not `data` (framing errors etc.) are skipped. Values may be hex
+//! (`0x45`), decimal or a character (`'E'`, `'\n'`, `","`).
+//!
+//! Each stream's direction is the signature it contains most often
+//! (`PACKET_SIGN_TX` host -> device, `PACKET_SIGN_RX` device -> host); it is
+//! then cut into frames and log text by a recording `PacketParser`. Streams
+//! with timestamps are merged in time order, raw streams follow each other
+//! and are located by byte offset.
+
+use std::fs;
+
+use super::packet::{PacketParser, PacketParserExt, StreamItem, PACKET_SIGN_RX, PACKET_SIGN_TX};
+use super::protocol::{command_name, parse_erase_info, parse_sfu_info, parse_speed_info, parse_start_info, parse_write_info, SpeedInfo};
+use super::protocol::{SFU_CMD_ERASE, SFU_CMD_ERASE_PART, SFU_CMD_INFO, SFU_CMD_SPEED, SFU_CMD_START, SFU_CMD_WRITE};
+
+/// Bytes of one UART line, with capture time in seconds if the format has one.
+#[derive(Debug)]
+pub struct CaptureStream {
+    pub label: String,
+    pub bytes: Vec<u8>,
+    pub times: Option<Vec<f64>>,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Direction {
+    HostToDevice,
+    DeviceToHost,
+}
+
+impl Direction {
+    fn arrow(self) -> &'static str {
+        match self {
+            Direction::HostToDevice => "host>dev",
+            Direction::DeviceToHost => "dev>host",
+        }
+    }
+}
+
+/// Where an item ends (frames) or starts (log text) in its stream.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Position {
+    Time(f64),
+    Offset(usize),
+}
+
+struct Decoded {
+    position: Position,
+    direction: Direction,
+    item: StreamItem,
+}
+
+pub fn load_capture(path: &str) -> Result<Vec<CaptureStream>, String> {
+    if path.to_ascii_lowercase().ends_with(".csv") {
+        let text = fs::read_to_string(path).map_err(|e| format!("{path}: {e}"))?;
+        parse_csv(path, &text).map_err(|e| format!("{path}: {e}"))
+    } else {
+        let bytes = fs::read(path).map_err(|e| format!("{path}: {e}"))?;
+        Ok(vec![CaptureStream { label: path.to_string(), bytes, times: None }])
+    }
+}
+
+fn parse_csv(path: &str, text: &str) -> Result<Vec<CaptureStream>, String> {
+    let mut lines = text.lines().enumerate().filter(|(_, l)| !l.trim().is_empty());
+    let Some((_, header)) = lines.next() else {
+        return Err("empty CSV file".into());
+    };
+    let header: Vec<String> = csv_fields(header).iter().map(|h| h.to_ascii_lowercase()).collect();
+    let column = |names: &[&str]| header.iter().position(|h| names.iter().any(|n| h.contains(n)));
+    let time_col = column(&["time"]);
+    let Some(data_col) = column(&["data", "value"]) else {
+        return Err(format!("no 'data' or 'value' column in header {header:?}"));
+    };
+    let channel_col = header.iter().position(|h| h == "name" || h.contains("channel"));
+    let type_col = header.iter().position(|h| h == "type");
+
+    let mut streams: Vec<CaptureStream> = Vec::new();
+    for (n, line) in lines {
+        let line_no = n + 1;
+        let fields = csv_fields(line);
+        let field = |col: usize| fields.get(col).map(String::as_str).unwrap_or("");
+        if type_col.is_some_and(|col| !field(col).eq_ignore_ascii_case("data")) {
+            continue;
+        }
+        let byte = parse_csv_byte(field(data_col)).ok_or_else(|| format!("line {line_no}: invalid data value '{}'", field(data_col)))?;
+        let time = match time_col {
+            Some(col) => Some(field(col).parse::<f64>().map_err(|e| format!("line {line_no}: invalid time '{}': {e}", field(col)))?),
+            None => None,
+        };
+        let label = match channel_col {
+            Some(col) => format!("{path}:{}", field(col)),
+            None => path.to_string(),
+        };
+
+        let stream = match streams.iter().position(|s| s.label == label) {
+            Some(i) => &mut streams[i],
+            None => {
+                streams.push(CaptureStream { label, bytes: Vec::new(), times: time.map(|_| Vec::new()) });
+                streams.last_mut().unwrap()
+            }
+        };
+        stream.bytes.push(byte);
+        if let (Some(times), Some(t)) = (&mut stream.times, time) {
+            times.push(t);
+        }
+    }
+    Ok(streams)
+}
+
+/// Split one CSV line; double quotes group a field (`""` is a quote).
+fn csv_fields(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                fields.last_mut().unwrap().push('"');
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(String::new()),
+            _ => fields.last_mut().unwrap().push(c),
+        }
+    }
+    fields.iter().map(|f| f.trim().to_string()).collect()
+}
+
+fn parse_csv_byte(value: &str) -> Option<u8> {
+    if let Some(hex) = value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        return u8::from_str_radix(hex, 16).ok();
+    }
+    if let Some(c) = value.strip_prefix('\'').and_then(|v| v.strip_suffix('\'')) {
+        return match c {
+            "\\n" => Some(b'\n'),
+            "\\r" => Some(b'\r'),
+            "\\t" => Some(b'\t'),
+            "\\0" => Some(0),
+            "\\\\" => Some(b'\\'),
+            _ if c.len() == 1 => Some(c.as_bytes()[0]),
+            _ => c.strip_prefix("\\x").and_then(|h| u8::from_str_radix(h, 16).ok()),
+        };
+    }
+    match value.as_bytes() {
+        // A bare character, e.g. a comma the exporter had to put in double quotes.
+        [c] if !c.is_ascii_digit() => Some(*c),
+        _ => value.parse::<u8>().ok(),
+    }
+}
+
+/// The direction whose frame signature the stream contains most often.
+pub fn detect_direction(bytes: &[u8]) -> Direction {
+    let count = |sign: u32| bytes.windows(4).filter(|w| *w == sign.to_be_bytes()).count();
+    if count(PACKET_SIGN_TX) > count(PACKET_SIGN_RX) {
+        Direction::HostToDevice
+    } else {
+        Direction::DeviceToHost
+    }
+}
+
+fn decode_stream(stream: &CaptureStream, direction: Direction) -> Vec<Decoded> {
+    let sign = match direction {
+        Direction::HostToDevice => PACKET_SIGN_TX,
+        Direction::DeviceToHost => PACKET_SIGN_RX,
+    };
+    let position = |i: usize| match &stream.times {
+        Some(times) => Position::Time(times[i]),
+        None => Position::Offset(i),
+    };
+    let mut parser = PacketParser::recording(sign);
+    let mut out = Vec::new();
+    let mut log_start = None;
+    for (i, &b) in stream.bytes.iter().enumerate() {
+        parser.receive_byte(b);
+        let Some(items) = &mut parser.stream else {
+            break;
+        };
+        // A log run at the back may still grow; it is complete once something follows it.
+        while let Some(item) = items.front() {
+            let at = match item {
+                StreamItem::Log(_) if items.len() == 1 => {
+                    log_start.get_or_insert(position(i));
+                    break;
+                }
+                StreamItem::Log(_) => log_start.take().unwrap_or(position(i)),
+                StreamItem::Frame { .. } => position(i),
+            };
+            out.push(Decoded { position: at, direction, item: items.pop_front().unwrap() });
+        }
+    }
+    if let Some(items) = &mut parser.stream {
+        for item in items.drain(..) {
+            let at = log_start.take().unwrap_or(position(stream.bytes.len().saturating_sub(1)));
+            out.push(Decoded { position: at, direction, item });
+        }
+    }
+    out
+}
+
+/// Decode all streams of all captures into printable lines, followed by a per-stream summary.
+pub fn decode_captures(streams: &[CaptureStream]) -> Vec<String> {
+    let mut decoded = Vec::new();
+    let mut summary = Vec::new();
+    for stream in streams {
+        let direction = detect_direction(&stream.bytes);
+        let items = decode_stream(stream, direction);
+        let frames = items.iter().filter(|d| matches!(d.item, StreamItem::Frame { .. })).count();
+        let crc_errors = items.iter().filter(|d| matches!(d.item, StreamItem::Frame { crc_ok: false, .. })).count();
+        let log_bytes: usize = items.iter().map(|d| if let StreamItem::Log(b) = &d.item { b.len() } else { 0 }).sum();
+        summary.push(format!(
+            "{}: {} bytes, {}, {frames} frames ({crc_errors} CRC errors), {log_bytes} log bytes",
+            stream.label,
+            stream.bytes.len(),
+            direction.arrow()
+        ));
+        decoded.extend(items);
+    }
+    // Timed streams are merged; a stable sort keeps raw streams in their own order.
+    if decoded.iter().all(|d| matches!(d.position, Position::Time(_))) {
+        decoded.sort_by(|a, b| match (a.position, b.position) {
+            (Position::Time(a), Position::Time(b)) => a.total_cmp(&b),
+            _ => std::cmp::Ordering::Equal,
+        });
+    }
+
+    let mut lines = Vec::new();
+    for d in &decoded {
+        let at = match d.position {
+            Position::Time(t) => format!("{t:>12.6}"),
+            Position::Offset(o) => format!("{:>12}", format!("@{o}")),
+        };
+        match &d.item {
+            StreamItem::Frame { code, body, crc_ok } => {
+                let name = command_name(*code).unwrap_or("unknown");
+                let crc = if *crc_ok { "CRC ok" } else { "CRC ERROR" };
+                let line = format!(
+                    "{at} {} {name} (0x{code:02X}) len {} {crc}  {}",
+                    d.direction.arrow(),
+                    body.len(),
+                    describe_body(d.direction, *code, body)
+                );
+                lines.push(line.trim_end().to_string());
+            }
+            StreamItem::Log(bytes) => {
+                let text = String::from_utf8_lossy(bytes);
+                for line in text.split('\n').map(|l| l.trim_end_matches('\r')).filter(|l| !l.is_empty()) {
+                    lines.push(format!("{at} {} log | {}", d.direction.arrow(), line.escape_debug()));
+                }
+            }
+        }
+    }
+    lines.extend(summary);
+    lines
+}
+
+/// Decoded frame body, or its hex bytes for what has no decoder.
+fn describe_body(direction: Direction, code: u8, body: &[u8]) -> String {
+    let u32_at = |i: usize| body.get(i..i + 4).map(|b| u32::from_le_bytes(b.try_into().unwrap()));
+    let decoded = match (direction, code) {
+        (Direction::HostToDevice, SFU_CMD_INFO) if body.is_empty() => Some(String::new()),
+        (Direction::HostToDevice, SFU_CMD_ERASE) => u32_at(0).map(|size| format!("size {size} (0x{size:X})")),
+        (Direction::HostToDevice, SFU_CMD_WRITE) => u32_at(0).map(|addr| format!("addr 0x{addr:08X}, {} data bytes", body.len() - 4)),
+        (Direction::HostToDevice, SFU_CMD_START) => u32_at(0).map(|crc| format!("crc32 0x{crc:08X}")),
+        (Direction::HostToDevice, SFU_CMD_SPEED) if body.is_empty() => Some("get".into()),
+        (Direction::HostToDevice, SFU_CMD_SPEED) => u32_at(0).map(|baud| format!("set {baud}")),
+        (Direction::DeviceToHost, SFU_CMD_INFO) => parse_sfu_info(body, 0).map(|i| {
+            format!(
+                "id {} cpu 0x{:08X} flash {}K sfu {:04X} receive {} start 0x{:08X} run 0x{:08X}",
+                i.device_id.iter().map(|b| format!("{b:02X}")).collect::<String>(),
+                i.cpu_type,
+                i.flash_size_correct / 1024,
+                i.sfu_ver,
+                i.receive_size,
+                i.main_start_from,
+                i.main_run_from
+            )
+        }),
+        (Direction::DeviceToHost, SFU_CMD_ERASE_PART) => parse_erase_info(body).map(|part| format!("part {part}")),
+        (Direction::DeviceToHost, SFU_CMD_ERASE) => Some("erase done".into()),
+        (Direction::DeviceToHost, SFU_CMD_WRITE) => {
+            parse_write_info(body).map(|w| format!("mcu_addr 0x{:08X}, mcu_used {}", w.mcu_write_addr, w.mcu_receive_count))
+        }
+        (Direction::DeviceToHost, SFU_CMD_START) => {
+            parse_start_info(body).map(|s| format!("from 0x{:08X}, size {}, crc32 0x{:08X}", s.mcu_from, s.mcu_count, s.mcu_crc32))
+        }
+        (Direction::DeviceToHost, SFU_CMD_SPEED) => parse_speed_info(body).map(|s| match s {
+            SpeedInfo::GET(baud) => format!("current {baud}"),
+            SpeedInfo::CHANGE(c) => format!("{} -> {}", c.old_bod, c.new_bod),
+        }),
+        _ => None,
+    };
+    decoded.unwrap_or_else(|| {
+        let hex: String = body.iter().take(32).map(|b| format!("{b:02X}")).collect();
+        if body.len() > 32 { format!("{hex}...") } else { hex }
+    })
+}
+
+// ---- Unit tests ----
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::packet_build;
+    use crate::testutil::device_frame;
+
+    #[test]
+    fn csv_streams_and_values() {
+        let csv = "name,type,start_time,duration,data\n\
+                   TX,data,0.001,0.00001,0x81\n\
+                   RX,error,0.002,0.00001,0x00\n\
+                   RX,data,0.003,0.00001,'\\n'\n\
+                   RX,data,0.004,0.00001,\",\"\n";
+        let streams = parse_csv("cap.csv", csv).unwrap();
+        assert_eq!(streams.iter().map(|s| s.label.as_str()).collect::<Vec<_>>(), ["cap.csv:TX", "cap.csv:RX"]);
+        assert_eq!(streams[1].bytes, b"\n,");
+        assert_eq!(streams[1].times.as_deref(), Some(&[0.003, 0.004][..]));
+        assert!(parse_csv("cap.csv", "Time [s],Value\n0.1,0xZZ\n").unwrap_err().contains("line 2"));
+    }
+
+    #[test]
+    fn both_directions_merged_by_time() {
+        let tx = packet_build(SFU_CMD_WRITE, &[&0x0800_4000u32.to_le_bytes()[..], &[0xFF; 8]].concat());
+        let mut rx = b"boot ok\r\n".to_vec();
+        rx.extend(device_frame(SFU_CMD_WRITE, &[0x08, 0x40, 0, 0x08, 16, 0, 0, 0]));
+        let mut bad = device_frame(SFU_CMD_START, &[0; 12]);
+        *bad.last_mut().unwrap() ^= 1;
+        rx.extend(&bad);
+
+        let host = CaptureStream { label: "tx".into(), times: Some((0..tx.len()).map(|i| 1.0 + i as f64 * 1e-4).collect()), bytes: tx };
+        let dev = CaptureStream { label: "rx".into(), times: Some((0..rx.len()).map(|i| i as f64 * 0.1).collect()), bytes: rx };
+        let lines = decode_captures(&[host, dev]);
+
+        assert!(lines[0].ends_with("dev>host log | boot ok"), "{lines:?}");
+        assert!(lines[1].contains("host>dev SFU_CMD_WRITE (0x38) len 12 CRC ok  addr 0x08004000, 8 data bytes"), "{lines:?}");
+        assert!(lines[2].contains("dev>host SFU_CMD_WRITE (0x38) len 8 CRC ok  mcu_addr 0x08004008, mcu_used 16"), "{lines:?}");
+        assert!(lines[3].contains("SFU_CMD_START (0x26) len 12 CRC ERROR"), "{lines:?}");
+        assert_eq!(lines[4], "tx: 24 bytes, host>dev, 1 frames (0 CRC errors), 0 log bytes");
+        assert_eq!(lines[5], "rx: 53 bytes, dev>host, 2 frames (1 CRC errors), 9 log bytes");
+    }
+
+    #[test]
+    fn raw_stream_positions_are_offsets() {
+        let stream = CaptureStream { label: "raw.bin".into(), bytes: [b"hi\n".to_vec(), device_frame(SFU_CMD_ERASE, &[])].concat(), times: None };
+        let lines = decode_captures(&[stream]);
+        assert_eq!(lines[0], "          @0 dev>host log | hi");
+        assert_eq!(lines[1], "         @14 dev>host SFU_CMD_ERASE (0xC5) len 0 CRC ok  erase done");
+    }
+}
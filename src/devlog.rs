@@ -0,0 +1,107 @@
+//! Device log capture (`--device-log`).
+//!
+//! Every text line `PacketParser` separates from the packets is written with
+//! the host timestamp (ms since start) and a tab, one line per entry
+//! (`\t` stands for the tab):
+//!
+//! ```text
+//! 1532\tSFU boot v2.1
+//! 1533\tflash 2048K
+//! 4410\t[unfinished] waiting for
+//! ```
+//!
+//! A line still being received when the tool exits is written with the
+//! `[unfinished]` marker, so nothing the device sent is lost. The file is
+//! line buffered, it stays complete if the tool is killed. When the file can
+//! no longer be written the device output still goes to the console.
+
+use std::fs::File;
+use std::io::{LineWriter, Write};
+
+use super::sink::Sink;
+
+pub struct DeviceLog {
+    out: Sink<Box<dyn Write + Send>>,
+}
+
+impl DeviceLog {
+    pub fn create(path: &str) -> Result<Self, String> {
+        let file = File::create(path).map_err(|e| format!("{path}: {e}"))?;
+        Ok(DeviceLog::with_writer(path, Box::new(LineWriter::new(file))))
+    }
+
+    fn with_writer(path: &str, out: Box<dyn Write + Send>) -> Self {
+        DeviceLog { out: Sink::new(format!("device log {path}"), "logging stopped", out) }
+    }
+
+    pub fn line(&mut self, ms: u128, text: &str) {
+        self.out.write(|out| writeln!(out, "{ms}\t{text}"));
+    }
+
+    /// Write the partial last line (if any) and flush.
+    pub fn finish(&mut self, ms: u128, partial: &str) {
+        if !partial.is_empty() {
+            self.out.write(|out| writeln!(out, "{ms}\t[unfinished] {partial}"));
+        }
+        self.out.write(|out| out.flush());
+    }
+}
+
+// ---- Unit tests ----
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    /// Collects what is written; fails every write once `fail` is set.
+    #[derive(Clone, Default)]
+    struct Shared {
+        data: Arc<Mutex<Vec<u8>>>,
+        fail: Arc<Mutex<bool>>,
+        attempts: Arc<Mutex<usize>>,
+    }
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            *self.attempts.lock().unwrap() += 1;
+            if *self.fail.lock().unwrap() {
+                return Err(io::Error::other("disk full"));
+            }
+            self.data.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn timestamp_tab_and_unfinished_line() {
+        let shared = Shared::default();
+        let mut log = DeviceLog::with_writer("test", Box::new(shared.clone()));
+        log.line(1532, "SFU boot v2.1");
+        log.line(1533, "");
+        log.finish(4410, "waiting for");
+        log.finish(4411, "");
+        let text = String::from_utf8(shared.data.lock().unwrap().clone()).unwrap();
+        assert_eq!(text, "1532\tSFU boot v2.1\n1533\t\n4410\t[unfinished] waiting for\n");
+    }
+
+    #[test]
+    fn write_error_stops_logging() {
+        let shared = Shared::default();
+        let mut log = DeviceLog::with_writer("test", Box::new(shared.clone()));
+        log.line(1, "kept");
+        *shared.fail.lock().unwrap() = true;
+        log.line(2, "lost");
+        let attempts = *shared.attempts.lock().unwrap();
+        *shared.fail.lock().unwrap() = false;
+        log.line(3, "not written after the error");
+        log.finish(4, "nor this");
+        assert_eq!(*shared.attempts.lock().unwrap(), attempts, "no write after the error");
+        assert_eq!(shared.data.lock().unwrap().as_slice(), b"1\tkept\n");
+    }
+}
@@ -0,0 +1,231 @@
+//! FTDI FT-X (FT230X/FT231X/FT234XD...) CBUS bit-bang reset backend.
+//!
+//! GPIO0..GPIO3 of the reset sequence map to CBUS0..CBUS3. Only pins
+//! programmed as CBUS_IOMODE in the EEPROM follow bit-bang mode, anything else
+//! (TXLED, SLEEP...) is rejected before touching the lines. When the sequence
+//! ends (or fails) bit-bang mode is switched off, so the pins return to the
+//! function configured in the EEPROM.
+
+use std::time::Duration;
+
+use rusb::{Context, DeviceHandle, Direction, Recipient, RequestType};
+
+use crate::reset::{run_steps, LatchAttempt, ResetSequence, ResetStep};
+use crate::usbtty::{open_usb_device, UsbTtyLocation};
+
+const FTDI_VID: u16 = 0x0403;
+const FTX_PID: u16 = 0x6015;
+
+const SIO_SET_BITMODE_REQUEST: u8 = 0x0B;
+const SIO_READ_PINS_REQUEST: u8 = 0x0C;
+const SIO_READ_EEPROM_REQUEST: u8 = 0x90;
+
+const BITMODE_RESET: u8 = 0x00;
+const BITMODE_CBUS: u8 = 0x20;
+
+/// FT-X EEPROM: CBUS0..CBUS3 function bytes at byte offsets 0x1A..0x1D.
+const FTX_EEPROM_CBUS_OFFSET: u16 = 0x1A;
+const FTX_CBUS_COUNT: usize = 4;
+const CBUSX_IOMODE: u8 = 0x08;
+
+const USB_TIMEOUT: Duration = Duration::from_millis(200);
+
+pub fn is_ftdi(loc: &UsbTtyLocation) -> bool {
+    loc.driver == "ftdi_sio" || loc.vid == FTDI_VID
+}
+
+struct FtdiCbus {
+    handle: DeviceHandle<Context>,
+    /// Port index used by FTDI vendor requests: interface number + 1.
+    index: u16,
+}
+
+impl FtdiCbus {
+    fn set_bitmode(&self, mode: u8, pins: u8) -> Result<(), String> {
+        let req_type = rusb::request_type(Direction::Out, RequestType::Vendor, Recipient::Device);
+        self.handle
+            .write_control(
+                req_type,
+                SIO_SET_BITMODE_REQUEST,
+                ((mode as u16) << 8) | pins as u16,
+                self.index,
+                &[],
+                USB_TIMEOUT,
+            )
+            .map(|_| ())
+            .map_err(|e| format!("FTDI SET_BITMODE 0x{mode:02X} failed: {e}"))
+    }
+
+    fn read_pins(&self) -> Result<u8, String> {
+        let req_type = rusb::request_type(Direction::In, RequestType::Vendor, Recipient::Device);
+        let mut buf = [0u8; 1];
+        let n = self
+            .handle
+            .read_control(req_type, SIO_READ_PINS_REQUEST, 0, self.index, &mut buf, USB_TIMEOUT)
+            .map_err(|e| format!("FTDI READ_PINS failed: {e}"))?;
+        if n != 1 {
+            return Err(format!("FTDI READ_PINS returned {n} bytes"));
+        }
+        Ok(buf[0])
+    }
+
+    fn read_eeprom_word(&self, word_addr: u16) -> Result<u16, String> {
+        let req_type = rusb::request_type(Direction::In, RequestType::Vendor, Recipient::Device);
+        let mut buf = [0u8; 2];
+        let n = self
+            .handle
+            .read_control(req_type, SIO_READ_EEPROM_REQUEST, 0, word_addr, &mut buf, USB_TIMEOUT)
+            .map_err(|e| format!("FTDI READ_EEPROM @0x{word_addr:02X} failed: {e}"))?;
+        if n != 2 {
+            return Err(format!("FTDI READ_EEPROM returned {n} bytes"));
+        }
+        Ok(u16::from_le_bytes(buf))
+    }
+
+    /// CBUS0..CBUS3 function codes from the FT-X EEPROM.
+    fn cbus_functions(&self) -> Result<[u8; FTX_CBUS_COUNT], String> {
+        let mut words = [0u16; FTX_CBUS_COUNT / 2];
+        for (i, word) in words.iter_mut().enumerate() {
+            *word = self.read_eeprom_word(FTX_EEPROM_CBUS_OFFSET / 2 + i as u16)?;
+        }
+        Ok(decode_cbus_functions(words))
+    }
+}
+
+/// EEPROM words are little endian: the low byte is the lower CBUS pin.
+fn decode_cbus_functions(words: [u16; FTX_CBUS_COUNT / 2]) -> [u8; FTX_CBUS_COUNT] {
+    let mut out = [0u8; FTX_CBUS_COUNT];
+    for (pair, word) in out.chunks_mut(2).zip(words) {
+        pair.copy_from_slice(&word.to_le_bytes());
+    }
+    out
+}
+
+/// Every pin of `mask` must exist and be programmed as CBUS_IOMODE.
+fn check_cbus_mask(mask: u16, functions: &[u8; FTX_CBUS_COUNT]) -> Result<(), String> {
+    let cbus_mask = (1u16 << FTX_CBUS_COUNT) - 1;
+    if mask & !cbus_mask != 0 {
+        return Err(format!(
+            "reset mask 0x{mask:04X} uses pins beyond CBUS0..CBUS3 (mask 0x{cbus_mask:04X})"
+        ));
+    }
+    for (pin, &func) in functions.iter().enumerate() {
+        if mask & (1 << pin) != 0 && func != CBUSX_IOMODE {
+            return Err(format!(
+                "CBUS{pin} is configured as function 0x{func:02X}, not GPIO (CBUS_IOMODE 0x{CBUSX_IOMODE:02X}); reprogram the EEPROM"
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// SET_BITMODE pin byte. High nibble: direction (1 = output), low nibble: output level.
+fn cbus_pins(driven_mask: u16, driven_values: u16) -> u8 {
+    ((driven_mask as u8 & 0x0F) << 4) | (driven_values as u8 & 0x0F)
+}
+
+impl Drop for FtdiCbus {
+    fn drop(&mut self) {
+        // Leave bit-bang mode: CBUS pins go back to their EEPROM configuration.
+        if let Err(msg) = self.set_bitmode(BITMODE_RESET, 0) {
+            eprintln!("{msg}: CBUS pins may stay in bit-bang mode until replug");
+        }
+    }
+}
+
+/// Run reset sequence on CBUS pins of the FTDI adapter at `loc`; DTR/RTS/BREAK
+/// steps go through `set_lines`. A CBUS pin becomes an output at the first step
+/// that drives it.
+///
+/// `Unsupported` - the adapter is an FTDI part without CBUS bit-bang (FT232R/FT2232...),
+/// the caller may use DTR/RTS instead.
+/// Open the FT-X behind `loc` once `mask` fits its CBUS pins in I/O mode.
+fn open_cbus(loc: &UsbTtyLocation, mask: u16) -> Result<FtdiCbus, String> {
+    // Pin range first, before the device is opened.
+    check_cbus_mask(mask, &[CBUSX_IOMODE; FTX_CBUS_COUNT])?;
+
+    let dev = FtdiCbus {
+        handle: open_usb_device(loc)?,
+        index: loc.interface as u16 + 1,
+    };
+    check_cbus_mask(mask, &dev.cbus_functions()?)?;
+    Ok(dev)
+}
+
+pub fn run_sequence_ftdi_cbus(
+    loc: &UsbTtyLocation,
+    rst_seq: &ResetSequence,
+    set_lines: impl FnMut(&ResetStep) -> Result<(), String>,
+) -> Result<LatchAttempt, String> {
+    if loc.pid != FTX_PID {
+        return Ok(LatchAttempt::Unsupported(format!(
+            "FTDI {:04x}:{:04x} is not an FT-X part, CBUS bit-bang not supported",
+            loc.vid, loc.pid
+        )));
+    }
+
+    // Nothing is written before the first step: setup failures only rule CBUS out.
+    let dev = match open_cbus(loc, rst_seq.gpio_mask()) {
+        Ok(dev) => dev,
+        Err(msg) => return Ok(LatchAttempt::Unsupported(msg)),
+    };
+
+    run_steps(
+        rst_seq,
+        |_, driven_mask, driven_values| {
+            dev.set_bitmode(BITMODE_CBUS, cbus_pins(driven_mask, driven_values))?;
+            Ok(Some((dev.read_pins()? & 0x0F) as u16))
+        },
+        set_lines,
+    )?;
+
+    Ok(LatchAttempt::Used)
+    // dev dropped here: bit-bang mode reset
+}
+
+// ---- Unit tests ----
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cbus_functions_from_eeprom_words() {
+        let cases = [
+            ([0x0808, 0x0808], [0x08, 0x08, 0x08, 0x08]),
+            ([0x0801, 0x0A08], [0x01, 0x08, 0x08, 0x0A]),
+            ([0x0000, 0xFFFF], [0x00, 0x00, 0xFF, 0xFF]),
+        ];
+        for (words, functions) in cases {
+            assert_eq!(decode_cbus_functions(words), functions, "{words:04X?}");
+        }
+    }
+
+    #[test]
+    fn cbus_mask_against_functions() {
+        let io = CBUSX_IOMODE;
+        let cases: [(u16, [u8; 4], Option<&str>); 6] = [
+            (0x0003, [io, io, 0x01, 0x02], None),
+            (0x0000, [0x01, 0x02, 0x03, 0x04], None),
+            (0x000F, [io; 4], None),
+            (0x0004, [io, io, 0x0D, io], Some("CBUS2 is configured as function 0x0D")),
+            (0x0009, [io, io, io, 0x00], Some("CBUS3 is configured as function 0x00")),
+            (0x0010, [io; 4], Some("beyond CBUS0..CBUS3")),
+        ];
+        for (mask, functions, err) in cases {
+            match (check_cbus_mask(mask, &functions), err) {
+                (Ok(()), None) => {}
+                (Err(e), Some(text)) => assert!(e.contains(text), "mask 0x{mask:04X}: {e}"),
+                (result, _) => panic!("mask 0x{mask:04X}: unexpected {result:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn bitmode_direction_and_levels() {
+        let cases = [(0x0000, 0x0000, 0x00), (0x0001, 0x0001, 0x11), (0x0003, 0x0002, 0x32), (0x000F, 0x0005, 0xF5)];
+        for (driven_mask, driven_values, pins) in cases {
+            assert_eq!(cbus_pins(driven_mask, driven_values), pins, "mask {driven_mask:X} values {driven_values:X}");
+        }
+    }
+}
@@ -0,0 +1,271 @@
+//! Linux GPIO character device reset backend (/dev/gpiochipN, uAPI v2).
+//!
+//! For hosts that wire BOOT/NRST to their own GPIO header (Raspberry Pi
+//! fixtures etc.) instead of to the USB-UART. GPIO N of the reset sequence
+//! drives line `GpioChipLines.offsets[N]` of the chip. All lines are requested
+//! as outputs up front, each starting at the level of the first step that drives it.
+//!
+//! Can be tried without hardware using the kernel gpio-sim module:
+//!   modprobe gpio-sim
+//!   mkdir -p /sys/kernel/config/gpio-sim/sfu/bank0
+//!   echo 8 > /sys/kernel/config/gpio-sim/sfu/bank0/num_lines
+//!   echo 1 > /sys/kernel/config/gpio-sim/sfu/live
+//!   cat /sys/kernel/config/gpio-sim/sfu/bank0/chip_name   # e.g. gpiochip2
+//!   SFU_TEST_GPIOCHIP=/dev/gpiochip2 cargo test -- --ignored gpiochip
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use crate::reset::{run_steps, GpioChipLines, ResetSequence, ResetStep};
+
+const GPIO_V2_LINES_MAX: usize = 64;
+const GPIO_MAX_NAME_SIZE: usize = 32;
+const GPIO_V2_LINE_NUM_ATTRS_MAX: usize = 10;
+
+const GPIO_V2_LINE_FLAG_OUTPUT: u64 = 1 << 3;
+const GPIO_V2_LINE_ATTR_ID_OUTPUT_VALUES: u32 = 2;
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct GpioV2LineAttribute {
+    id: u32,
+    padding: u32,
+    /// union { flags, values, debounce_period_us }
+    value: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct GpioV2LineConfigAttribute {
+    attr: GpioV2LineAttribute,
+    mask: u64,
+}
+
+#[repr(C)]
+struct GpioV2LineConfig {
+    flags: u64,
+    num_attrs: u32,
+    padding: [u32; 5],
+    attrs: [GpioV2LineConfigAttribute; GPIO_V2_LINE_NUM_ATTRS_MAX],
+}
+
+#[repr(C)]
+struct GpioV2LineRequest {
+    offsets: [u32; GPIO_V2_LINES_MAX],
+    consumer: [u8; GPIO_MAX_NAME_SIZE],
+    config: GpioV2LineConfig,
+    num_lines: u32,
+    event_buffer_size: u32,
+    padding: [u32; 5],
+    fd: i32,
+}
+
+#[repr(C)]
+struct GpioV2LineValues {
+    bits: u64,
+    mask: u64,
+}
+
+// Layout must match <linux/gpio.h> exactly, the ioctl numbers encode the sizes.
+const _: () = assert!(std::mem::size_of::<GpioV2LineRequest>() == 592);
+const _: () = assert!(std::mem::size_of::<GpioV2LineValues>() == 16);
+
+const fn iowr(nr: u32, size: usize) -> u32 {
+    const GPIO_IOC_MAGIC: u32 = 0xB4;
+    (3 << 30) | ((size as u32) << 16) | (GPIO_IOC_MAGIC << 8) | nr
+}
+
+const GPIO_V2_GET_LINE_IOCTL: u32 = iowr(0x07, std::mem::size_of::<GpioV2LineRequest>());
+const GPIO_V2_LINE_GET_VALUES_IOCTL: u32 = iowr(0x0E, std::mem::size_of::<GpioV2LineValues>());
+const GPIO_V2_LINE_SET_VALUES_IOCTL: u32 = iowr(0x0F, std::mem::size_of::<GpioV2LineValues>());
+
+/// Requested output lines; released when dropped (fd closed).
+struct LineRequest {
+    fd: File,
+    /// Sequence bit for each requested line, in request order.
+    bits: Vec<u32>,
+}
+
+impl LineRequest {
+    fn open(lines: &GpioChipLines, mask: u16, initial: u16) -> Result<Self, String> {
+        let chip = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&lines.chip)
+            .map_err(|e| format!("{}: {e}", lines.chip))?;
+
+        let bits: Vec<u32> = (0..16).filter(|bit| mask & (1 << bit) != 0).collect();
+
+        let mut req = GpioV2LineRequest {
+            offsets: [0; GPIO_V2_LINES_MAX],
+            consumer: [0; GPIO_MAX_NAME_SIZE],
+            config: GpioV2LineConfig {
+                flags: GPIO_V2_LINE_FLAG_OUTPUT,
+                num_attrs: 1,
+                padding: [0; 5],
+                attrs: [GpioV2LineConfigAttribute::default(); GPIO_V2_LINE_NUM_ATTRS_MAX],
+            },
+            num_lines: bits.len() as u32,
+            event_buffer_size: 0,
+            padding: [0; 5],
+            fd: -1,
+        };
+        for (i, &bit) in bits.iter().enumerate() {
+            req.offsets[i] = lines.offsets[bit as usize];
+        }
+        let consumer = b"sfu-cli-uploader";
+        req.consumer[..consumer.len()].copy_from_slice(consumer);
+
+        // Drive the first step's levels right away instead of glitching through defaults.
+        req.config.attrs[0] = GpioV2LineConfigAttribute {
+            attr: GpioV2LineAttribute {
+                id: GPIO_V2_LINE_ATTR_ID_OUTPUT_VALUES,
+                padding: 0,
+                value: to_line_bits(&bits, initial),
+            },
+            mask: line_mask(bits.len()),
+        };
+
+        let res = unsafe { libc::ioctl(chip.as_raw_fd(), GPIO_V2_GET_LINE_IOCTL as _, &mut req) };
+        if res == -1 {
+            return Err(format!(
+                "{}: GPIO_V2_GET_LINE for offsets {:?} failed: {}",
+                lines.chip,
+                &req.offsets[..bits.len()],
+                io::Error::last_os_error()
+            ));
+        }
+
+        Ok(LineRequest {
+            fd: unsafe { File::from_raw_fd(req.fd) },
+            bits,
+        })
+    }
+
+    fn set(&self, values: u16) -> Result<(), String> {
+        let mut lv = GpioV2LineValues {
+            bits: to_line_bits(&self.bits, values),
+            mask: line_mask(self.bits.len()),
+        };
+        let res = unsafe { libc::ioctl(self.fd.as_raw_fd(), GPIO_V2_LINE_SET_VALUES_IOCTL as _, &mut lv) };
+        if res == -1 {
+            return Err(format!("GPIO_V2_LINE_SET_VALUES failed: {}", io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
+    fn get(&self) -> Result<u16, String> {
+        let mut lv = GpioV2LineValues {
+            bits: 0,
+            mask: line_mask(self.bits.len()),
+        };
+        let res = unsafe { libc::ioctl(self.fd.as_raw_fd(), GPIO_V2_LINE_GET_VALUES_IOCTL as _, &mut lv) };
+        if res == -1 {
+            return Err(format!("GPIO_V2_LINE_GET_VALUES failed: {}", io::Error::last_os_error()));
+        }
+        let mut values = 0u16;
+        for (i, &bit) in self.bits.iter().enumerate() {
+            if lv.bits & (1 << i) != 0 {
+                values |= 1 << bit;
+            }
+        }
+        Ok(values)
+    }
+}
+
+fn line_mask(count: usize) -> u64 {
+    if count >= 64 { u64::MAX } else { (1u64 << count) - 1 }
+}
+
+/// Sequence value (bit N = sequence pin N) -> request value (bit i = i-th requested line).
+fn to_line_bits(bits: &[u32], values: u16) -> u64 {
+    let mut out = 0u64;
+    for (i, &bit) in bits.iter().enumerate() {
+        if values & (1 << bit) != 0 {
+            out |= 1 << i;
+        }
+    }
+    out
+}
+
+/// Level each driven line takes first, so requesting the lines doesn't glitch them.
+fn initial_values(rst_seq: &ResetSequence) -> u16 {
+    let mut seen = 0u16;
+    let mut values = 0u16;
+    for step in &rst_seq.steps {
+        let new = step.mask & !seen;
+        values |= step.values & new;
+        seen |= new;
+    }
+    values
+}
+
+pub fn run_sequence_gpiochip(
+    lines: &GpioChipLines,
+    rst_seq: &ResetSequence,
+    set_lines: impl FnMut(&ResetStep) -> Result<(), String>,
+) -> Result<(), String> {
+    let mask = rst_seq.gpio_mask();
+    let mapped = if lines.offsets.len() >= 16 { 0xFFFF } else { (1u16 << lines.offsets.len()) - 1 };
+    if mask & !mapped != 0 {
+        return Err(format!(
+            "reset mask 0x{:04X} uses bits without a line offset ({} offsets given for {})",
+            mask,
+            lines.offsets.len(),
+            lines.chip
+        ));
+    }
+    if mask == 0 {
+        return Err("reset sequence drives no GPIO pins".into());
+    }
+
+    let initial = initial_values(rst_seq);
+    let req = LineRequest::open(lines, mask, initial)?;
+
+    // Lines not driven yet stay at their first level, all lines are set each step.
+    run_steps(
+        rst_seq,
+        |_, driven_mask, driven_values| {
+            let values = (initial & !driven_mask) | driven_values;
+            req.set(values)?;
+            req.get().map(Some)
+        },
+        set_lines,
+    )
+
+    // req dropped here: lines released
+}
+
+// ---- Unit tests ----
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_bits_follow_mask_order() {
+        // mask 0b1010: line 0 <- bit 1, line 1 <- bit 3
+        let bits = [1, 3];
+        assert_eq!(to_line_bits(&bits, 0b1000), 0b10);
+        assert_eq!(to_line_bits(&bits, 0b0010), 0b01);
+        assert_eq!(to_line_bits(&bits, 0b0101), 0b00);
+    }
+
+    #[test]
+    fn initial_values_take_first_driven_level() {
+        let mut seq = ResetSequence::from_values(0, 0b01, &[0b00, 0b01]);
+        seq.steps.push(ResetStep { mask: 0b10, values: 0b10, ..ResetStep::default() });
+        seq.steps.push(ResetStep { mask: 0b10, values: 0b00, ..ResetStep::default() });
+        assert_eq!(initial_values(&seq), 0b10);
+    }
+
+    /// Needs a gpio-sim chip with at least 2 lines, see module docs.
+    #[test]
+    #[ignore]
+    fn gpiochip_sequence_on_gpio_sim() {
+        let chip = std::env::var("SFU_TEST_GPIOCHIP").expect("SFU_TEST_GPIOCHIP not set");
+        let lines = GpioChipLines { chip, offsets: vec![0, 1] };
+        let seq = ResetSequence::from_values(1, 0b11, &[0b01, 0b00, 0b10, 0b11]);
+        run_sequence_gpiochip(&lines, &seq, |_| Ok(())).unwrap();
+    }
+}
@@ -1,7 +1,8 @@
 //use std::env;
 //use std::fs::File;
+use std::collections::VecDeque;
 use std::time::{Duration, Instant};
-use std::io::{self};
+use std::io;
 use std::fs;
 use std::thread::{self, sleep};
 use std::process::ExitCode;
@@ -12,9 +13,7 @@ mod misc;
 //use misc::spawn_stdin_channel;
 //use misc::strip_trailing_newline;
 use misc::tostr;
-use misc::deserialize_bytes;
-use misc::deserialize_u16_le;
-use misc::deserialize_u32_le;
+use misc::{catch_interrupt, interrupted};
 
 mod crc32;
 
@@ -23,148 +22,110 @@ use packet::packet_build;
 use packet::PacketParser;
 use packet::PacketParserExt;
 
+mod protocol;
+use protocol::{SFU_CMD_ERASE, SFU_CMD_ERASE_PART, SFU_CMD_HWRESET, SFU_CMD_INFO, SFU_CMD_SPEED, SFU_CMD_START, SFU_CMD_TIMEOUT, SFU_CMD_WRERROR, SFU_CMD_WRITE};
+use protocol::{parse_erase_info, parse_sfu_info, parse_speed_info, parse_start_info, parse_write_info, SfuInfo, SpeedInfo};
+
 mod cmdline;
 use cmdline::parse_cmdline_from_env;
+use cmdline::print_version;
+use cmdline::CmdConfig;
+use cmdline::Command;
+use cmdline::AUTO_BAUD_RATES;
 
 mod reset;
 use reset::GpioResetStatus;
-use reset::cp210x_gpio_reset;
+use reset::ResetSequence;
+use reset::SessionPort;
+use reset::gpio_reset;
+use reset::cp210x_gpio_read;
 
-use crate::crc32::crc32::crc32_sfu;
+mod sink;
 
+mod devlog;
+use devlog::DeviceLog;
 
-const SFU_CMD_ERASE_PART :u8 =   0xB3;
-const SFU_CMD_INFO   :u8 = 0x97;
-const SFU_CMD_ERASE  :u8 = 0xC5;
-const SFU_CMD_WRITE  :u8 = 0x38;
-const SFU_CMD_START  :u8 = 0x26;
-const SFU_CMD_SPEED  :u8 = 0x4B;
-const SFU_CMD_TIMEOUT:u8 = 0xAA;
-const SFU_CMD_WRERROR:u8 = 0x55;
-const SFU_CMD_HWRESET:u8 = 0x11;
-
-const WR_BLOCK_SIZE:usize = 0x800; //must be a multiple of 256
-
-#[derive(Debug, Clone)]
-pub struct SfuInfo {
-    pub device_id: [u8; 12],
-    pub cpu_type: u32,
-    pub flash_size_correct: u32,
-    pub sfu_ver: u16,
-    pub receive_size: usize,
-    pub main_start_from: u32,
-    pub main_run_from: u32,
-    pub firmware_end_at: u32,
-}
+mod trace;
+use trace::{parse_trace, TracePort, TraceWriter};
 
-pub fn parse_sfu_info(body: &[u8], fw_len:u32) -> Option<SfuInfo> {
-    if body.len() < 32 {
-        return None;
-    }
-
-    let device_id      = deserialize_bytes::<12>(body, 0);
-    let cpu_type       = deserialize_u32_le(body, 12);
-    let flash_correct  = deserialize_u16_le(body, 16);
-    let sfu_ver        = deserialize_u16_le(body, 18);
-    let receive_size   = deserialize_u32_le(body, 20) as usize;
-    let main_start     = deserialize_u32_le(body, 24);
-    let main_run       = deserialize_u32_le(body, 28);
-
-    Some(SfuInfo {
-        device_id,
-        cpu_type,
-        flash_size_correct: (flash_correct as u32 * 1024),
-        sfu_ver,
-        receive_size,
-        main_start_from: main_start,
-        main_run_from: main_run,
-        firmware_end_at: (main_start + fw_len),
-    })
-}
+mod pcapng;
 
-pub fn parse_erase_info(body: &[u8]) -> Option<i32> {
-    if body.len() < 4 {
-        return None;
-    }
-    let part_num = deserialize_u32_le(body, 0) as i32;
-    Some(part_num)
-}
+mod decode;
 
-#[derive(Debug, Clone)]
-pub struct SpeedChangeInfo {
-    pub old_bod: u32,
-    pub new_bod: u32,
-}
+mod maxspeed;
+use maxspeed::{cached_speed, store_speed, usb_serial_number, SpeedProbe, MAX_SPEED_RATES};
 
-pub enum SpeedInfo {
-    GET(u32),
-    CHANGE(SpeedChangeInfo),
-}
+mod writeblock;
+use writeblock::WriteBlock;
 
-pub fn parse_speed_info(body: &[u8]) -> Option<SpeedInfo> {
-    if body.len() == 4 {
-        let bod     = deserialize_u32_le(body, 0);
-        return Some(SpeedInfo::GET(bod));
-    } else if body.len() == 8 {
-        let old_bod     = deserialize_u32_le(body, 0);
-        let new_bod     = deserialize_u32_le(body, 4);
-        return Some(SpeedInfo::CHANGE(SpeedChangeInfo{old_bod:old_bod, new_bod:new_bod}));
-    } else {
-        return None;
-    }
-}
+mod report;
+use report::{LatencyStats, Phases, SessionReport, WriteLatency};
 
-#[derive(Debug, Clone)]
-pub struct WriteInfo {
-    pub mcu_write_addr: u32,
-    pub mcu_receive_count: u32,
-}
+mod replay;
+use replay::{ReplayPort, ReplayTiming};
 
-pub fn parse_write_info(body: &[u8]) -> Option<WriteInfo> {
-    if body.len() < 8 {
-        return None;
-    }
+mod config;
 
-    let mcu_write_addr     = deserialize_u32_le(body, 0);
-    let mcu_receive_count  = deserialize_u32_le(body, 4);
-
-    Some(WriteInfo {
-        mcu_write_addr: mcu_write_addr,
-        mcu_receive_count: mcu_receive_count,
-    })
-}
+#[cfg(unix)]
+mod usbtty;
+#[cfg(unix)]
+mod ftdi;
+#[cfg(target_os = "linux")]
+mod gpiochip;
+#[cfg(test)]
+mod testutil;
 
-#[derive(Debug, Clone)]
-pub struct StartInfo {
-    pub mcu_from: u32,
-    pub mcu_count: u32,
-    pub mcu_crc32: u32,
-}
+use crate::crc32::crc32::crc32_sfu;
 
-pub fn parse_start_info(body: &[u8]) -> Option<StartInfo> {
-    if body.len() < 12 {
-        return None;
-    }
 
-    let mcu_from  = deserialize_u32_le(body, 0);
-    let mcu_count = deserialize_u32_le(body, 4);
-    let mcu_crc32 = deserialize_u32_le(body, 8);
+const WR_BLOCK_SIZE:usize = 0x800; //starting size, must be a multiple of 256 (see writeblock.rs)
+/// SFU_CMD_SPEED(SET) sent without answer before looking for the device at both rates.
+const SPEED_SET_TRIES: u32 = 2;
+/// How long `--init-speed auto` waits for the INFO answer at each candidate rate.
+const AUTO_BAUD_WAIT: Duration = Duration::from_millis(300);
 
-    Some(StartInfo {
-        mcu_from: mcu_from,
-        mcu_count: mcu_count,
-        mcu_crc32: mcu_crc32,
-    })
+/// Host limit for a whole session at `baud`: two minutes plus twice the transfer time.
+/// INFO retries run inside it, only a main speed change restarts it.
+fn host_timeout(fw_len: usize, baud: u32) -> Duration {
+    Duration::from_secs((2*60 + 2*((fw_len*10) / baud as usize)) as u64)
 }
 
 fn show_port_list() {
     println!("Available serial port list:");
     let ports = serialport::available_ports().expect("No ports found!");
     for p in ports {
-        println!("{}", p.port_name);
+        match p.port_type {
+            serialport::SerialPortType::UsbPort(usb) => println!(
+                "{:<20} USB {:04X}:{:04X} {} {}",
+                p.port_name,
+                usb.vid,
+                usb.pid,
+                usb.product.unwrap_or_default(),
+                usb.serial_number.map(|s| format!("S/N {s}")).unwrap_or_default()
+            ),
+            _ => println!("{}", p.port_name),
+        }
     }
 }
 
+/// Read a firmware image, padded with 0xFF to a multiple of 4 bytes as the device expects.
+fn load_firmware(fname: &str) -> io::Result<Vec<u8>> {
+    let mut bin = fs::read(fname)?;
+    while bin.len() % 4 != 0 {
+        bin.push(0xFF);
+    }
+    Ok(bin)
+}
+
+fn open_session_port(timeline: &Instant, params: &CmdConfig, baud: u32) -> SessionPort {
+    println!("{}\tHOST: open port {}", timeline.elapsed().as_millis(), params.port);
+    let port = serialport::new(&params.port, baud)
+        .timeout(Duration::from_millis(1))
+        .open_native().expect("Failed to open port");
+    println!("{}\tHOST: open port done", timeline.elapsed().as_millis());
+    port
+}
+
 fn write_all_serial(port: &mut dyn SerialPort, buf: &[u8]) -> io::Result<()> {
     let mut written = 0;
     let mut deadline = Instant::now() + Duration::from_millis(500);
@@ -193,9 +154,10 @@ fn write_all_serial(port: &mut dyn SerialPort, buf: &[u8]) -> io::Result<()> {
     Ok(())
 }
 
-fn send_write_command(timeline:&Instant, port: &mut dyn SerialPort, wr_addr_host:&mut u32, addr_shift:u32, fw_bin:&[u8], inflight_bytes_estimate:&mut usize) -> io::Result<()> {
+#[allow(clippy::too_many_arguments)]
+fn send_write_command(timeline:&Instant, port: &mut dyn SerialPort, wr_addr_host:&mut u32, addr_shift:u32, fw_bin:&[u8], block_size:usize, inflight_bytes_estimate:&mut usize) -> io::Result<()> {
     let start_index = (*wr_addr_host - addr_shift) as usize;
-    let mut end_index = start_index + WR_BLOCK_SIZE;
+    let mut end_index = start_index + block_size;
     if end_index >= fw_bin.len() {
         end_index = fw_bin.len()
     }
@@ -224,67 +186,291 @@ const RESULT_INFO_ERROR:u8 = 12;
 const RESULT_PARSE_WRITE_ERROR:u8 = 13;
 const RESULT_DEVICE_WRITE_ERROR:u8 = 14;
 const RESULT_SPEED_ERROR:u8 = 15;
+const RESULT_REPLAY_ERROR:u8 = 16;
 
-fn main() -> ExitCode {
-    let timeline = Instant::now();
-    let params = parse_cmdline_from_env();
-    if params.is_none() {
-        show_port_list();
-        return ExitCode::from(RESULT_PARAM_ERROR);
+/// Run one reset sequence with the configured backend and log the outcome; `false` on failure.
+fn run_reset(timeline: &Instant, params: &CmdConfig, serial: &mut SessionPort, rst_seq: &ResetSequence, stage: &str) -> bool {
+    match &params.reset_profile {
+        Some(name) => println!("{}\tHOST: {stage} begin, profile {name}, backend {}", timeline.elapsed().as_millis(), params.reset_backend),
+        None => println!("{}\tHOST: {stage} begin, backend {}", timeline.elapsed().as_millis(), params.reset_backend),
     }
-    let params = params.unwrap();
-
-    let mut fw_bin = vec![];
-    let mut fw_crc32 = 0u32;
-    if let Some(fname) = params.firmware_path {
-        println!("{}\tHOST: load firmware file {}", timeline.elapsed().as_millis(), fname);
-        fw_bin = if let Ok(mut bin) = fs::read(fname) {
-            while bin.len() % 4 != 0 {
-                bin.push(0xFF);
+    match gpio_reset(&params.port, serial, rst_seq, params.reset_backend, params.gpiochip.as_ref()) {
+        Ok(report) => {
+            for (backend, reason) in &report.skipped {
+                println!("{}\tHOST: reset backend {backend} skipped: {reason}", timeline.elapsed().as_millis());
             }
-            bin
-        } else {
-            eprintln!("{}\tHOST: load error", timeline.elapsed().as_millis());
-            return ExitCode::from(RESULT_FW_LOAD_ERROR);
-        };
-        fw_crc32 = crc32_sfu(&fw_bin);
-        println!("{}\tHOST: loaded {} (0x{:08X}) bytes, CRC32_SFU = 0x{:08X}", timeline.elapsed().as_millis(), fw_bin.len(), fw_bin.len(), fw_crc32);
+            let via = match report.status {
+                GpioResetStatus::UsedCp210x => "CP210x GPIO latch",
+                GpioResetStatus::UsedDtrRts => "DTR/RTS",
+                #[cfg(unix)]
+                GpioResetStatus::UsedFtdiCbus => "FTDI CBUS bit-bang",
+                #[cfg(target_os = "linux")]
+                GpioResetStatus::UsedGpioChip => "host GPIO lines",
+            };
+            let (first, rest) = stage.split_at(1);
+            println!("{}\tHOST: {}{rest} done via {via}", timeline.elapsed().as_millis(), first.to_ascii_uppercase());
+            true
+        }
+        Err(e) => {
+            eprintln!("{}\tHOST: {stage} error: {e}", timeline.elapsed().as_millis());
+            false
+        }
+    }
+}
+
+/// Print the complete device log lines (and copy them to `--device-log`).
+fn print_device_log(timeline: &Instant, packet: &mut PacketParser, device_log: &mut Option<DeviceLog>) {
+    while let Some(str) = packet.logs.pop_front() {
+        let ms = timeline.elapsed().as_millis();
+        println!("{ms}\tDEVICE: {str}");
+        if let Some(log) = device_log {
+            log.line(ms, &str);
+        }
+    }
+}
+
+/// `--device-log` and `--trace` files, created before the port is touched.
+fn open_log_files(params: &CmdConfig) -> Result<(Option<DeviceLog>, Option<TraceWriter>), String> {
+    let device_log = params.device_log.as_deref().map(DeviceLog::create).transpose().map_err(|e| format!("device log {e}"))?;
+    let trace = params.trace.as_deref().map(|path| TraceWriter::create(path, &params.port)).transpose().map_err(|e| format!("trace {e}"))?;
+    Ok((device_log, trace))
+}
+
+/// The port used for the protocol, recording its traffic with `--trace`.
+fn traced_port(port: SessionPort, trace: Option<TraceWriter>) -> Box<dyn SerialPort> {
+    match trace {
+        Some(trace) => Box::new(TracePort::new(Box::new(port), trace)),
+        None => Box::new(port),
+    }
+}
+
+/// Print device log lines until Ctrl-C or `timeout_ms`.
+fn monitor_port(
+    timeline: &Instant,
+    port: &mut dyn SerialPort,
+    packet: &mut PacketParser,
+    device_log: &mut Option<DeviceLog>,
+    timeout_ms: Option<u32>,
+) -> io::Result<()> {
+    catch_interrupt();
+    let deadline = timeout_ms.map(|ms| Instant::now() + Duration::from_millis(ms as u64));
+    match timeout_ms {
+        Some(ms) => println!("{}\tHOST: monitor for {ms} ms, Ctrl-C to stop", timeline.elapsed().as_millis()),
+        None => println!("{}\tHOST: monitor, Ctrl-C to stop", timeline.elapsed().as_millis()),
+    }
+
+    let mut serial_buf: Vec<u8> = vec![0; 0x10000];
+    while !interrupted() && deadline.is_none_or(|d| Instant::now() < d) {
+        match port.read(serial_buf.as_mut_slice()) {
+            Ok(t) => packet.receive_data(&serial_buf[..t]),
+            Err(ref e) if e.kind() == io::ErrorKind::TimedOut => packet.tick(),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+        // Stray bootloader packets are of no interest here.
+        for queue in packet.packets.iter_mut() {
+            queue.clear();
+        }
+        print_device_log(timeline, packet, device_log);
+    }
+    let why = if interrupted() { "interrupted" } else { "timeout" };
+    println!("{}\tHOST: monitor stopped ({why})", timeline.elapsed().as_millis());
+    Ok(())
+}
+
+/// `monitor`: optional reset (and `--reset-after`), then print the device log.
+fn monitor(timeline: &Instant, params: &CmdConfig) -> ExitCode {
+    let (mut device_log, trace) = match open_log_files(params) {
+        Ok(files) => files,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            return ExitCode::from(RESULT_PARAM_ERROR);
+        }
     };
-    
-    let global_timout_sec = 2*60 + 2*((fw_bin.len()*10) / params.baud_main as usize);
-    let mut self_close = Instant::now() + Duration::from_secs(global_timout_sec as u64);
-    println!("{}\tHOST: setup host timeout {} sec ", timeline.elapsed().as_millis(), global_timout_sec);
-
-    if let Some(rst_seq) = params.reset {
-        println!("{}\tHOST: reset begin", timeline.elapsed().as_millis());
-        match cp210x_gpio_reset(&params.port, &rst_seq) {
-            Ok(GpioResetStatus::UsedCp210x) => {println!("{}\tHOST: Reset done via CP210x GPIO latch", timeline.elapsed().as_millis());}
-            Ok(GpioResetStatus::UsedDtrRts) => {println!("{}\tHOST: Reset done via DTR/RTS", timeline.elapsed().as_millis());}
-            Err(e) => {
-                eprintln!("{}\tHOST: GPIO reset error: {e}", timeline.elapsed().as_millis());
+    let session_port = open_session_port(timeline, params, params.baud_init);
+    let mut reset_port = session_port.try_clone_native().expect("Failed to clone port handle");
+    let mut port = traced_port(session_port, trace);
+    if params.reset.is_some() || params.reset_after.is_some() {
+        // As with `reset`, the after-sequence follows the reset directly.
+        let seqs = [(&params.reset, "reset"), (&params.reset_after, "reset-after")];
+        for (seq, stage) in seqs {
+            if let Some(seq) = seq
+                && !run_reset(timeline, params, &mut reset_port, seq, stage)
+            {
                 return ExitCode::from(RESULT_RESET_ERROR);
             }
-        }        
+        }
+        let _ = port.clear(serialport::ClearBuffer::Input);
     }
 
-    println!("{}\tHOST: open port {}", timeline.elapsed().as_millis(), params.port);
-    let mut port: Box<dyn SerialPort> = serialport::new(params.port, params.baud_init)
-        .timeout(Duration::from_millis(1))
-        .open().expect("Failed to open port");
-    // thread::sleep(Duration::from_millis(32));
-    // let _ = port.clear_break();
-    // let _ = port.clear(serialport::ClearBuffer::All);
-    // thread::sleep(Duration::from_millis(32));
-    // let _ = port.clear(serialport::ClearBuffer::All);
-    println!("{}\tHOST: open port done", timeline.elapsed().as_millis());
+    let mut packet = PacketParser::new();
+    let result = match monitor_port(timeline, &mut *port, &mut packet, &mut device_log, params.monitor_timeout_ms) {
+        Ok(()) => RESULT_SUCCESS,
+        Err(e) => {
+            eprintln!("{}\tHOST: read error: {e}", timeline.elapsed().as_millis());
+            RESULT_HOST_TIMEOUT_ERROR
+        }
+    };
+    if let Some(log) = &mut device_log {
+        log.finish(timeline.elapsed().as_millis(), &packet.current_log_line);
+    }
+    ExitCode::from(result)
+}
 
+/// Parser counters, printed only when something looks wrong.
+fn print_parser_stats(packet: &PacketParser) {
+    if (packet.stat_crc_error_packets != 0) ||
+       (packet.stat_incomplete_bytes != 0) ||
+       (packet.stat_other_error_packets != 0) ||
+       (packet.stat_size_or_code_error_packets != 0) ||
+       (packet.stat_log_bytes == 0) ||
+       (packet.stat_log_lines == 0) ||
+       (packet.stat_valid_packets == 0)
+    {
+        println!("");
+        packet.print_stats();
+        println!("");
+
+        if packet.current_log_line.len() != 0 {
+            println!("WARNING: non finished device log line: {}", packet.current_log_line);
+        }
+    }
+}
+
+fn print_session_warnings(packet: &PacketParser, session: &SessionOutcome, result: u8) {
+    let SessionOutcome { erase_done, write_done, start_done, stat_write_resend_errors, .. } = *session;
+    if result == RESULT_HOST_TIMEOUT_ERROR {
+        println!("ERROR: HOST TIMEOUT!!!");
+    } else if !(write_done && erase_done && start_done && (result == RESULT_SUCCESS)) {
+        println!("WARNING: UPDATING NOT FINISHED!!!!");
+    }
+    if let Some((wanted, used)) = session.speed_fallback {
+        println!("WARNING: main speed {wanted} baud was not reached, the session ran at {used} baud");
+    }
+
+    let mut stat_unhandled_commands = 0;
+    for cmd_code in &packet.packets {
+        stat_unhandled_commands += cmd_code.len();
+    }
+    if (stat_unhandled_commands + stat_write_resend_errors) !=0 {
+        println!("WARNING: stat_write_resend_errors: {stat_write_resend_errors}");
+        println!("WARNING: stat_unhandled_commands:  {stat_unhandled_commands}");
+    }
+}
+
+/// Statistics of a finished session, see `report.rs`.
+fn session_report(timeline: &Instant, params: &CmdConfig, packet: &PacketParser, session: &SessionOutcome, result: u8, firmware_bytes: usize, baud: Option<u32>) -> SessionReport {
+    SessionReport {
+        command: params.command.to_string(),
+        result,
+        firmware_bytes,
+        baud,
+        total: timeline.elapsed(),
+        phases: SessionReport::phases_since(timeline, &session.phases),
+        info_attempts: session.info_attempts,
+        write_resends: session.stat_write_resend_errors,
+        write_ack: LatencyStats::new(&session.write_latency.samples),
+        parser: vec![
+            ("valid_packets", packet.stat_valid_packets),
+            ("crc_errors", packet.stat_crc_error_packets),
+            ("size_or_code_errors", packet.stat_size_or_code_error_packets),
+            ("other_errors", packet.stat_other_error_packets),
+            ("incomplete_bytes", packet.stat_incomplete_bytes),
+            ("log_bytes", packet.stat_log_bytes),
+            ("log_lines", packet.stat_log_lines),
+        ],
+        unhandled: (0..=255u8).map(|code| (code, packet.packets[code as usize].len())).filter(|&(_, n)| n > 0).collect(),
+    }
+}
+
+/// Print the report and write it to `--report`; the exit code stays the session's.
+fn finish_report(report: &SessionReport, params: &CmdConfig) {
+    println!();
+    report.print();
+    if let Some(path) = &params.report
+        && let Err(e) = report.save(path)
+    {
+        eprintln!("WARNING: report {e}");
+    }
+}
+
+/// `--replay`: run the session against a recorded trace instead of the device.
+fn replay(timeline: &Instant, params: &CmdConfig, path: &str, fw_bin: &[u8], fw_crc32: u32, device_log: &mut Option<DeviceLog>) -> ExitCode {
+    let records = match fs::read_to_string(path).map_err(|e| e.to_string()).and_then(|text| parse_trace(&text)) {
+        Ok(records) => records,
+        Err(e) => {
+            eprintln!("Error: replay {path}: {e}");
+            return ExitCode::from(RESULT_PARAM_ERROR);
+        }
+    };
+    let timing = if params.replay_fast { ReplayTiming::Fast } else { ReplayTiming::Original };
+    println!("{}\tHOST: replay {path}, {} records, {timing:?} timing", timeline.elapsed().as_millis(), records.len());
+
+    let mut port = ReplayPort::new(&records, timing);
     let mut packet = PacketParser::new();
+    let session = run_session(timeline, params, &mut port, &mut packet, device_log, fw_bin, fw_crc32, host_timeout, &mut |_| {
+        println!("{}\tHOST: reset skipped in replay", timeline.elapsed().as_millis());
+        true
+    });
+
+    print_parser_stats(&packet);
+    if let Some(log) = device_log {
+        log.finish(timeline.elapsed().as_millis(), &packet.current_log_line);
+    }
+    finish_report(&session_report(timeline, params, &packet, &session, session.result, fw_bin.len(), port.baud_rate().ok()), params);
+    print_session_warnings(&packet, &session, session.result);
+
+    let report = port.report();
+    for line in &report {
+        println!("REPLAY: {line}");
+    }
+    if !report.is_empty() {
+        return ExitCode::from(RESULT_REPLAY_ERROR);
+    }
+    println!("REPLAY: host traffic matches the recording");
+    ExitCode::from(session.result)
+}
+
+/// How a bootloader session ended.
+struct SessionOutcome {
+    result: u8,
+    erase_done: bool,
+    write_done: bool,
+    start_done: bool,
+    stat_write_resend_errors: usize,
+    info_attempts: u32,
+    phases: Phases,
+    write_latency: WriteLatency,
+    /// Main speed wanted and the rate the upload ran at, when the switch fell back.
+    speed_fallback: Option<(u32, u32)>,
+}
+
+/// The SFU state machine: INFO, speed switch, erase, write, start on `port`.
+/// `reset` re-runs the reset sequence when `--retries` needs another attempt;
+/// `host_timeout` is `host_timeout()` outside of tests.
+#[allow(clippy::too_many_arguments)]
+fn run_session(
+    timeline: &Instant,
+    params: &CmdConfig,
+    port: &mut dyn SerialPort,
+    packet: &mut PacketParser,
+    device_log: &mut Option<DeviceLog>,
+    fw_bin: &[u8],
+    fw_crc32: u32,
+    host_timeout: fn(usize, u32) -> Duration,
+    reset: &mut dyn FnMut(&ResetSequence) -> bool,
+) -> SessionOutcome {
+    let mut baud_main = params.baud_main;
+    let session_timeout = |baud: u32| host_timeout(fw_bin.len(), baud);
+    let mut self_close = Instant::now() + session_timeout(baud_main);
+    println!("{}\tHOST: setup host timeout {} sec ", timeline.elapsed().as_millis(), session_timeout(baud_main).as_secs());
+
 
     let cmd_info = packet_build(SFU_CMD_INFO, &[]);
     let cmd_erase = packet_build(SFU_CMD_ERASE, &bytes![serialize_u32!(fw_bin.len() as u32)]);
     let cmd_start = packet_build(SFU_CMD_START, &bytes![serialize_u32!(fw_crc32)]);
     let cmd_speed_get =  packet_build(SFU_CMD_SPEED, &[]);
-    let cmd_speed_set =  packet_build(SFU_CMD_SPEED, &bytes![serialize_u32!(params.baud_main)]);
+    let mut cmd_speed_set =  packet_build(SFU_CMD_SPEED, &bytes![serialize_u32!(baud_main)]);
 
     let mut stat_write_resend_errors = 0;
 
@@ -297,15 +483,23 @@ fn main() -> ExitCode {
 
     let mut resend_timeout = Duration::from_millis(250);
 
+    // `start` skips erase and upload and sends SFU_CMD_START right after INFO.
+    let start_only = params.command == Command::Start;
+
     let mut dev_info:Option<SfuInfo> = None;
     let mut erase_began = false;
-    let mut erase_done = false;
-    let mut write_done = false;
+    let mut erase_done = start_only;
+    let mut write_done = start_only;
     let mut start_done = false;
-    let mut speed_get_done = params.baud_main == params.baud_init;
+    let mut speed_get_done = start_only || (!params.main_speed_max && params.baud_main == params.baud_init);
     let mut speed_set_done = speed_get_done;
 
     let mut speed_get_attempts = 4;
+    let mut speed_set_attempts = SPEED_SET_TRIES;
+    // Main speed wanted and the rate used instead after a lost SFU_CMD_SPEED handshake.
+    let mut speed_fallback = None;
+    // `--init-speed auto`: INFO sent so far while looking for the bootloader's rate.
+    let mut auto_baud_tries = params.init_speed_auto.then_some(0usize);
 
     let mut wr_addr_host = 0u32;    
     let mut last_mcu_addr = 0;
@@ -313,20 +507,107 @@ fn main() -> ExitCode {
 
     let mut inflight_bytes_estimate = 0;
     let mut inflight_bytes_limit = 0x10000;
-    let mut write_actual_size = WR_BLOCK_SIZE;
+    let mut write_block = WriteBlock::new(WR_BLOCK_SIZE);
+    // Frame length of every block not acked yet, oldest first.
+    let mut inflight_frames: VecDeque<usize> = VecDeque::new();
+    // Parser errors when the last block was acked (or the first one sent).
+    let mut write_errors_seen: Option<u64> = None;
 
     let mut write_bulk_size = 0;
     let     write_bulk_limit = 0x8000; //TODO: fix it, read device extra info for example
 
     let mut serial_buf: Vec<u8> = vec![0; 0x10000];
 
+    let info_timeout = params.info_timeout_ms.map(|ms| Duration::from_millis(ms as u64));
+    let mut info_deadline = info_timeout.map(|t| Instant::now() + t);
+    let mut attempt = 0;
+
+    let mut phases = Phases::default();
+    phases.begin("info");
+    let mut write_latency = WriteLatency::default();
+
     let mut result = RESULT_HOST_TIMEOUT_ERROR;
     let mut run = true;
     while run && (Instant::now() < self_close) {
+        // Bootloader silent: the board probably missed the reset window, reset it again.
+        if dev_info.is_none() && info_deadline.is_some_and(|d| Instant::now() > d) {
+            let waited = params.info_timeout_ms.unwrap_or(0);
+            if attempt >= params.retries {
+                println!("{}\tHOST: no answer to SFU_CMD_INFO within {waited} ms, {} attempt(s) failed", timeline.elapsed().as_millis(), attempt + 1);
+                result = RESULT_INFO_ERROR;
+                break;
+            }
+            attempt += 1;
+            println!("{}\tHOST: no answer to SFU_CMD_INFO within {waited} ms, retry {attempt}/{}", timeline.elapsed().as_millis(), params.retries);
+            if let Some(rst_seq) = &params.reset
+                && !reset(rst_seq)
+            {
+                result = RESULT_RESET_ERROR;
+                break;
+            }
+            let _ = port.clear(serialport::ClearBuffer::Input);
+            *packet = PacketParser::new();
+            timeout_info = Instant::now();
+            info_deadline = info_timeout.map(|t| Instant::now() + t);
+        }
+
         if dev_info.is_none() && Instant::now() > timeout_info {
+            let mut wait = Duration::from_millis(1000);
+            if let Some(tries) = &mut auto_baud_tries {
+                let baud = AUTO_BAUD_RATES[*tries % AUTO_BAUD_RATES.len()];
+                *tries += 1;
+                if port.baud_rate().ok() != Some(baud) {
+                    port.set_baud_rate(baud).expect("ERROR: port.set_baud_rate");
+                    // What arrived at the previous rate is noise.
+                    let _ = port.clear(serialport::ClearBuffer::Input);
+                    *packet = PacketParser::new();
+                }
+                println!("{}\tHOST: trying init speed {baud}", timeline.elapsed().as_millis());
+                wait = AUTO_BAUD_WAIT;
+            }
             println!("{}\tHOST: send SFU_CMD_INFO", timeline.elapsed().as_millis());
             write_all_serial(&mut *port, &cmd_info).expect("Write ERROR");
-            timeout_info = Instant::now() + Duration::from_millis(1000);
+            timeout_info = Instant::now() + wait;
+        }
+
+        if dev_info.is_some() && params.main_speed_max && !speed_set_done {
+            let current = port.baud_rate().unwrap_or(params.baud_init);
+            let serial = if params.speed_cache { usb_serial_number(&params.port) } else { None };
+            let cached = serial.as_deref().and_then(cached_speed);
+            let candidates = cached.map_or_else(|| MAX_SPEED_RATES.to_vec(), |baud| vec![baud]);
+            if let Some(baud) = cached {
+                println!("{}\tHOST: max speed: cached {baud} baud for this adapter", timeline.elapsed().as_millis());
+            }
+            let mut probe = SpeedProbe { timeline, port: &mut *port, packet: &mut *packet, device_log: &mut *device_log };
+            match probe.run(current, &candidates) {
+                Ok(baud) => {
+                    println!("{}\tHOST: max speed: {baud} baud", timeline.elapsed().as_millis());
+                    baud_main = baud;
+                    self_close = Instant::now() + session_timeout(baud_main);
+                    let update = match cached {
+                        Some(c) if c == baud => None,
+                        // Not clean any more (or not above the init speed): search again next time.
+                        Some(c) => {
+                            println!("{}\tHOST: max speed: cached {c} baud forgotten", timeline.elapsed().as_millis());
+                            Some(None)
+                        }
+                        None => Some(Some(baud)),
+                    };
+                    if let (Some(serial), Some(update)) = (&serial, update)
+                        && let Err(e) = store_speed(serial, update)
+                    {
+                        eprintln!("WARNING: {e}");
+                    }
+                }
+                Err(e) => {
+                    println!("{}\tHOST: {e}", timeline.elapsed().as_millis());
+                    result = RESULT_SPEED_ERROR;
+                    run = false;
+                }
+            }
+            phases.end("speed");
+            speed_get_done = true;
+            speed_set_done = true;
         }
 
         if dev_info.is_some() && !erase_done && !erase_began && !write_done && Instant::now() > timeout_speed_get && !speed_get_done {
@@ -342,14 +623,50 @@ fn main() -> ExitCode {
         }
 
         if dev_info.is_some() && !erase_done && !erase_began && !write_done && Instant::now() > timeout_speed_set && speed_get_done && !speed_set_done {
-            println!("{}\tHOST: send SFU_CMD_SPEED(SET)", timeline.elapsed().as_millis());
-            write_all_serial(&mut *port, &cmd_speed_set).expect("Write ERROR");
-            timeout_speed_set = Instant::now() + Duration::from_millis(1000);
+            if speed_set_attempts > 0 {
+                println!("{}\tHOST: send SFU_CMD_SPEED(SET)", timeline.elapsed().as_millis());
+                write_all_serial(&mut *port, &cmd_speed_set).expect("Write ERROR");
+                timeout_speed_set = Instant::now() + Duration::from_millis(1000);
+                speed_set_attempts -= 1;
+            } else {
+                // The device may have switched with its CHANGE answer lost.
+                let old = port.baud_rate().unwrap_or(params.baud_init);
+                println!("{}\tHOST: no answer to SFU_CMD_SPEED(SET), probing {baud_main} and {old} baud", timeline.elapsed().as_millis());
+                let mut probe = SpeedProbe { timeline, port: &mut *port, packet: &mut *packet, device_log: &mut *device_log };
+                match probe.resync(old, baud_main) {
+                    Ok(Some(baud)) if baud == baud_main => {
+                        // As after a CHANGE answer: confirm with SFU_CMD_SPEED(GET) at the new rate.
+                        speed_set_done = true;
+                        speed_get_done = false;
+                        timeout_speed_get = Instant::now();
+                    }
+                    Ok(Some(baud)) => {
+                        println!("{}\tWARNING: device stayed at {baud} baud, uploading at the init speed instead of {baud_main}", timeline.elapsed().as_millis());
+                        speed_fallback = Some((baud_main, baud));
+                        baud_main = baud;
+                        self_close = Instant::now() + session_timeout(baud_main);
+                        speed_set_done = true;
+                        speed_get_done = true;
+                    }
+                    Ok(None) => {
+                        println!("{}\tHOST: no answer at {baud_main} nor {old} baud after SFU_CMD_SPEED(SET)", timeline.elapsed().as_millis());
+                        result = RESULT_SPEED_ERROR;
+                        run = false;
+                    }
+                    Err(e) => {
+                        println!("{}\tHOST: port error while probing {baud_main} and {old} baud: {e}", timeline.elapsed().as_millis());
+                        result = RESULT_SPEED_ERROR;
+                        run = false;
+                    }
+                }
+            }
         }
 
         if dev_info.is_some() && Instant::now() > timeout_erase && !erase_began && !erase_done && !write_done && speed_set_done && speed_get_done {
             println!("{}\tHOST: send SFU_CMD_ERASE", timeline.elapsed().as_millis());
-            if params.erase_only {
+            phases.end("speed");
+            phases.begin("erase");
+            if params.command == Command::Erase {
                 if let Some(info) = &dev_info {
                     let cmd_erase = packet_build(SFU_CMD_ERASE, &bytes![serialize_u32!(info.flash_size_correct)]);
                     write_all_serial(&mut *port, &cmd_erase).expect("Write ERROR");
@@ -365,22 +682,29 @@ fn main() -> ExitCode {
         }
 
         if Instant::now() > timeout_write && ((erase_began && !params.no_prewrite) || erase_done) &&  !write_done && speed_set_done && speed_get_done {
-            if ((inflight_bytes_estimate + write_actual_size*2) < inflight_bytes_limit) && 
-                ((write_bulk_size + write_actual_size*2) < write_bulk_limit) 
+            let frame_len = write_block.frame_len();
+            if ((inflight_bytes_estimate + frame_len*2) < inflight_bytes_limit) && 
+                ((write_bulk_size + frame_len*2) < write_bulk_limit) 
             {
                 let size_before = inflight_bytes_estimate;
-                send_write_command(&timeline, &mut *port, &mut wr_addr_host, addr_shift, &fw_bin, &mut inflight_bytes_estimate).expect("Write error!");
-                if write_actual_size == WR_BLOCK_SIZE {
-                    write_actual_size = inflight_bytes_estimate - size_before;
+                write_errors_seen.get_or_insert(packet.stat_error_packets());
+                let sent_at = Instant::now();
+                let addr_before = wr_addr_host;
+                phases.begin("write");
+                send_write_command(timeline, &mut *port, &mut wr_addr_host, addr_shift, fw_bin, write_block.size(), &mut inflight_bytes_estimate).expect("Write error!");
+                if wr_addr_host != addr_before {
+                    write_latency.sent(wr_addr_host, sent_at);
+                    inflight_frames.push_back(inflight_bytes_estimate - size_before);
                 }
-                write_bulk_size += write_actual_size;
+                write_bulk_size += inflight_bytes_estimate - size_before;
             } else {
                 timeout_write = Instant::now() + Duration::from_millis(10);
             }
         }
 
-        if erase_done && write_done && speed_set_done && speed_get_done && Instant::now() > timeout_start {
+        if dev_info.is_some() && erase_done && write_done && speed_set_done && speed_get_done && Instant::now() > timeout_start {
             println!("{}\tHOST: send SFU_CMD_START", timeline.elapsed().as_millis());
+            phases.begin("start");
             write_all_serial(&mut *port, &cmd_start).expect("Write ERROR");
             timeout_start = Instant::now() + Duration::from_millis(1000);
         }
@@ -417,12 +741,28 @@ fn main() -> ExitCode {
                         wr_addr_host = info.main_start_from;
                         addr_shift = info.main_start_from;
                         inflight_bytes_limit = info.receive_size as usize;
-                        run = !params.info_only;
-
+                        write_block.limit(info.receive_size);
+                        run = params.command != Command::Info;
+
+                        if let Some(tries) = auto_baud_tries.take() {
+                            let baud = AUTO_BAUD_RATES[(tries - 1) % AUTO_BAUD_RATES.len()];
+                            println!("{}\tHOST: init speed detected: {baud} baud", timeline.elapsed().as_millis());
+                            if params.main_speed_follows_init() {
+                                baud_main = baud;
+                                self_close = Instant::now() + session_timeout(baud_main);
+                            }
+                            cmd_speed_set = packet_build(SFU_CMD_SPEED, &bytes![serialize_u32!(baud_main)]);
+                            speed_get_done = start_only || (!params.main_speed_max && baud_main == baud);
+                            speed_set_done = speed_get_done;
+                        }
                         if info.sfu_ver < 0x200 { //check not supported SFU_CMD_SPEED
                             speed_get_done = true;
                             speed_set_done = true;
                         }
+                        phases.end("info");
+                        if !(speed_get_done && speed_set_done) {
+                            phases.begin("speed");
+                        }
                     } else {
                         println!("{}\tHOST: SFU INFO PARSING ERROR", timeline.elapsed().as_millis());                        
                         result = RESULT_INFO_ERROR;
@@ -440,7 +780,8 @@ fn main() -> ExitCode {
                 while let Some(body) = packet.packets[SFU_CMD_ERASE as usize].pop_front() {
                     println!("{}\tHOST: response to SFU_CMD_ERASE was received: {:2X}:{:02X?} ERASE DONE", timeline.elapsed().as_millis(), SFU_CMD_ERASE, body.as_slice());
                     erase_done = true;
-                    run = !params.erase_only;
+                    phases.end("erase");
+                    run = params.command != Command::Erase;
                 };
 
                 while let Some(body) = packet.packets[SFU_CMD_SPEED as usize].pop_front() {
@@ -477,14 +818,23 @@ fn main() -> ExitCode {
                 while let Some(body) = packet.packets[SFU_CMD_WRITE as usize].pop_front() {
                     let write_info = parse_write_info(body.as_slice());
                     if let Some(info) = &write_info {
+                        write_latency.acked(info.mcu_write_addr, Instant::now());
                         write_bulk_size = 0;
-                        if inflight_bytes_estimate < write_actual_size {
-                            inflight_bytes_estimate = 0
-                        } else {
-                            inflight_bytes_estimate -= write_actual_size;
+                        let acked = inflight_frames.pop_front().unwrap_or(0);
+                        inflight_bytes_estimate = inflight_bytes_estimate.saturating_sub(acked);
+                        let errors = packet.stat_error_packets();
+                        let resend = last_mcu_addr == info.mcu_write_addr;
+                        if resend || errors > write_errors_seen.unwrap_or(errors) {
+                            if write_block.trouble(info.mcu_write_addr) {
+                                println!("{}\tHOST: write block size down to {} bytes", timeline.elapsed().as_millis(), write_block.size());
+                            }
+                        } else if write_block.clean_ack() {
+                            println!("{}\tHOST: write block size up to {} bytes", timeline.elapsed().as_millis(), write_block.size());
                         }
-                        if last_mcu_addr == info.mcu_write_addr {
+                        write_errors_seen = Some(errors);
+                        if resend {
                             wr_addr_host = info.mcu_write_addr;
+                            write_latency.resend();
                             println!("{}\tHOST: Write address corrected at 0x{:08X}", timeline.elapsed().as_millis(), wr_addr_host);
                             timeout_write = Instant::now() + resend_timeout;
                             resend_timeout += Duration::from_millis(250);
@@ -498,6 +848,7 @@ fn main() -> ExitCode {
                         if let Some(dev_info) = &dev_info {
                             if info.mcu_write_addr == dev_info.firmware_end_at {
                                 write_done = true;
+                                phases.end("write");
                                 println!("{}\tHOST: ================ Write done =================", timeline.elapsed().as_millis());
                             }
                         }
@@ -520,6 +871,7 @@ fn main() -> ExitCode {
                         self_close = Instant::now() + Duration::from_millis(500);
                         result = RESULT_SUCCESS;
                         start_done = true;
+                        phases.end("start");
                     };
                 }
                 while let Some(body) = packet.packets[SFU_CMD_WRERROR as usize].pop_front() {
@@ -537,49 +889,423 @@ fn main() -> ExitCode {
             Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {
                 packet.tick(); //for log timeouts checking
             },
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                println!("{}\tHOST: {e}", timeline.elapsed().as_millis());
+                run = false;
+            },
             Err(e) => {
                 panic!("{:?}", e);            
             }
         }
 
-        while let Some(str) = packet.logs.pop_front() {
-            println!("{}\tDEVICE: {str}", timeline.elapsed().as_millis());
+        print_device_log(timeline, packet, device_log);
+    }
+
+    SessionOutcome { result, erase_done, write_done, start_done, stat_write_resend_errors, info_attempts: attempt + 1, phases, write_latency, speed_fallback }
+}
+
+fn main() -> ExitCode {
+    let timeline = Instant::now();
+    let params = parse_cmdline_from_env();
+    if params.is_none() {
+        show_port_list();
+        return ExitCode::from(RESULT_PARAM_ERROR);
+    }
+    let params = params.unwrap();
+
+    if params.list_reset_profiles {
+        if params.config_files.is_empty() {
+            println!("No configuration files found ({} in current directory or its parents, or user config)", config::CONFIG_FILE_NAME);
+        }
+        for path in &params.config_files {
+            println!("config: {}", path.display());
+        }
+        for p in &params.reset_profiles {
+            let seq = &p.sequence;
+            println!("{:<24} {}", p.name, p.description);
+            println!("{:<24} backend {}, sequence \"{seq}\"", "", p.backend);
+            let mask = seq.gpio_mask();
+            let pins: Vec<String> = (0..16).filter(|bit| mask & (1 << bit) != 0).map(|bit| seq.pin_name(bit)).collect();
+            if !pins.is_empty() {
+                println!("{:<24} pins {}", "", pins.join(", "));
+            }
+            if let Some(lines) = &p.gpiochip {
+                println!("{:<24} gpiochip {} lines {:?}", "", lines.chip, lines.offsets);
+            }
+            println!("{:<24} from {}", "", p.source.display());
+        }
+        return ExitCode::from(RESULT_SUCCESS);
+    }
+
+    if params.print_config {
+        for path in &params.config_files {
+            println!("config: {}", path.display());
         }
+        let opt = |v: Option<String>| v.unwrap_or_else(|| "-".to_string());
+        let effective = [
+            ("port", params.port.clone()),
+            ("init-speed", if params.init_speed_auto { "auto".to_string() } else { params.baud_init.to_string() }),
+            ("main-speed", if params.main_speed_max {
+                "max".to_string()
+            } else if params.main_speed_follows_init() {
+                "init speed".to_string()
+            } else {
+                params.baud_main.to_string()
+            }),
+            ("speed-cache", params.speed_cache.to_string()),
+            ("firmware", opt(params.firmware_path.clone())),
+            ("no-prewrite", params.no_prewrite.to_string()),
+            ("reset-profile", opt(params.reset_profile.clone())),
+            ("reset-seq", opt(params.reset.as_ref().map(|s| format!("\"{s}\"")))),
+            ("reset-backend", params.reset_backend.to_string()),
+            ("reset-after", opt(params.reset_after.as_ref().map(|s| format!("\"{s}\"")))),
+            ("reset-after-always", params.reset_after_always.to_string()),
+            ("retries", params.retries.to_string()),
+            ("info-timeout", opt(params.info_timeout_ms.map(|ms| format!("{ms}ms")))),
+            ("device-log", opt(params.device_log.clone())),
+            ("trace", opt(params.trace.clone())),
+            ("report", opt(params.report.clone())),
+            ("monitor", params.monitor.to_string()),
+            ("monitor-baud", opt(params.monitor_baud.map(|b| b.to_string()))),
+            ("monitor-timeout", opt(params.monitor_timeout_ms.map(|ms| format!("{ms}ms")))),
+            ("gpiochip", opt(params.gpiochip.as_ref().map(|l| format!("{} lines {:?}", l.chip, l.offsets)))),
+        ];
+        for (key, value) in effective {
+            let from = params.origin.iter().find(|(k, _)| *k == key).map_or("default", |(_, o)| o.as_str());
+            println!("{key:<20} {value:<32} ({from})");
+        }
+        return ExitCode::from(RESULT_SUCCESS);
     }
 
+    if params.gpio_read {
+        return match cp210x_gpio_read(&params.port) {
+            Ok(state) => {
+                let part = state.part.map(|p| p.to_string()).unwrap_or_else(|| "CP210x".to_string());
+                println!("{}\tHOST: {} GPIO latch: 0x{:04X} (GPIO mask 0x{:04X})", timeline.elapsed().as_millis(), part, state.latch, state.gpio_mask);
+                for bit in 0..16 {
+                    if state.gpio_mask & (1 << bit) != 0 {
+                        println!("{}\tHOST:   GPIO{bit:<2} = {}", timeline.elapsed().as_millis(), (state.latch >> bit) & 1);
+                    }
+                }
+                ExitCode::from(RESULT_SUCCESS)
+            }
+            Err(e) => {
+                eprintln!("{}\tHOST: GPIO read error: {e}", timeline.elapsed().as_millis());
+                ExitCode::from(RESULT_RESET_ERROR)
+            }
+        };
+    }
+
+    match params.command {
+        Command::Help => return ExitCode::from(RESULT_SUCCESS),
+        Command::Version => {
+            print_version();
+            return ExitCode::from(RESULT_SUCCESS);
+        }
+        Command::Ports => {
+            show_port_list();
+            return ExitCode::from(RESULT_SUCCESS);
+        }
+        Command::Crc => {
+            let fname = params.firmware_path.as_deref().unwrap_or_default();
+            return match load_firmware(fname) {
+                Ok(bin) => {
+                    println!("{fname}: {} (0x{:08X}) bytes, CRC32_SFU = 0x{:08X}", bin.len(), bin.len(), crc32_sfu(&bin));
+                    ExitCode::from(RESULT_SUCCESS)
+                }
+                Err(e) => {
+                    eprintln!("{fname}: load error: {e}");
+                    ExitCode::from(RESULT_FW_LOAD_ERROR)
+                }
+            };
+        }
+        Command::Decode => {
+            let mut streams = Vec::new();
+            for path in &params.captures {
+                match decode::load_capture(path) {
+                    Ok(s) => streams.extend(s),
+                    Err(e) => {
+                        eprintln!("Error: {e}");
+                        return ExitCode::from(RESULT_PARAM_ERROR);
+                    }
+                }
+            }
+            for line in decode::decode_captures(&streams) {
+                println!("{line}");
+            }
+            return ExitCode::from(RESULT_SUCCESS);
+        }
+        Command::Reset => {
+            let mut port = open_session_port(&timeline, &params, params.baud_init);
+            let seqs = [(&params.reset, "reset"), (&params.reset_after, "reset-after")];
+            for (seq, stage) in seqs {
+                if let Some(seq) = seq
+                    && !run_reset(&timeline, &params, &mut port, seq, stage)
+                {
+                    return ExitCode::from(RESULT_RESET_ERROR);
+                }
+            }
+            return ExitCode::from(RESULT_SUCCESS);
+        }
+        Command::Monitor => return monitor(&timeline, &params),
+        Command::Flash | Command::Info | Command::Erase | Command::Start => {}
+    }
+
+    let mut fw_bin = vec![];
+    let mut fw_crc32 = 0u32;
+    if let Some(fname) = &params.firmware_path {
+        println!("{}\tHOST: load firmware file {}", timeline.elapsed().as_millis(), fname);
+        fw_bin = if let Ok(bin) = load_firmware(fname) {
+            bin
+        } else {
+            eprintln!("{}\tHOST: load error", timeline.elapsed().as_millis());
+            return ExitCode::from(RESULT_FW_LOAD_ERROR);
+        };
+        fw_crc32 = crc32_sfu(&fw_bin);
+        println!("{}\tHOST: loaded {} (0x{:08X}) bytes, CRC32_SFU = 0x{:08X}", timeline.elapsed().as_millis(), fw_bin.len(), fw_bin.len(), fw_crc32);
+    };
+    
+    let (mut device_log, trace) = match open_log_files(&params) {
+        Ok(files) => files,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            return ExitCode::from(RESULT_PARAM_ERROR);
+        }
+    };
+
+    if let Some(path) = &params.replay {
+        return replay(&timeline, &params, path, &fw_bin, fw_crc32, &mut device_log);
+    }
+
+    // The port stays open from reset through upload: reopening it would toggle
+    // DTR/RTS behind the reset sequence's back.
+    let session_port = open_session_port(&timeline, &params, params.baud_init);
+    let mut reset_port = session_port.try_clone_native().expect("Failed to clone port handle");
+    let mut port = traced_port(session_port, trace);
+    // thread::sleep(Duration::from_millis(32));
+    // let _ = port.clear_break();
+    // let _ = port.clear(serialport::ClearBuffer::All);
+    // thread::sleep(Duration::from_millis(32));
+    // let _ = port.clear(serialport::ClearBuffer::All);
+
+    let mut reset_phases = Phases::default();
+    if let Some(rst_seq) = &params.reset {
+        let start = Instant::now();
+        if !run_reset(&timeline, &params, &mut reset_port, rst_seq, "reset") {
+            return ExitCode::from(RESULT_RESET_ERROR);
+        }
+        reset_phases.record("reset", start, Instant::now());
+        // Whatever the board sent while in reset is not part of the session.
+        let _ = port.clear(serialport::ClearBuffer::Input);
+    }
+
+    let mut packet = PacketParser::new();
+    let mut session = run_session(&timeline, &params, &mut *port, &mut packet, &mut device_log, &fw_bin, fw_crc32, host_timeout, &mut |seq| {
+        run_reset(&timeline, &params, &mut reset_port, seq, "reset")
+    });
+    let mut result = session.result;
+
     // thread::sleep(Duration::from_millis(64));
     // let _ = send_blocking(&mut *port, &mut packet, SFU_CMD_INFO, &[], 3000);
 
-    if (packet.stat_crc_error_packets != 0) ||
-       (packet.stat_incomplete_bytes != 0) ||
-       (packet.stat_other_error_packets != 0) ||
-       (packet.stat_size_or_code_error_packets != 0) ||
-       (packet.stat_log_bytes == 0) ||
-       (packet.stat_log_lines == 0) ||
-       (packet.stat_valid_packets == 0)
-    {
-        println!("");
-        packet.print_stats();
-        println!("");
+    print_parser_stats(&packet);
 
-        if packet.current_log_line.len() != 0 {
-            println!("WARNING: non finished device log line: {}", packet.current_log_line);
+    if let Some(after_seq) = &params.reset_after {
+        let started = session.start_done && result == RESULT_SUCCESS;
+        if started || params.reset_after_always {
+            let start = Instant::now();
+            if !run_reset(&timeline, &params, &mut reset_port, after_seq, "reset-after") && result == RESULT_SUCCESS {
+                result = RESULT_RESET_ERROR;
+            }
+            reset_phases.record("reset-after", start, Instant::now());
+        } else {
+            println!("{}\tHOST: reset-after skipped, firmware was not started", timeline.elapsed().as_millis());
         }
     }
 
-    if result == RESULT_HOST_TIMEOUT_ERROR {
-        println!("ERROR: HOST TIMEOUT!!!");
-    } else if !(write_done && erase_done && start_done && (result == RESULT_SUCCESS)) {
-        println!("WARNING: UPDATING NOT FINISHED!!!!");
-    }
+    session.phases.extend(reset_phases);
+    let report = session_report(&timeline, &params, &packet, &session, result, fw_bin.len(), port.baud_rate().ok());
 
-    let mut stat_unhandled_commands = 0;
-    for cmd_code in &packet.packets {
-        stat_unhandled_commands += cmd_code.len();
+    if params.monitor && session.start_done && result == RESULT_SUCCESS {
+        if let Some(baud) = params.monitor_baud {
+            port.set_baud_rate(baud).expect("ERROR: port.set_baud_rate");
+            println!("{}\tHOST: Baud rate changed to {baud} for monitor", timeline.elapsed().as_millis());
+        }
+        if let Err(e) = monitor_port(&timeline, &mut *port, &mut packet, &mut device_log, params.monitor_timeout_ms) {
+            eprintln!("{}\tHOST: monitor read error: {e}", timeline.elapsed().as_millis());
+        }
     }
-    if (stat_unhandled_commands + stat_write_resend_errors) !=0 {
-        println!("WARNING: stat_write_resend_errors: {stat_write_resend_errors}");
-        println!("WARNING: stat_unhandled_commands:  {stat_unhandled_commands}");
+
+    if let Some(log) = &mut device_log {
+        log.finish(timeline.elapsed().as_millis(), &packet.current_log_line);
     }
+
+    finish_report(&report, &params);
+    print_session_warnings(&packet, &session, result);
     return ExitCode::from(result);
 }
+
+// ---- Unit tests ----
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{args, device_frame, records, sfu_info_body, MAIN_START};
+    use crate::trace::TraceEvent;
+
+    #[test]
+    fn flash_session_replays_a_recording() {
+        let args = args(&["flash", "--no-prewrite", "--replay", "unused.trace", "fw.bin"]);
+        let params = cmdline::parse_cmdline(&args).unwrap();
+        let fw_bin: Vec<u8> = (0..0x1000u32).map(|i| i as u8).collect();
+        let fw_crc32: u32 = 0x1234_5678;
+        let start = MAIN_START;
+        let info = sfu_info_body(0x0105); // no SFU_CMD_SPEED
+        let write = |addr: u32| packet_build(SFU_CMD_WRITE, &[&addr.to_le_bytes(), &fw_bin[(addr - start) as usize..][..WR_BLOCK_SIZE]].concat());
+        let ack = |addr: u32| device_frame(SFU_CMD_WRITE, &[addr.to_le_bytes(), 0u32.to_le_bytes()].concat());
+
+        let events = [
+            TraceEvent::Baud(params.baud_init),
+            TraceEvent::Tx(packet_build(SFU_CMD_INFO, &[])),
+            TraceEvent::Rx(device_frame(SFU_CMD_INFO, &info)),
+            TraceEvent::Tx(packet_build(SFU_CMD_ERASE, &(fw_bin.len() as u32).to_le_bytes())),
+            TraceEvent::Rx(device_frame(SFU_CMD_ERASE, &[0; 4])),
+            TraceEvent::Tx(write(start)),
+            TraceEvent::Tx(write(start + 0x800)),
+            TraceEvent::Rx(ack(start + 0x800)),
+            TraceEvent::Rx(ack(start + 0x1000)),
+            TraceEvent::Tx(packet_build(SFU_CMD_START, &fw_crc32.to_le_bytes())),
+            TraceEvent::Rx(device_frame(SFU_CMD_START, &[start.to_le_bytes(), 0x1000u32.to_le_bytes(), fw_crc32.to_le_bytes()].concat())),
+        ];
+        let records = records(events);
+
+        let mut port = ReplayPort::new(&records, ReplayTiming::Fast);
+        let mut packet = PacketParser::new();
+        let session = run_session(&Instant::now(), &params, &mut port, &mut packet, &mut None, &fw_bin, fw_crc32, host_timeout, &mut |_| true);
+
+        assert_eq!(session.result, RESULT_SUCCESS);
+        assert!(session.erase_done && session.write_done && session.start_done);
+        assert_eq!(port.report(), Vec::<String>::new());
+
+        let report = session_report(&Instant::now(), &params, &packet, &session, session.result, fw_bin.len(), None);
+        let phases: Vec<(&str, bool)> = report.phases.iter().map(|p| (p.0, p.2.is_some())).collect();
+        assert_eq!(phases, [("info", true), ("erase", true), ("write", true), ("start", true)]);
+        assert_eq!((report.info_attempts, report.write_resends, report.unhandled.len()), (1, 0, 0));
+        assert_eq!(report.write_ack.map(|w| w.count), Some(2));
+    }
+
+    #[test]
+    fn init_speed_auto_finds_the_answering_rate() {
+        let args = args(&["info", "-si", "auto", "--replay", "unused.trace"]);
+        let params = cmdline::parse_cmdline(&args).unwrap();
+        let info = sfu_info_body(0x0200);
+
+        // Silent at the first candidate, answers at the second.
+        let events = [
+            TraceEvent::Baud(AUTO_BAUD_RATES[0]),
+            TraceEvent::Tx(packet_build(SFU_CMD_INFO, &[])),
+            TraceEvent::Baud(AUTO_BAUD_RATES[1]),
+            TraceEvent::Tx(packet_build(SFU_CMD_INFO, &[])),
+            TraceEvent::Rx(device_frame(SFU_CMD_INFO, &info)),
+        ];
+        let records = records(events);
+
+        let mut port = ReplayPort::new(&records, ReplayTiming::Fast);
+        let mut packet = PacketParser::new();
+        let session = run_session(&Instant::now(), &params, &mut port, &mut packet, &mut None, &[], 0, host_timeout, &mut |_| true);
+
+        assert_eq!((session.info_attempts, packet.stat_valid_packets), (1, 1));
+        assert_eq!(port.report(), Vec::<String>::new());
+        assert_eq!(port.baud_rate().unwrap(), AUTO_BAUD_RATES[1]);
+    }
+
+    #[test]
+    fn main_speed_max_falls_back_to_the_last_clean_rate() {
+        let args = args(&["erase", "-sm", "max", "--replay", "unused.trace"]);
+        let params = cmdline::parse_cmdline(&args).unwrap();
+        let info = sfu_info_body(0x0200);
+        let speed = |baud: u32| packet_build(SFU_CMD_SPEED, &baud.to_le_bytes());
+        let change = |old: u32, new: u32| device_frame(SFU_CMD_SPEED, &[old.to_le_bytes(), new.to_le_bytes()].concat());
+        let info_tx = || TraceEvent::Tx(packet_build(SFU_CMD_INFO, &[]));
+        let info_rx = || TraceEvent::Rx(device_frame(SFU_CMD_INFO, &info));
+
+        // 1000000 is clean, 1500000 does not answer INFO.
+        let mut events = vec![TraceEvent::Baud(921600), info_tx(), info_rx()];
+        events.extend([TraceEvent::Tx(speed(1000000)), TraceEvent::Rx(change(921600, 1000000)), TraceEvent::Baud(1000000)]);
+        for _ in 0..8 {
+            events.extend([info_tx(), info_rx()]);
+        }
+        events.extend([TraceEvent::Tx(speed(1500000)), TraceEvent::Rx(change(1000000, 1500000)), TraceEvent::Baud(1500000), info_tx()]);
+        events.extend([TraceEvent::Tx(speed(1000000)), TraceEvent::Rx(change(1500000, 1000000)), TraceEvent::Baud(1000000), info_tx(), info_rx()]);
+        events.extend([
+            TraceEvent::Tx(packet_build(SFU_CMD_ERASE, &0x40000u32.to_le_bytes())),
+            TraceEvent::Rx(device_frame(SFU_CMD_ERASE, &[0; 4])),
+        ]);
+        let records = records(events);
+
+        let mut port = ReplayPort::new(&records, ReplayTiming::Fast);
+        let mut packet = PacketParser::new();
+        let session = run_session(&Instant::now(), &params, &mut port, &mut packet, &mut None, &[], 0, host_timeout, &mut |_| true);
+
+        assert!(session.erase_done);
+        assert_eq!(port.report(), Vec::<String>::new());
+        assert_eq!(port.baud_rate().unwrap(), 1000000);
+    }
+
+    #[test]
+    fn lost_speed_change_answer_is_recovered() {
+        let args = args(&["erase", "-sm", "2000000", "--replay", "unused.trace"]);
+        let params = cmdline::parse_cmdline(&args).unwrap();
+        let info = sfu_info_body(0x0200);
+        let set = packet_build(SFU_CMD_SPEED, &2000000u32.to_le_bytes());
+        let mut corrupted = device_frame(SFU_CMD_SPEED, &[921600u32.to_le_bytes(), 2000000u32.to_le_bytes()].concat());
+        *corrupted.last_mut().unwrap() ^= 0xFF;
+
+        // The device switched after the first SET, its CHANGE answer arrived broken.
+        let events = [
+            TraceEvent::Baud(921600),
+            TraceEvent::Tx(packet_build(SFU_CMD_INFO, &[])),
+            TraceEvent::Rx(device_frame(SFU_CMD_INFO, &info)),
+            TraceEvent::Tx(packet_build(SFU_CMD_SPEED, &[])),
+            TraceEvent::Rx(device_frame(SFU_CMD_SPEED, &921600u32.to_le_bytes())),
+            TraceEvent::Tx(set.clone()),
+            TraceEvent::Rx(corrupted),
+            TraceEvent::Tx(set),
+            TraceEvent::Baud(2000000),
+            TraceEvent::Tx(packet_build(SFU_CMD_INFO, &[])),
+            TraceEvent::Rx(device_frame(SFU_CMD_INFO, &info)),
+            TraceEvent::Tx(packet_build(SFU_CMD_SPEED, &[])),
+            TraceEvent::Rx(device_frame(SFU_CMD_SPEED, &2000000u32.to_le_bytes())),
+            TraceEvent::Tx(packet_build(SFU_CMD_ERASE, &0x40000u32.to_le_bytes())),
+            TraceEvent::Rx(device_frame(SFU_CMD_ERASE, &[0; 4])),
+        ];
+        let records = records(events);
+
+        let mut port = ReplayPort::new(&records, ReplayTiming::Fast);
+        let mut packet = PacketParser::new();
+        let session = run_session(&Instant::now(), &params, &mut port, &mut packet, &mut None, &[], 0, host_timeout, &mut |_| true);
+
+        assert!(session.erase_done);
+        assert_eq!(session.speed_fallback, None);
+        assert_eq!(port.report(), Vec::<String>::new());
+        assert_eq!((port.baud_rate().unwrap(), packet.stat_crc_error_packets), (2000000, 1));
+    }
+
+    #[test]
+    fn info_retries_end_at_the_host_timeout() {
+        let args = args(&["info", "--replay", "unused.trace", "--retries", "5", "--info-timeout", "200"]);
+        let params = cmdline::parse_cmdline(&args).unwrap();
+        // More INFO frames than any run sends, so the recording does not end the session.
+        let events = std::iter::once(TraceEvent::Baud(params.baud_init)).chain((0..10).map(|_| TraceEvent::Tx(packet_build(SFU_CMD_INFO, &[]))));
+        let records = records(events);
+
+        let mut port = ReplayPort::new(&records, ReplayTiming::Fast);
+        let mut packet = PacketParser::new();
+        let begin = Instant::now();
+        let session = run_session(&begin, &params, &mut port, &mut packet, &mut None, &[], 0, |_, _| Duration::from_millis(500), &mut |_| true);
+
+        // Restarting the host timeout per retry would run all 6 attempts into RESULT_INFO_ERROR.
+        assert_eq!((session.result, session.info_attempts), (RESULT_HOST_TIMEOUT_ERROR, 3));
+        assert!(begin.elapsed() < Duration::from_millis(700));
+    }
+}
@@ -0,0 +1,252 @@
+//! `--main-speed max`: the fastest baud rate the adapter and cable carry cleanly.
+//!
+//! Every rate of `MAX_SPEED_RATES` above the current one is switched to with
+//! SFU_CMD_SPEED and checked with a burst of SFU_CMD_INFO round trips. A rate is
+//! clean when every INFO is answered and the `PacketParser` error counters did
+//! not move. The first rate that is not clean ends the search and the device is
+//! switched back to the last clean one.
+//!
+//! With `--speed-cache` the result is kept per USB serial number in
+//! `speed-cache` next to the user `sfu.toml`, one `<serial> <baud>` line per
+//! adapter. A cached rate is the only candidate tried; when it is no longer
+//! clean it is forgotten and the next run searches again.
+//!
+//! `SpeedProbe::resync` is also what the session uses when an SFU_CMD_SPEED
+//! switch gets no CHANGE answer: the device may have switched and only the
+//! answer was lost, so INFO is tried at both rates.
+//!
+//! A port error other than a read timeout ends the search: it is not the
+//! rate that failed but the port, and no other rate would do better.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use serialport::{SerialPort, SerialPortType};
+
+use super::config::speed_cache_path;
+use super::devlog::DeviceLog;
+use super::packet::{packet_build, PacketParser, PacketParserExt};
+use super::protocol::{parse_speed_info, SpeedInfo, SFU_CMD_INFO, SFU_CMD_SPEED};
+use super::{print_device_log, write_all_serial};
+
+/// Candidates in the order they are tried.
+pub const MAX_SPEED_RATES: &[u32] = &[115200, 230400, 460800, 921600, 1000000, 1500000, 2000000, 3000000, 4000000];
+/// INFO round trips per candidate rate.
+const INFO_BURST: usize = 8;
+const INFO_WAIT: Duration = Duration::from_millis(200);
+const SPEED_WAIT: Duration = Duration::from_millis(300);
+const SPEED_TRIES: usize = 3;
+/// Rounds of INFO at the new and then the old rate in `resync`.
+const RESYNC_ROUNDS: usize = 3;
+
+/// The session's port and parser, borrowed for the search.
+pub struct SpeedProbe<'a> {
+    pub timeline: &'a Instant,
+    pub port: &'a mut dyn SerialPort,
+    pub packet: &'a mut PacketParser,
+    pub device_log: &'a mut Option<DeviceLog>,
+}
+
+impl SpeedProbe<'_> {
+    /// Fastest clean rate of `candidates` above `current` (or `current` itself);
+    /// `Err` on a port error or when the device does not answer at the rate it
+    /// was switched back to.
+    pub fn run(&mut self, current: u32, candidates: &[u32]) -> Result<u32, String> {
+        let port_error = |e: io::Error| format!("max speed: search aborted, port error: {e}");
+        let mut clean = current;
+        for &baud in candidates.iter().filter(|&&b| b > current) {
+            self.log(&format!("max speed: trying {baud} baud"));
+            if let Some(reached) = self.switch(baud).map_err(port_error)?
+                && self.burst_is_clean().map_err(port_error)?
+            {
+                clean = reached;
+                continue;
+            }
+            self.log(&format!("max speed: {baud} baud is not clean, back to {clean}"));
+            return match self.fall_back(clean).map_err(port_error)? {
+                true => Ok(clean),
+                false => Err(format!("max speed: no answer at {clean} baud after the search")),
+            };
+        }
+        Ok(clean)
+    }
+
+    /// SFU_CMD_SPEED to `baud`; the rate the device reports it changed to,
+    /// or the one it answers INFO at when the CHANGE answer never came.
+    fn switch(&mut self, baud: u32) -> io::Result<Option<u32>> {
+        let old = self.port.baud_rate()?;
+        let frame = packet_build(SFU_CMD_SPEED, &baud.to_le_bytes());
+        for _ in 0..SPEED_TRIES {
+            if let Some(body) = self.exchange(&frame, SFU_CMD_SPEED, SPEED_WAIT)?
+                && let Some(SpeedInfo::CHANGE(change)) = parse_speed_info(&body)
+            {
+                self.set_host_baud(change.new_bod)?;
+                return Ok(Some(change.new_bod));
+            }
+        }
+        self.log(&format!("no answer to SFU_CMD_SPEED({baud}), probing {baud} and {old} baud"));
+        Ok(self.resync(old, baud)?.filter(|&at| at == baud))
+    }
+
+    /// Find the device after a speed switch without answer: INFO at `new`, then
+    /// at `old`, a few rounds. The rate it answered at, the host stays there.
+    pub fn resync(&mut self, old: u32, new: u32) -> io::Result<Option<u32>> {
+        let info = packet_build(SFU_CMD_INFO, &[]);
+        for _ in 0..RESYNC_ROUNDS {
+            for baud in [new, old] {
+                if self.port.baud_rate()? != baud {
+                    self.set_host_baud(baud)?;
+                }
+                if self.exchange(&info, SFU_CMD_INFO, INFO_WAIT)?.is_some() {
+                    self.log(&format!("device answers at {baud} baud"));
+                    return Ok(Some(baud));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Back to `clean` after a failed candidate; `false` when the device does not answer there.
+    fn fall_back(&mut self, clean: u32) -> io::Result<bool> {
+        if self.port.baud_rate()? != clean && self.switch(clean)?.is_none() {
+            // The CHANGE answer may be what got lost: try the clean rate anyway.
+            self.set_host_baud(clean)?;
+        }
+        let info = packet_build(SFU_CMD_INFO, &[]);
+        Ok(self.exchange(&info, SFU_CMD_INFO, INFO_WAIT)?.is_some())
+    }
+
+    fn burst_is_clean(&mut self) -> io::Result<bool> {
+        let info = packet_build(SFU_CMD_INFO, &[]);
+        let errors = self.packet.stat_error_packets();
+        for _ in 0..INFO_BURST {
+            if self.exchange(&info, SFU_CMD_INFO, INFO_WAIT)?.is_none() {
+                return Ok(false);
+            }
+        }
+        Ok(self.packet.stat_error_packets() == errors)
+    }
+
+    fn set_host_baud(&mut self, baud: u32) -> io::Result<()> {
+        let _ = self.port.clear(serialport::ClearBuffer::All);
+        self.port.set_baud_rate(baud)?;
+        std::thread::sleep(Duration::from_millis(1));
+        let _ = self.port.clear(serialport::ClearBuffer::All);
+        self.log(&format!("Baud rate changed to {baud} !"));
+        Ok(())
+    }
+
+    /// Send `frame` and wait for the next packet with `code`, printing device log lines meanwhile.
+    /// `Ok(None)` when `wait` runs out, `Err` on any port error but a read timeout.
+    fn exchange(&mut self, frame: &[u8], code: u8, wait: Duration) -> io::Result<Option<Vec<u8>>> {
+        self.packet.packets[code as usize].clear();
+        write_all_serial(&mut *self.port, frame)?;
+        let deadline = Instant::now() + wait;
+        let mut buf = [0u8; 1024];
+        while Instant::now() < deadline {
+            match self.port.read(&mut buf) {
+                Ok(n) => self.packet.receive_data(&buf[..n]),
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => self.packet.tick(),
+                Err(e) => return Err(e),
+            }
+            print_device_log(self.timeline, self.packet, self.device_log);
+            if let Some(body) = self.packet.packets[code as usize].pop_front() {
+                return Ok(Some(body));
+            }
+        }
+        Ok(None)
+    }
+
+    fn log(&self, text: &str) {
+        println!("{}\tHOST: {text}", self.timeline.elapsed().as_millis());
+    }
+}
+
+/// USB serial number of the adapter behind `port`, also through /dev/serial/by-id links.
+pub fn usb_serial_number(port: &str) -> Option<String> {
+    let wanted = fs::canonicalize(port).ok();
+    serialport::available_ports().ok()?.into_iter().find_map(|p| {
+        let same = p.port_name == port || (wanted.is_some() && fs::canonicalize(&p.port_name).ok() == wanted);
+        match p.port_type {
+            SerialPortType::UsbPort(usb) if same => usb.serial_number,
+            _ => None,
+        }
+    })
+}
+
+pub fn cached_speed(serial: &str) -> Option<u32> {
+    cached_speed_in(&speed_cache_path()?, serial)
+}
+
+/// Remember `baud` for `serial`, `None` forgets it.
+pub fn store_speed(serial: &str, baud: Option<u32>) -> Result<(), String> {
+    let path = speed_cache_path().ok_or("speed cache: no user config directory")?;
+    store_speed_in(&path, serial, baud)
+}
+
+fn cached_speed_in(path: &Path, serial: &str) -> Option<u32> {
+    let text = fs::read_to_string(path).ok()?;
+    parse_cache(&text).into_iter().find(|(s, _)| s == serial).map(|(_, baud)| baud)
+}
+
+fn store_speed_in(path: &Path, serial: &str, baud: Option<u32>) -> Result<(), String> {
+    let mut entries = fs::read_to_string(path).map(|text| parse_cache(&text)).unwrap_or_default();
+    entries.retain(|(s, _)| s != serial);
+    entries.extend(baud.map(|b| (serial.to_string(), b)));
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| format!("{}: {e}", dir.display()))?;
+    }
+    fs::write(path, format_cache(&entries)).map_err(|e| format!("{}: {e}", path.display()))
+}
+
+/// `<serial> <baud>` lines; the serial number may contain spaces, lines that do not parse are dropped.
+fn parse_cache(text: &str) -> Vec<(String, u32)> {
+    text.lines()
+        .filter_map(|line| {
+            let (serial, baud) = line.trim().rsplit_once(' ')?;
+            Some((serial.trim().to_string(), baud.parse().ok()?))
+        })
+        .collect()
+}
+
+fn format_cache(entries: &[(String, u32)]) -> String {
+    entries.iter().map(|(serial, baud)| format!("{serial} {baud}\n")).collect()
+}
+
+// ---- Unit tests ----
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cache_lines() {
+        let entries = parse_cache("0001 2000000\nA B C 921600\n\nbroken\nX fast\n");
+        assert_eq!(entries, [("0001".to_string(), 2000000), ("A B C".to_string(), 921600)]);
+        assert_eq!(format_cache(&entries), "0001 2000000\nA B C 921600\n");
+    }
+
+    #[test]
+    fn store_replaces_and_forgets() {
+        let root = std::env::temp_dir().join(format!("sfu-speed-cache-{}", std::process::id()));
+        let path = root.join("sfu").join("speed-cache");
+        let _ = fs::remove_dir_all(&root);
+
+        store_speed_in(&path, "0001", Some(921600)).unwrap();
+        assert!(path.is_file(), "the directory is created");
+        store_speed_in(&path, "A B C", Some(2000000)).unwrap();
+        store_speed_in(&path, "0001", Some(3000000)).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "A B C 2000000\n0001 3000000\n");
+        assert_eq!(cached_speed_in(&path, "0001"), Some(3000000));
+
+        store_speed_in(&path, "A B C", None).unwrap();
+        assert_eq!(cached_speed_in(&path, "A B C"), None);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "0001 3000000\n");
+        store_speed_in(&path, "unknown", None).unwrap();
+        assert_eq!(cached_speed_in(&path, "0001"), Some(3000000));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -1,4 +1,5 @@
 use std::thread;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc;
 use std::sync::mpsc::Receiver;
 use std::io::{self};
@@ -54,6 +55,38 @@ pub fn spawn_stdin_channel() -> Receiver<String> {
     rx
 }
 
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Turn Ctrl-C into a flag polled with `interrupted()` instead of killing the process.
+pub fn catch_interrupt() {
+    #[cfg(unix)]
+    {
+        extern "C" fn on_sigint(_: libc::c_int) {
+            INTERRUPTED.store(true, Ordering::SeqCst);
+        }
+        unsafe {
+            libc::signal(libc::SIGINT, on_sigint as extern "C" fn(libc::c_int) as libc::sighandler_t);
+        }
+    }
+    #[cfg(windows)]
+    {
+        unsafe extern "system" {
+            fn SetConsoleCtrlHandler(handler: Option<unsafe extern "system" fn(u32) -> i32>, add: i32) -> i32;
+        }
+        unsafe extern "system" fn on_ctrl(_: u32) -> i32 {
+            INTERRUPTED.store(true, Ordering::SeqCst);
+            1
+        }
+        unsafe {
+            SetConsoleCtrlHandler(Some(on_ctrl), 1);
+        }
+    }
+}
+
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
 #[allow(dead_code)]
 pub fn strip_trailing_newline(input: &str) -> &str {
     input
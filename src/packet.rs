@@ -9,12 +9,6 @@ pub const PACKET_SIGN_RX: u32 = 0x45A3_7E81;
 
 pub const MAX_PACKET_SIZE: usize = 4096;
 
-const SIGNATURE_BYTES: [u8; 4] = [
-    (PACKET_SIGN_RX >> 24) as u8,
-    (PACKET_SIGN_RX >> 16) as u8,
-    (PACKET_SIGN_RX >> 8) as u8,
-    (PACKET_SIGN_RX >> 0) as u8,
-];
 
 /// Build SFU-framed packet:
 /// [4 bytes sign][code][code^0xFF][len_lo][len_hi][body...][4 bytes CRC (LE)]
@@ -66,6 +60,15 @@ pub fn packet_build(code: u8, body: &[u8]) -> Vec<u8> {
     buf
 }
 
+/// One piece of the byte stream, kept in order when the parser records (see `PacketParser::recording`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StreamItem {
+    /// A complete frame; `crc_ok` is false when the CRC check failed.
+    Frame { code: u8, body: Vec<u8>, crc_ok: bool },
+    /// Bytes outside of frames (device log text), as received.
+    Log(Vec<u8>),
+}
+
 /// Parsed firmware packets storage and statistics.
 ///
 /// `packets[code]` contains the last successfully received body for that command code.
@@ -96,8 +99,13 @@ pub struct PacketParser {
 
     pub current_log_line: String,
 
+    /// Frames and log bytes in stream order, `None` unless recording.
+    pub stream: Option<VecDeque<StreamItem>>,
+
     // --- internal state ---
 
+    signature: [u8; 4],
+
     state: ParseState,
 
     current_code: u8,
@@ -179,6 +187,9 @@ impl PacketParserExt for PacketParser {
 
             state: ParseState::Idle,
             current_log_line: String::new(),
+            stream: None,
+
+            signature: PACKET_SIGN_RX.to_be_bytes(),
 
             current_code: 0,
             expected_size: 0,
@@ -195,7 +206,7 @@ impl PacketParserExt for PacketParser {
     fn receive_byte(&mut self, x: u8) {
         match self.state {
             ParseState::Idle => {
-                if x == SIGNATURE_BYTES[0] {
+                if x == self.signature[0] {
                     // Possible start of signature.
                     self.state = ParseState::WaitSignature { matched: 1 };
                 } else {
@@ -206,7 +217,7 @@ impl PacketParserExt for PacketParser {
 
             ParseState::WaitSignature { matched } => {
                 // We have already matched `matched` bytes of SIGNATURE_BYTES.
-                if matched < 4 && x == SIGNATURE_BYTES[matched as usize] {
+                if matched < 4 && x == self.signature[matched as usize] {
                     let new_matched = matched + 1;
                     if new_matched == 4 {
                         // Full signature matched: start packet header parsing.
@@ -217,10 +228,10 @@ impl PacketParserExt for PacketParser {
                 } else {
                     // Signature failed. Previous matched bytes are actually log bytes.
                     for i in 0..matched {
-                        self.handle_log_byte(SIGNATURE_BYTES[i as usize]);
+                        self.handle_log_byte(self.signature[i as usize]);
                     }
                     // Current byte may start a new signature or be a log byte.
-                    if x == SIGNATURE_BYTES[0] {
+                    if x == self.signature[0] {
                         self.state = ParseState::WaitSignature { matched: 1 };
                     } else {
                         self.state = ParseState::Idle;
@@ -372,6 +383,9 @@ impl PacketParserExt for PacketParser {
 
         self.state = ParseState::Idle;
         self.current_log_line.clear();
+        if let Some(stream) = &mut self.stream {
+            stream.clear();
+        }
         self.current_code = 0;
         self.expected_size = 0;
         self.body_buf.clear();
@@ -401,6 +415,21 @@ impl PacketParserExt for PacketParser {
 }
 
 impl PacketParser {
+    /// Frames dropped for CRC, size/code or other errors so far.
+    pub fn stat_error_packets(&self) -> u64 {
+        self.stat_crc_error_packets + self.stat_size_or_code_error_packets + self.stat_other_error_packets
+    }
+
+    /// Parser for one direction of a capture: frames starting with `sign`
+    /// (`PACKET_SIGN_TX` or `PACKET_SIGN_RX`), every frame and log byte kept in `stream`.
+    /// `packets` and `logs` stay empty, the stream is the only output.
+    pub fn recording(sign: u32) -> Self {
+        let mut parser = PacketParser::new();
+        parser.signature = sign.to_be_bytes();
+        parser.stream = Some(VecDeque::new());
+        parser
+    }
+
     /// Called when full signature has been matched.
     fn start_packet_after_signature(&mut self) {
         self.state = ParseState::HeaderCode;
@@ -415,19 +444,23 @@ impl PacketParser {
     /// Handle a single log byte (non-packet data).
     fn handle_log_byte(&mut self, b: u8) {
         self.stat_log_bytes += 1;
+        if let Some(stream) = &mut self.stream {
+            match stream.back_mut() {
+                Some(StreamItem::Log(bytes)) => bytes.push(b),
+                _ => stream.push_back(StreamItem::Log(vec![b])),
+            }
+        }
         self.timeout_log = Instant::now() + Duration::from_millis(250);
         match b {
             b'\n' => {
                 // End of line.
-                self.logs.push_back(std::mem::take(&mut self.current_log_line));
-                self.stat_log_lines += 1;
+                self.end_log_line();
             }
             32..=126 => {
                 // Printable ASCII.
                 self.current_log_line.push(b as char);
                 if self.current_log_line.len() >= 256 {
-                    self.logs.push_back(std::mem::take(&mut self.current_log_line));
-                    self.stat_log_lines += 1;
+                    self.end_log_line();
                 }
             }
             b'\r' => {}
@@ -445,6 +478,14 @@ impl PacketParser {
         }
     }
 
+    fn end_log_line(&mut self) {
+        let line = std::mem::take(&mut self.current_log_line);
+        if self.stream.is_none() {
+            self.logs.push_back(line);
+        }
+        self.stat_log_lines += 1;
+    }
+
     /// Abort current packet parsing due to a hard error.
     fn abort_current_packet(&mut self) {
         self.state = ParseState::Idle;
@@ -478,19 +519,32 @@ impl PacketParser {
         let crc_calc = crc32_sfu(&crc_input);
         let crc_recv = u32::from_le_bytes(self.crc_buf);
 
+        if let Some(stream) = &mut self.stream {
+            stream.push_back(StreamItem::Frame {
+                code: self.current_code,
+                body: self.body_buf.clone(),
+                crc_ok: crc_calc == crc_recv,
+            });
+        }
+
         if crc_calc == crc_recv {
             // Successful packet.
             let idx = self.current_code as usize;
             if idx < self.packets.len() {
-                self.packets[idx].push_back(self.body_buf.clone());
+                if self.stream.is_none() {
+                    self.packets[idx].push_back(self.body_buf.clone());
+                }
             } else {
                 // Should not happen as code is u8, but be safe.
                 self.stat_other_error_packets += 1;
             }
             self.stat_valid_packets += 1;
         } else {
-            println!("CRC32 Broken body: {:02X?}", crc_input);
-            println!("CRC32 Broken code: {}", self.current_code);
+            // A recording parser reports the CRC status with the frame instead.
+            if self.stream.is_none() {
+                println!("CRC32 Broken body: {:02X?}", crc_input);
+                println!("CRC32 Broken code: {}", self.current_code);
+            }
             // CRC error: ignore this whole packet.
             self.stat_crc_error_packets += 1;
         }
@@ -0,0 +1,243 @@
+//! pcapng export of the wire trace (`--trace FILE.pcapng`).
+//!
+//! The capture has one interface with the user link type `LINKTYPE_USER0`
+//! (147). Each direction is cut into SFU frames by a recording `PacketParser`;
+//! every frame, every run of bytes outside of frames (device log) and every
+//! baud rate change becomes one packet:
+//!
+//! ```text
+//! offset  size  field
+//! 0       1     direction: 0 host -> device, 1 device -> host
+//! 1       1     kind: 0 frame, 1 frame with CRC error, 2 log bytes, 3 baud rate
+//! 2       ...   frame:     code (1), body size (2, LE), body
+//!               log bytes: the bytes as received
+//!               baud rate: new rate (4, LE)
+//! ```
+//!
+//! The direction is also set in the standard `epb_flags` option and the packet
+//! comment names the command, so Wireshark can filter and time a session
+//! (`frame.comment contains "SFU_CMD_WRITE"`) without a dissector.
+
+use super::protocol::command_name;
+use super::packet::{PacketParser, PacketParserExt, StreamItem, PACKET_SIGN_RX, PACKET_SIGN_TX};
+
+pub const LINKTYPE_USER0: u16 = 147;
+
+pub const DIR_HOST_TO_DEVICE: u8 = 0;
+pub const DIR_DEVICE_TO_HOST: u8 = 1;
+
+pub const KIND_FRAME: u8 = 0;
+pub const KIND_FRAME_CRC_ERROR: u8 = 1;
+pub const KIND_LOG: u8 = 2;
+pub const KIND_BAUD: u8 = 3;
+
+const BLOCK_SHB: u32 = 0x0A0D_0D0A;
+const BLOCK_IDB: u32 = 0x0000_0001;
+const BLOCK_EPB: u32 = 0x0000_0006;
+
+const OPT_COMMENT: u16 = 1;
+const OPT_SHB_USERAPPL: u16 = 4;
+const OPT_IF_NAME: u16 = 2;
+const OPT_IF_TSRESOL: u16 = 9;
+const OPT_EPB_FLAGS: u16 = 2;
+
+/// Turns the traffic of one session into pcapng blocks.
+pub struct PcapngEncoder {
+    tx: PacketParser,
+    rx: PacketParser,
+}
+
+impl PcapngEncoder {
+    pub fn new() -> Self {
+        PcapngEncoder {
+            tx: PacketParser::recording(PACKET_SIGN_TX),
+            rx: PacketParser::recording(PACKET_SIGN_RX),
+        }
+    }
+
+    /// Section header and interface description, written once at the start of the file.
+    pub fn header(port: &str) -> Vec<u8> {
+        let mut out = Vec::new();
+
+        let mut shb = Vec::new();
+        shb.extend_from_slice(&0x1A2B_3C4Du32.to_le_bytes());
+        shb.extend_from_slice(&1u16.to_le_bytes());
+        shb.extend_from_slice(&0u16.to_le_bytes());
+        shb.extend_from_slice(&(-1i64).to_le_bytes()); // section length not known
+        push_option(&mut shb, OPT_SHB_USERAPPL, format!("sfu-cli-uploader {}", env!("CARGO_PKG_VERSION")).as_bytes());
+        end_options(&mut shb);
+        push_block(&mut out, BLOCK_SHB, &shb);
+
+        let mut idb = Vec::new();
+        idb.extend_from_slice(&LINKTYPE_USER0.to_le_bytes());
+        idb.extend_from_slice(&0u16.to_le_bytes());
+        idb.extend_from_slice(&0u32.to_le_bytes()); // no snap length limit
+        push_option(&mut idb, OPT_IF_NAME, port.as_bytes());
+        push_option(&mut idb, OPT_IF_TSRESOL, &[6]); // microseconds
+        end_options(&mut idb);
+        push_block(&mut out, BLOCK_IDB, &idb);
+
+        out
+    }
+
+    /// Packets for bytes written to the device; `us` is unix time in microseconds.
+    pub fn tx(&mut self, us: u64, data: &[u8]) -> Vec<u8> {
+        stream_packets(&mut self.tx, DIR_HOST_TO_DEVICE, us, data)
+    }
+
+    /// Packets for bytes read from the device.
+    pub fn rx(&mut self, us: u64, data: &[u8]) -> Vec<u8> {
+        stream_packets(&mut self.rx, DIR_DEVICE_TO_HOST, us, data)
+    }
+
+    pub fn baud(&self, us: u64, baud: u32) -> Vec<u8> {
+        let mut data = vec![DIR_HOST_TO_DEVICE, KIND_BAUD];
+        data.extend_from_slice(&baud.to_le_bytes());
+        let mut out = Vec::new();
+        push_packet(&mut out, us, &data, &format!("baud {baud}"));
+        out
+    }
+}
+
+/// Feed `data` to the direction's parser and encode whatever it completed.
+fn stream_packets(parser: &mut PacketParser, dir: u8, us: u64, data: &[u8]) -> Vec<u8> {
+    parser.receive_data(data);
+    let mut out = Vec::new();
+    let Some(stream) = &mut parser.stream else {
+        return out;
+    };
+    for item in stream.drain(..) {
+        match item {
+            StreamItem::Frame { code, body, crc_ok } => {
+                let mut data = vec![dir, if crc_ok { KIND_FRAME } else { KIND_FRAME_CRC_ERROR }, code];
+                data.extend_from_slice(&(body.len() as u16).to_le_bytes());
+                data.extend_from_slice(&body);
+                let name = command_name(code).map_or_else(|| format!("cmd 0x{code:02X}"), str::to_string);
+                let comment = if crc_ok { name } else { format!("{name} CRC error") };
+                push_packet(&mut out, us, &data, &comment);
+            }
+            StreamItem::Log(bytes) => {
+                let mut data = vec![dir, KIND_LOG];
+                data.extend_from_slice(&bytes);
+                push_packet(&mut out, us, &data, "log");
+            }
+        }
+    }
+    out
+}
+
+/// Enhanced packet block on interface 0.
+fn push_packet(out: &mut Vec<u8>, us: u64, data: &[u8], comment: &str) {
+    let mut epb = Vec::new();
+    epb.extend_from_slice(&0u32.to_le_bytes());
+    epb.extend_from_slice(&((us >> 32) as u32).to_le_bytes());
+    epb.extend_from_slice(&(us as u32).to_le_bytes());
+    epb.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    epb.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    epb.extend_from_slice(data);
+    pad(&mut epb);
+    // epb_flags bits 0-1: 1 inbound, 2 outbound (seen from the host).
+    let flags: u32 = if data[0] == DIR_DEVICE_TO_HOST { 1 } else { 2 };
+    push_option(&mut epb, OPT_EPB_FLAGS, &flags.to_le_bytes());
+    push_option(&mut epb, OPT_COMMENT, comment.as_bytes());
+    end_options(&mut epb);
+    push_block(out, BLOCK_EPB, &epb);
+}
+
+fn push_block(out: &mut Vec<u8>, block_type: u32, body: &[u8]) {
+    let total = (12 + body.len()) as u32;
+    out.extend_from_slice(&block_type.to_le_bytes());
+    out.extend_from_slice(&total.to_le_bytes());
+    out.extend_from_slice(body);
+    out.extend_from_slice(&total.to_le_bytes());
+}
+
+fn push_option(out: &mut Vec<u8>, code: u16, value: &[u8]) {
+    out.extend_from_slice(&code.to_le_bytes());
+    out.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    out.extend_from_slice(value);
+    pad(out);
+}
+
+fn end_options(out: &mut Vec<u8>) {
+    out.extend_from_slice(&[0; 4]);
+}
+
+fn pad(out: &mut Vec<u8>) {
+    while !out.len().is_multiple_of(4) {
+        out.push(0);
+    }
+}
+
+// ---- Unit tests ----
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::packet_build;
+    use crate::testutil::device_frame;
+
+    /// (block type, body) of every block, checking both length fields.
+    fn blocks(mut data: &[u8]) -> Vec<(u32, Vec<u8>)> {
+        let mut out = Vec::new();
+        while !data.is_empty() {
+            let block_type = u32::from_le_bytes(data[0..4].try_into().unwrap());
+            let total = u32::from_le_bytes(data[4..8].try_into().unwrap()) as usize;
+            assert_eq!(total % 4, 0);
+            assert_eq!(data[total - 4..total], data[4..8]);
+            out.push((block_type, data[8..total - 4].to_vec()));
+            data = &data[total..];
+        }
+        out
+    }
+
+    /// Packet data of an enhanced packet block.
+    fn packet_data(epb: &[u8]) -> &[u8] {
+        let len = u32::from_le_bytes(epb[12..16].try_into().unwrap()) as usize;
+        &epb[20..20 + len]
+    }
+
+    #[test]
+    fn header_declares_user_link_type() {
+        let header = blocks(&PcapngEncoder::header("/dev/ttyUSB0"));
+        assert_eq!(header.iter().map(|b| b.0).collect::<Vec<_>>(), [BLOCK_SHB, BLOCK_IDB]);
+        assert_eq!(header[0].1[..4], 0x1A2B_3C4Du32.to_le_bytes());
+        assert_eq!(header[1].1[..2], LINKTYPE_USER0.to_le_bytes());
+    }
+
+    #[test]
+    fn one_packet_per_frame_and_log_run() {
+        let mut enc = PcapngEncoder::new();
+        let info = packet_build(0x97, &[]);
+        assert!(enc.tx(1, &info[..5]).is_empty(), "half a frame is not a packet yet");
+        let tx = blocks(&enc.tx(2, &info[5..]));
+        assert_eq!(tx.len(), 1);
+        assert_eq!(packet_data(&tx[0].1), [DIR_HOST_TO_DEVICE, KIND_FRAME, 0x97, 0, 0]);
+        assert_eq!(tx[0].1[4..12], [0, 0, 0, 0, 2, 0, 0, 0], "timestamp");
+
+        let mut answer = b"boot\n".to_vec();
+        answer.extend_from_slice(&device_frame(0x38, &[1, 2, 3, 4]));
+        let rx = blocks(&enc.rx(3, &answer));
+        assert_eq!(packet_data(&rx[0].1), [&[DIR_DEVICE_TO_HOST, KIND_LOG][..], b"boot\n"].concat());
+        assert_eq!(packet_data(&rx[1].1), [DIR_DEVICE_TO_HOST, KIND_FRAME, 0x38, 4, 0, 1, 2, 3, 4]);
+        assert!(String::from_utf8_lossy(&rx[1].1).contains("SFU_CMD_WRITE"));
+
+        let baud = blocks(&enc.baud(4, 921600));
+        assert_eq!(packet_data(&baud[0].1), [&[DIR_HOST_TO_DEVICE, KIND_BAUD][..], &921600u32.to_le_bytes()].concat());
+    }
+
+    #[test]
+    fn recording_parsers_keep_no_queues() {
+        let mut enc = PcapngEncoder::new();
+        for _ in 0..100 {
+            enc.tx(1, &packet_build(0x97, &[]));
+            enc.rx(2, b"log line\n");
+        }
+        for parser in [&enc.tx, &enc.rx] {
+            assert!(parser.logs.is_empty());
+            assert!(parser.packets.iter().all(|queue| queue.is_empty()));
+            assert!(parser.stream.as_ref().is_some_and(|stream| stream.is_empty()));
+        }
+        assert_eq!((enc.tx.stat_valid_packets, enc.rx.stat_log_lines), (100, 100));
+    }
+}
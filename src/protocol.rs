@@ -0,0 +1,146 @@
+//! SFU bootloader protocol: command codes and the answer bodies.
+//!
+//! Frames around the bodies are built and parsed by `packet.rs`. Every
+//! answer is a little-endian struct; a body too short for it is `None`.
+
+use super::misc::deserialize_bytes;
+use super::misc::deserialize_u16_le;
+use super::misc::deserialize_u32_le;
+
+pub const SFU_CMD_ERASE_PART :u8 =   0xB3;
+pub const SFU_CMD_INFO   :u8 = 0x97;
+pub const SFU_CMD_ERASE  :u8 = 0xC5;
+pub const SFU_CMD_WRITE  :u8 = 0x38;
+pub const SFU_CMD_START  :u8 = 0x26;
+pub const SFU_CMD_SPEED  :u8 = 0x4B;
+pub const SFU_CMD_TIMEOUT:u8 = 0xAA;
+pub const SFU_CMD_WRERROR:u8 = 0x55;
+pub const SFU_CMD_HWRESET:u8 = 0x11;
+
+/// Protocol name of a command code, for traces and decoded captures.
+pub fn command_name(code: u8) -> Option<&'static str> {
+    match code {
+        SFU_CMD_ERASE_PART => Some("SFU_CMD_ERASE_PART"),
+        SFU_CMD_INFO => Some("SFU_CMD_INFO"),
+        SFU_CMD_ERASE => Some("SFU_CMD_ERASE"),
+        SFU_CMD_WRITE => Some("SFU_CMD_WRITE"),
+        SFU_CMD_START => Some("SFU_CMD_START"),
+        SFU_CMD_SPEED => Some("SFU_CMD_SPEED"),
+        SFU_CMD_TIMEOUT => Some("SFU_CMD_TIMEOUT"),
+        SFU_CMD_WRERROR => Some("SFU_CMD_WRERROR"),
+        SFU_CMD_HWRESET => Some("SFU_CMD_HWRESET"),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SfuInfo {
+    pub device_id: [u8; 12],
+    pub cpu_type: u32,
+    pub flash_size_correct: u32,
+    pub sfu_ver: u16,
+    pub receive_size: usize,
+    pub main_start_from: u32,
+    pub main_run_from: u32,
+    pub firmware_end_at: u32,
+}
+
+pub fn parse_sfu_info(body: &[u8], fw_len:u32) -> Option<SfuInfo> {
+    if body.len() < 32 {
+        return None;
+    }
+
+    let device_id      = deserialize_bytes::<12>(body, 0);
+    let cpu_type       = deserialize_u32_le(body, 12);
+    let flash_correct  = deserialize_u16_le(body, 16);
+    let sfu_ver        = deserialize_u16_le(body, 18);
+    let receive_size   = deserialize_u32_le(body, 20) as usize;
+    let main_start     = deserialize_u32_le(body, 24);
+    let main_run       = deserialize_u32_le(body, 28);
+
+    Some(SfuInfo {
+        device_id,
+        cpu_type,
+        flash_size_correct: (flash_correct as u32 * 1024),
+        sfu_ver,
+        receive_size,
+        main_start_from: main_start,
+        main_run_from: main_run,
+        firmware_end_at: (main_start + fw_len),
+    })
+}
+
+pub fn parse_erase_info(body: &[u8]) -> Option<i32> {
+    if body.len() < 4 {
+        return None;
+    }
+    let part_num = deserialize_u32_le(body, 0) as i32;
+    Some(part_num)
+}
+
+#[derive(Debug, Clone)]
+pub struct SpeedChangeInfo {
+    pub old_bod: u32,
+    pub new_bod: u32,
+}
+
+pub enum SpeedInfo {
+    GET(u32),
+    CHANGE(SpeedChangeInfo),
+}
+
+pub fn parse_speed_info(body: &[u8]) -> Option<SpeedInfo> {
+    if body.len() == 4 {
+        let bod     = deserialize_u32_le(body, 0);
+        return Some(SpeedInfo::GET(bod));
+    } else if body.len() == 8 {
+        let old_bod     = deserialize_u32_le(body, 0);
+        let new_bod     = deserialize_u32_le(body, 4);
+        return Some(SpeedInfo::CHANGE(SpeedChangeInfo{old_bod:old_bod, new_bod:new_bod}));
+    } else {
+        return None;
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct WriteInfo {
+    pub mcu_write_addr: u32,
+    pub mcu_receive_count: u32,
+}
+
+pub fn parse_write_info(body: &[u8]) -> Option<WriteInfo> {
+    if body.len() < 8 {
+        return None;
+    }
+
+    let mcu_write_addr     = deserialize_u32_le(body, 0);
+    let mcu_receive_count  = deserialize_u32_le(body, 4);
+
+    Some(WriteInfo {
+        mcu_write_addr: mcu_write_addr,
+        mcu_receive_count: mcu_receive_count,
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct StartInfo {
+    pub mcu_from: u32,
+    pub mcu_count: u32,
+    pub mcu_crc32: u32,
+}
+
+pub fn parse_start_info(body: &[u8]) -> Option<StartInfo> {
+    if body.len() < 12 {
+        return None;
+    }
+
+    let mcu_from  = deserialize_u32_le(body, 0);
+    let mcu_count = deserialize_u32_le(body, 4);
+    let mcu_crc32 = deserialize_u32_le(body, 8);
+
+    Some(StartInfo {
+        mcu_from: mcu_from,
+        mcu_count: mcu_count,
+        mcu_crc32: mcu_crc32,
+    })
+}
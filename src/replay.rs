@@ -0,0 +1,397 @@
+//! Replay of a recorded wire trace (`--replay`).
+//!
+//! `ReplayPort` stands in for the serial port and plays the device side of a
+//! trace back to the session. Each recorded RX chunk is released once the host
+//! has sent as many complete frames as it had at that point of the recording,
+//! after the recorded delay (or immediately with `ReplayTiming::Fast`).
+//! Every frame the host sends is compared with the recorded one, baud changes
+//! with the recorded `B` records; differences are collected in `mismatches`
+//! instead of failing the write, so the session runs to its natural end.
+//! Once everything recorded was played and the host sends a frame the
+//! recording has no answer for, reads fail with `UnexpectedEof`.
+
+use std::io::{self, Read, Write};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use serialport::{ClearBuffer, DataBits, FlowControl, Parity, SerialPort, StopBits};
+
+use super::packet::{MAX_PACKET_SIZE, PACKET_SIGN_TX};
+use super::trace::{TraceEvent, TraceRecord};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ReplayTiming {
+    /// Keep the recorded delays between host frames and device answers.
+    Original,
+    /// Answer as soon as the host has sent what the device was waiting for.
+    Fast,
+}
+
+/// One recorded device -> host chunk and what it waited for.
+struct RxChunk {
+    data: Vec<u8>,
+    /// Host frames sent before this chunk in the recording.
+    after_frames: usize,
+    /// Recorded time since the last host frame or device chunk, whichever was later.
+    delay: Duration,
+}
+
+/// Cuts a host byte stream into SFU frames: signature, code, ~code, size, body, CRC.
+#[derive(Default)]
+struct FrameSplitter {
+    buf: Vec<u8>,
+}
+
+impl FrameSplitter {
+    fn push(&mut self, data: &[u8]) -> Vec<Vec<u8>> {
+        const SIGN: [u8; 4] = PACKET_SIGN_TX.to_be_bytes();
+        self.buf.extend_from_slice(data);
+        let mut frames = Vec::new();
+        loop {
+            // Bytes before a signature are not a frame; drop them.
+            match self.buf.windows(4).position(|w| w == SIGN) {
+                Some(0) => {}
+                Some(pos) => {
+                    self.buf.drain(..pos);
+                }
+                None => {
+                    let keep = self.buf.len().min(3);
+                    self.buf.drain(..self.buf.len() - keep);
+                    return frames;
+                }
+            }
+            if self.buf.len() < 8 {
+                return frames;
+            }
+            let size = u16::from_le_bytes([self.buf[6], self.buf[7]]) as usize;
+            let total = (12 + size).min(MAX_PACKET_SIZE);
+            if self.buf.len() < total {
+                return frames;
+            }
+            frames.push(self.buf.drain(..total).collect());
+        }
+    }
+}
+
+/// Command code and size of a frame, for mismatch messages.
+fn describe(frame: &[u8]) -> String {
+    format!("cmd 0x{:02X} ({} bytes)", frame.get(4).copied().unwrap_or(0), frame.len())
+}
+
+pub struct ReplayPort {
+    timing: ReplayTiming,
+    timeout: Duration,
+    created: Instant,
+    chunks: Vec<RxChunk>,
+    next_chunk: usize,
+    /// Rest of a released chunk the host has not read yet.
+    pending: Vec<u8>,
+    last_release: Option<Instant>,
+
+    expected_frames: Vec<Vec<u8>>,
+    splitter: FrameSplitter,
+    /// When the host completed each frame.
+    frame_times: Vec<Instant>,
+
+    baud: u32,
+    expected_bauds: Vec<u32>,
+    next_baud: usize,
+
+    pub mismatches: Vec<String>,
+}
+
+impl ReplayPort {
+    pub fn new(records: &[TraceRecord], timing: ReplayTiming) -> Self {
+        let mut splitter = FrameSplitter::default();
+        let mut expected_frames = Vec::new();
+        let mut chunks = Vec::new();
+        let mut bauds = Vec::new();
+        let mut anchor_us = records.first().map_or(0, |r| r.us);
+
+        for rec in records {
+            match &rec.event {
+                TraceEvent::Tx(data) => {
+                    let frames = splitter.push(data);
+                    if !frames.is_empty() {
+                        anchor_us = anchor_us.max(rec.us);
+                    }
+                    expected_frames.extend(frames);
+                }
+                TraceEvent::Rx(data) => {
+                    chunks.push(RxChunk {
+                        data: data.clone(),
+                        after_frames: expected_frames.len(),
+                        delay: Duration::from_micros(rec.us.saturating_sub(anchor_us)),
+                    });
+                    anchor_us = anchor_us.max(rec.us);
+                }
+                TraceEvent::Baud(baud) => bauds.push(*baud),
+            }
+        }
+
+        // The first baud record is the rate the port was opened with.
+        let baud = bauds.first().copied().unwrap_or(0);
+        ReplayPort {
+            timing,
+            timeout: Duration::from_millis(1),
+            created: Instant::now(),
+            chunks,
+            next_chunk: 0,
+            pending: Vec::new(),
+            last_release: None,
+            expected_frames,
+            splitter: FrameSplitter::default(),
+            frame_times: Vec::new(),
+            baud,
+            expected_bauds: bauds.into_iter().skip(1).collect(),
+            next_baud: 0,
+            mismatches: Vec::new(),
+        }
+    }
+
+    /// Mismatches plus whatever of the recording was not played back.
+    pub fn report(&self) -> Vec<String> {
+        let mut out = self.mismatches.clone();
+        let unsent = self.expected_frames.len().saturating_sub(self.frame_times.len());
+        if unsent > 0 {
+            out.push(format!("{unsent} recorded host frame(s) were not sent"));
+        }
+        let unplayed = self.chunks.len() - self.next_chunk;
+        if unplayed > 0 {
+            out.push(format!("{unplayed} recorded device chunk(s) were not played"));
+        }
+        if self.next_baud < self.expected_bauds.len() {
+            out.push(format!("recorded baud change(s) to {:?} did not happen", &self.expected_bauds[self.next_baud..]));
+        }
+        out
+    }
+
+    /// When the next chunk may be released, `None` while the host still owes frames.
+    fn next_due(&self) -> Option<Instant> {
+        let chunk = self.chunks.get(self.next_chunk)?;
+        if self.frame_times.len() < chunk.after_frames {
+            return None;
+        }
+        let frame_time = chunk.after_frames.checked_sub(1).map(|i| self.frame_times[i]);
+        let anchor = match (frame_time, self.last_release) {
+            (Some(a), Some(b)) => a.max(b),
+            (Some(a), None) | (None, Some(a)) => a,
+            (None, None) => self.created,
+        };
+        Some(match self.timing {
+            ReplayTiming::Original => anchor + chunk.delay,
+            ReplayTiming::Fast => anchor,
+        })
+    }
+
+    fn take_pending(&mut self, buf: &mut [u8]) -> usize {
+        let n = buf.len().min(self.pending.len());
+        buf[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        n
+    }
+}
+
+impl Read for ReplayPort {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.pending.is_empty() {
+            return Ok(self.take_pending(buf));
+        }
+        if self.next_chunk == self.chunks.len() && self.frame_times.len() > self.expected_frames.len() {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "replay: end of the recording"));
+        }
+        let now = Instant::now();
+        match self.next_due() {
+            Some(due) if due <= now => {
+                self.pending = std::mem::take(&mut self.chunks[self.next_chunk].data);
+                self.next_chunk += 1;
+                self.last_release = Some(now);
+                Ok(self.take_pending(buf))
+            }
+            due => {
+                let wait = due.map_or(self.timeout, |d| (d - now).min(self.timeout));
+                sleep(wait);
+                Err(io::Error::new(io::ErrorKind::TimedOut, "replay: no device data due"))
+            }
+        }
+    }
+}
+
+impl Write for ReplayPort {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for frame in self.splitter.push(buf) {
+            let n = self.frame_times.len();
+            match self.expected_frames.get(n) {
+                Some(expected) if *expected == frame => {}
+                Some(expected) => self.mismatches.push(format!(
+                    "host frame {n}: sent {}, recorded {}{}",
+                    describe(&frame),
+                    describe(expected),
+                    if expected.get(4) == frame.get(4) { " with different content" } else { "" }
+                )),
+                None => self.mismatches.push(format!("host frame {n}: sent {} beyond the end of the recording", describe(&frame))),
+            }
+            self.frame_times.push(Instant::now());
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SerialPort for ReplayPort {
+    fn name(&self) -> Option<String> {
+        Some("replay".to_string())
+    }
+    fn baud_rate(&self) -> serialport::Result<u32> {
+        Ok(self.baud)
+    }
+    fn data_bits(&self) -> serialport::Result<DataBits> {
+        Ok(DataBits::Eight)
+    }
+    fn flow_control(&self) -> serialport::Result<FlowControl> {
+        Ok(FlowControl::None)
+    }
+    fn parity(&self) -> serialport::Result<Parity> {
+        Ok(Parity::None)
+    }
+    fn stop_bits(&self) -> serialport::Result<StopBits> {
+        Ok(StopBits::One)
+    }
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+    fn set_baud_rate(&mut self, baud_rate: u32) -> serialport::Result<()> {
+        match self.expected_bauds.get(self.next_baud) {
+            Some(&expected) if expected == baud_rate => {}
+            Some(&expected) => self.mismatches.push(format!("baud change to {baud_rate}, recorded {expected}")),
+            None => self.mismatches.push(format!("baud change to {baud_rate} beyond the end of the recording")),
+        }
+        self.next_baud += 1;
+        self.baud = baud_rate;
+        Ok(())
+    }
+    fn set_data_bits(&mut self, _: DataBits) -> serialport::Result<()> {
+        Ok(())
+    }
+    fn set_flow_control(&mut self, _: FlowControl) -> serialport::Result<()> {
+        Ok(())
+    }
+    fn set_parity(&mut self, _: Parity) -> serialport::Result<()> {
+        Ok(())
+    }
+    fn set_stop_bits(&mut self, _: StopBits) -> serialport::Result<()> {
+        Ok(())
+    }
+    fn set_timeout(&mut self, timeout: Duration) -> serialport::Result<()> {
+        self.timeout = timeout;
+        Ok(())
+    }
+    fn write_request_to_send(&mut self, _: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+    fn write_data_terminal_ready(&mut self, _: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+    fn read_clear_to_send(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+    fn read_data_set_ready(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+    fn read_ring_indicator(&mut self) -> serialport::Result<bool> {
+        Ok(false)
+    }
+    fn read_carrier_detect(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+    fn bytes_to_read(&self) -> serialport::Result<u32> {
+        Ok(self.pending.len() as u32)
+    }
+    fn bytes_to_write(&self) -> serialport::Result<u32> {
+        Ok(0)
+    }
+    fn clear(&self, _: ClearBuffer) -> serialport::Result<()> {
+        // What the real port discarded was never read, so it is not in the trace.
+        Ok(())
+    }
+    fn try_clone(&self) -> serialport::Result<Box<dyn SerialPort>> {
+        Err(serialport::Error::new(serialport::ErrorKind::Unknown, "replay port cannot be cloned"))
+    }
+    fn set_break(&self) -> serialport::Result<()> {
+        Ok(())
+    }
+    fn clear_break(&self) -> serialport::Result<()> {
+        Ok(())
+    }
+}
+
+// ---- Unit tests ----
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::packet_build;
+
+    fn rec(us: u64, event: TraceEvent) -> TraceRecord {
+        TraceRecord { us, event }
+    }
+
+    #[test]
+    fn frames_gate_device_chunks() {
+        let info = packet_build(0x97, &[]);
+        let erase = packet_build(0xC5, &[0, 0x10, 0, 0]);
+        let records = [
+            rec(0, TraceEvent::Baud(921600)),
+            rec(10, TraceEvent::Tx(info[..5].to_vec())),
+            rec(20, TraceEvent::Tx(info[5..].to_vec())),
+            rec(500, TraceEvent::Rx(b"answer".to_vec())),
+            rec(600, TraceEvent::Tx(erase.clone())),
+            rec(700, TraceEvent::Rx(b"done".to_vec())),
+            rec(800, TraceEvent::Baud(115200)),
+        ];
+        let mut port = ReplayPort::new(&records, ReplayTiming::Fast);
+        let mut buf = [0u8; 4];
+
+        assert!(port.read(&mut buf).is_err(), "nothing before the host frame");
+        port.write_all(&info).unwrap();
+        assert_eq!(port.read(&mut buf).unwrap(), 4);
+        assert_eq!(port.read(&mut buf).unwrap(), 2);
+        assert_eq!(&buf[..2], b"er");
+        assert!(port.read(&mut buf).is_err());
+
+        port.write_all(&packet_build(0xC5, &[0, 0x20, 0, 0])).unwrap();
+        assert_eq!(port.read(&mut buf).unwrap(), 4);
+        port.set_baud_rate(115200).unwrap();
+        assert!(port.read(&mut buf).is_err());
+        assert_eq!(port.report(), ["host frame 1: sent cmd 0xC5 (16 bytes), recorded cmd 0xC5 (16 bytes) with different content"]);
+
+        port.write_all(&info).unwrap();
+        assert_eq!(port.read(&mut buf).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn report_lists_what_was_not_replayed() {
+        let info = packet_build(0x97, &[]);
+        let records = [
+            rec(0, TraceEvent::Baud(921600)),
+            rec(1, TraceEvent::Tx(info.clone())),
+            rec(5, TraceEvent::Rx(vec![1])),
+            rec(9, TraceEvent::Baud(2000000)),
+        ];
+        let mut port = ReplayPort::new(&records, ReplayTiming::Original);
+        port.write_all(&packet_build(0x26, &[0; 4])).unwrap();
+        port.write_all(&info).unwrap();
+        assert_eq!(
+            port.report(),
+            [
+                "host frame 0: sent cmd 0x26 (16 bytes), recorded cmd 0x97 (12 bytes)",
+                "host frame 1: sent cmd 0x97 (12 bytes) beyond the end of the recording",
+                "1 recorded device chunk(s) were not played",
+                "recorded baud change(s) to [2000000] did not happen",
+            ]
+        );
+    }
+}
@@ -0,0 +1,337 @@
+//! Session statistics report (printed after every session, `--report FILE` as JSON).
+
+use std::collections::VecDeque;
+use std::fs;
+use std::time::{Duration, Instant};
+
+use super::protocol::command_name;
+
+/// One step of the session; `end` stays `None` if it never completed.
+#[derive(Debug, Clone)]
+pub struct Phase {
+    pub name: &'static str,
+    pub start: Instant,
+    pub end: Option<Instant>,
+}
+
+/// Phase timing collected while the session runs. Each phase is timed once:
+/// from its first `begin` to the first `end` after it.
+#[derive(Debug, Default, Clone)]
+pub struct Phases(Vec<Phase>);
+
+impl Phases {
+    pub fn begin(&mut self, name: &'static str) {
+        if !self.0.iter().any(|p| p.name == name) {
+            self.0.push(Phase { name, start: Instant::now(), end: None });
+        }
+    }
+
+    pub fn end(&mut self, name: &'static str) {
+        if let Some(phase) = self.0.iter_mut().find(|p| p.name == name && p.end.is_none()) {
+            phase.end = Some(Instant::now());
+        }
+    }
+
+    /// Add a phase timed outside the session (reset sequences).
+    pub fn record(&mut self, name: &'static str, start: Instant, end: Instant) {
+        self.0.push(Phase { name, start, end: Some(end) });
+    }
+
+    pub fn extend(&mut self, other: Phases) {
+        self.0.extend(other.0);
+    }
+}
+
+/// Round trip of every SFU_CMD_WRITE block: from `send_write_command` to the
+/// ack whose `mcu_write_addr` is the end address of the block.
+#[derive(Debug, Default, Clone)]
+pub struct WriteLatency {
+    /// Blocks waiting for their ack: end address, send time.
+    pending: VecDeque<(u32, Instant)>,
+    pub samples: Vec<Duration>,
+}
+
+impl WriteLatency {
+    pub fn sent(&mut self, end_addr: u32, at: Instant) {
+        self.pending.push_back((end_addr, at));
+    }
+
+    /// Blocks before `mcu_write_addr` are acked too, but only the block ending
+    /// there gets a sample.
+    pub fn acked(&mut self, mcu_write_addr: u32, at: Instant) {
+        while let Some(&(end_addr, sent)) = self.pending.front()
+            && end_addr <= mcu_write_addr
+        {
+            self.pending.pop_front();
+            if end_addr == mcu_write_addr {
+                self.samples.push(at - sent);
+            }
+        }
+    }
+
+    /// The device corrected the write address; everything in flight is sent again.
+    pub fn resend(&mut self) {
+        self.pending.clear();
+    }
+}
+
+/// min/median/p99/max and a histogram of `WriteLatency::samples`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LatencyStats {
+    pub count: usize,
+    pub min: Duration,
+    pub median: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+    /// Upper bound (power of two ms, inclusive) and count; empty buckets between used ones are kept.
+    pub histogram: Vec<(u64, usize)>,
+}
+
+impl LatencyStats {
+    pub fn new(samples: &[Duration]) -> Option<Self> {
+        let mut sorted = samples.to_vec();
+        sorted.sort();
+        let count = sorted.len();
+        // nearest rank
+        let rank = |p: f64| sorted[((p * count as f64).ceil() as usize).clamp(1, count) - 1];
+        let min = *sorted.first()?;
+        let max = sorted[count - 1];
+        let bucket = |d: Duration| ms(d).ceil().max(1.0) as u64;
+        let mut histogram: Vec<(u64, usize)> = Vec::new();
+        let mut bound = 1;
+        while bound < bucket(min) {
+            bound *= 2;
+        }
+        loop {
+            histogram.push((bound, 0));
+            if bound >= bucket(max) {
+                break;
+            }
+            bound *= 2;
+        }
+        for d in &sorted {
+            let slot = histogram.iter_mut().find(|(bound, _)| bucket(*d) <= *bound).expect("max bounds the histogram");
+            slot.1 += 1;
+        }
+        Some(LatencyStats { count, min, median: rank(0.5), p99: rank(0.99), max, histogram })
+    }
+
+    fn print(&self) {
+        println!(
+            "Write ack:       {} blocks, min {:.1} ms, median {:.1} ms, p99 {:.1} ms, max {:.1} ms",
+            self.count,
+            ms(self.min),
+            ms(self.median),
+            ms(self.p99),
+            ms(self.max)
+        );
+        let widest = self.histogram.iter().map(|h| h.1).max().unwrap_or(0).max(1);
+        for (bound, n) in &self.histogram {
+            let bar = "#".repeat((n * 40).div_ceil(widest));
+            println!("  <= {bound:>5} ms {n:>6} {bar}");
+        }
+    }
+
+    fn to_json(&self) -> String {
+        let histogram: Vec<String> = self.histogram.iter().map(|(bound, n)| format!("{{\"le_ms\": {bound}, \"count\": {n}}}")).collect();
+        format!(
+            "{{\"count\": {}, \"min_ms\": {:.3}, \"median_ms\": {:.3}, \"p99_ms\": {:.3}, \"max_ms\": {:.3}, \"histogram\": [{}]}}",
+            self.count,
+            ms(self.min),
+            ms(self.median),
+            ms(self.p99),
+            ms(self.max),
+            histogram.join(", ")
+        )
+    }
+}
+
+pub struct SessionReport {
+    pub command: String,
+    pub result: u8,
+    pub firmware_bytes: usize,
+    /// Baud rate the session ended at (the main speed after a successful switch).
+    pub baud: Option<u32>,
+    /// Since the tool started.
+    pub total: Duration,
+    /// Name, start since the tool started and duration (`None` = not finished), in start order.
+    pub phases: Vec<(&'static str, Duration, Option<Duration>)>,
+    pub info_attempts: u32,
+    pub write_resends: usize,
+    /// `None` when no block was acked.
+    pub write_ack: Option<LatencyStats>,
+    /// `PacketParser` counters by name.
+    pub parser: Vec<(&'static str, u64)>,
+    /// Received packets nobody consumed, by command code.
+    pub unhandled: Vec<(u8, usize)>,
+}
+
+impl SessionReport {
+    pub fn phases_since(timeline: &Instant, phases: &Phases) -> Vec<(&'static str, Duration, Option<Duration>)> {
+        let mut list: Vec<&Phase> = phases.0.iter().collect();
+        list.sort_by_key(|p| p.start);
+        list.iter().map(|p| (p.name, p.start.saturating_duration_since(*timeline), p.end.map(|e| e - p.start))).collect()
+    }
+
+    /// Firmware bytes per second over the write phase.
+    pub fn write_rate(&self) -> Option<f64> {
+        let (_, _, duration) = self.phases.iter().find(|p| p.0 == "write")?;
+        let secs = duration.as_ref()?.as_secs_f64();
+        (secs > 0.0).then(|| self.firmware_bytes as f64 / secs)
+    }
+
+    pub fn print(&self) {
+        println!("--- Session report ---");
+        println!("Result:          {} ({})", self.result, self.command);
+        println!("Firmware:        {} bytes", self.firmware_bytes);
+        println!("Total:           {:.1} ms", ms(self.total));
+        println!("Phase            start ms  duration ms");
+        for (name, start, duration) in &self.phases {
+            let duration = duration.map_or_else(|| "unfinished".to_string(), |d| format!("{:.1}", ms(d)));
+            println!("  {name:<12} {:>10.1} {duration:>12}", ms(*start));
+        }
+        if let Some(rate) = self.write_rate() {
+            // 10 bits per byte on the wire (start, 8 data, stop).
+            let line = self.baud.map(|b| format!(" ({:.0}% of {b} baud)", rate * 1000.0 / b as f64)).unwrap_or_default();
+            println!("Write rate:      {:.1} KiB/s{line}", rate / 1024.0);
+        }
+        println!("INFO attempts:   {}", self.info_attempts);
+        println!("Write resends:   {}", self.write_resends);
+        if let Some(write_ack) = &self.write_ack {
+            write_ack.print();
+        }
+        let parser: Vec<String> = self.parser.iter().map(|(name, n)| format!("{} {n}", name.replace('_', " "))).collect();
+        println!("Parser:          {}", parser.join(", "));
+        if !self.unhandled.is_empty() {
+            let unhandled: Vec<String> = self.unhandled.iter().map(|(code, n)| format!("{} x{n}", code_name(*code))).collect();
+            println!("Unhandled:       {}", unhandled.join(", "));
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        let phases: Vec<String> = self
+            .phases
+            .iter()
+            .map(|(name, start, duration)| {
+                format!(
+                    "    {{\"name\": \"{name}\", \"start_ms\": {:.3}, \"duration_ms\": {}}}",
+                    ms(*start),
+                    duration.map_or_else(|| "null".to_string(), |d| format!("{:.3}", ms(d)))
+                )
+            })
+            .collect();
+        let parser: Vec<String> = self.parser.iter().map(|(name, n)| format!("    \"{name}\": {n}")).collect();
+        let unhandled: Vec<String> = self.unhandled.iter().map(|(code, n)| format!("    \"0x{code:02X}\": {n}")).collect();
+        let opt = |v: Option<String>| v.unwrap_or_else(|| "null".to_string());
+        let block = |items: Vec<String>, open: char, close: char| match items.is_empty() {
+            true => format!("{open}{close}"),
+            false => format!("{open}\n{}\n  {close}", items.join(",\n")),
+        };
+        format!(
+            "{{\n  \"command\": \"{}\",\n  \"result\": {},\n  \"firmware_bytes\": {},\n  \"baud\": {},\n  \"total_ms\": {:.3},\n  \
+             \"phases\": {},\n  \"write_bytes_per_sec\": {},\n  \"info_attempts\": {},\n  \"write_resends\": {},\n  \"write_ack\": {},\n  \
+             \"parser\": {},\n  \"unhandled_packets\": {}\n}}\n",
+            self.command,
+            self.result,
+            self.firmware_bytes,
+            opt(self.baud.map(|b| b.to_string())),
+            ms(self.total),
+            block(phases, '[', ']'),
+            opt(self.write_rate().map(|r| format!("{r:.1}"))),
+            self.info_attempts,
+            self.write_resends,
+            opt(self.write_ack.as_ref().map(LatencyStats::to_json)),
+            block(parser, '{', '}'),
+            block(unhandled, '{', '}'),
+        )
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        fs::write(path, self.to_json()).map_err(|e| format!("{path}: {e}"))
+    }
+}
+
+fn ms(d: Duration) -> f64 {
+    d.as_secs_f64() * 1000.0
+}
+
+fn code_name(code: u8) -> String {
+    command_name(code).map_or_else(|| format!("0x{code:02X}"), str::to_string)
+}
+
+// ---- Unit tests ----
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn phases_are_timed_once() {
+        let timeline = Instant::now();
+        let mut phases = Phases::default();
+        phases.begin("write");
+        let first = phases.0[0].start;
+        phases.begin("write");
+        phases.end("write");
+        let end = phases.0[0].end;
+        phases.end("write");
+        phases.begin("start");
+        let mut resets = Phases::default();
+        resets.record("reset", timeline, timeline + Duration::from_millis(5));
+        phases.extend(resets);
+
+        assert_eq!((phases.0.len(), phases.0[0].start, phases.0[0].end), (3, first, end));
+        let list = SessionReport::phases_since(&timeline, &phases);
+        assert_eq!(list.iter().map(|p| p.0).collect::<Vec<_>>(), ["reset", "write", "start"]);
+        assert_eq!((list[0].2, list[2].2), (Some(Duration::from_millis(5)), None));
+    }
+
+    #[test]
+    fn json_report() {
+        let report = SessionReport {
+            command: "flash".into(),
+            result: 0,
+            firmware_bytes: 4096,
+            baud: Some(921600),
+            total: Duration::from_millis(1500),
+            phases: vec![("write", Duration::from_millis(100), Some(Duration::from_millis(500))), ("start", Duration::from_millis(600), None)],
+            info_attempts: 1,
+            write_resends: 2,
+            write_ack: None,
+            parser: vec![("valid_packets", 7), ("crc_errors", 0)],
+            unhandled: vec![(0x97, 1)],
+        };
+        assert_eq!(report.write_rate(), Some(8192.0));
+        let json = report.to_json();
+        assert!(json.contains("\"phases\": [\n    {\"name\": \"write\", \"start_ms\": 100.000, \"duration_ms\": 500.000},\n"));
+        assert!(json.contains("{\"name\": \"start\", \"start_ms\": 600.000, \"duration_ms\": null}\n  ],"));
+        assert!(json.contains("\"write_bytes_per_sec\": 8192.0,"));
+        assert!(json.contains("\"write_ack\": null,"));
+        assert!(json.contains("\"parser\": {\n    \"valid_packets\": 7,\n    \"crc_errors\": 0\n  },"));
+        assert!(json.contains("\"unhandled_packets\": {\n    \"0x97\": 1\n  }\n}"));
+    }
+
+    #[test]
+    fn write_ack_latency() {
+        let t0 = Instant::now();
+        let at = |ms: u64| t0 + Duration::from_millis(ms);
+        let mut latency = WriteLatency::default();
+        latency.sent(0x800, at(0));
+        latency.sent(0x1000, at(1));
+        latency.acked(0x800, at(3));
+        latency.sent(0x1800, at(4));
+        latency.resend();
+        latency.sent(0x1800, at(10));
+        latency.sent(0x2000, at(11));
+        latency.acked(0x2000, at(20));
+        assert_eq!(latency.samples, [Duration::from_millis(3), Duration::from_millis(9)]);
+
+        let samples: Vec<Duration> = (1..=100).map(Duration::from_millis).collect();
+        let stats = LatencyStats::new(&samples).unwrap();
+        let ms = Duration::from_millis;
+        assert_eq!((stats.min, stats.median, stats.p99, stats.max), (ms(1), ms(50), ms(99), ms(100)));
+        assert_eq!(stats.histogram, [(1, 1), (2, 1), (4, 2), (8, 4), (16, 8), (32, 16), (64, 32), (128, 36)]);
+        assert!(stats.to_json().starts_with("{\"count\": 100, \"min_ms\": 1.000, \"median_ms\": 50.000,"));
+        assert_eq!(LatencyStats::new(&[]), None);
+    }
+}
@@ -1,11 +1,291 @@
 #![allow(non_camel_case_types)]
 use std::fmt;
+use std::thread::sleep;
+use std::time::Duration;
+
+use serialport::SerialPort;
+
+/// Native handle of the session's serial port. Resets drive DTR/RTS/BREAK
+/// through it, so the tty is never closed and reopened between reset and upload.
+#[cfg(unix)]
+pub type SessionPort = serialport::TTYPort;
+#[cfg(windows)]
+pub type SessionPort = serialport::COMPort;
+
+/// One step of a reset sequence: drive some pins, then hold for `hold_ms`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ResetStep {
+    /// GPIO bits driven by this step; other GPIO bits keep their level.
+    pub mask: u16,
+    /// Levels of the `mask` bits; bits outside the mask are ignored by the GPIO backends.
+    pub values: u16,
+    /// Serial control lines, `None` = left as is.
+    pub dtr: Option<bool>,
+    pub rts: Option<bool>,
+    /// UART BREAK (TX held low).
+    pub brk: Option<bool>,
+    pub hold_ms: u32,
+}
+
+impl ResetStep {
+    pub fn uses_serial_lines(&self) -> bool {
+        self.dtr.is_some() || self.rts.is_some() || self.brk.is_some()
+    }
+}
+
+impl fmt::Display for ResetStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut tokens: Vec<String> = Vec::new();
+        for bit in 0..16 {
+            if self.mask & (1 << bit) != 0 {
+                tokens.push(format!("GPIO{bit}={}", (self.values >> bit) & 1));
+            }
+        }
+        for (name, line) in [("DTR", self.dtr), ("RTS", self.rts), ("BREAK", self.brk)] {
+            if let Some(on) = line {
+                tokens.push(format!("{name}={}", on as u8));
+            }
+        }
+        if tokens.is_empty() {
+            tokens.push("wait".into());
+        }
+        if self.hold_ms > 0 {
+            tokens.push(format!("{}ms", self.hold_ms));
+        }
+        write!(f, "{}", tokens.join(" "))
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct ResetSequence {
-    pub quantum_ms: u32,
-    pub mask: u16,
-    pub values: Vec<u16>,
+    pub steps: Vec<ResetStep>,
+    /// Optional board-specific names of mask bits (index = bit), e.g. ["BOOT", "RESET"].
+    pub labels: Vec<String>,
+    /// Built by `from_values`: without a latch, DTR/RTS follow bits 0/1 of every raw value.
+    pub classic: bool,
+}
+
+impl ResetSequence {
+    /// Classic `-r T MASK VAL...` form: every value is one step held for `quantum_ms`.
+    /// Values are kept unmasked for the DTR/RTS fallback.
+    pub fn from_values(quantum_ms: u32, mask: u16, values: &[u16]) -> Self {
+        ResetSequence {
+            steps: values
+                .iter()
+                .map(|&values| ResetStep {
+                    mask,
+                    values,
+                    hold_ms: quantum_ms,
+                    ..ResetStep::default()
+                })
+                .collect(),
+            labels: Vec::new(),
+            classic: true,
+        }
+    }
+
+    /// All GPIO bits driven by any step.
+    pub fn gpio_mask(&self) -> u16 {
+        self.steps.iter().fold(0, |mask, step| mask | step.mask)
+    }
+
+    pub fn uses_serial_lines(&self) -> bool {
+        self.steps.iter().any(|step| step.uses_serial_lines())
+    }
+
+    /// "GPIO1" or "GPIO1 (RESET)" when the bit has a label.
+    pub fn pin_name(&self, bit: u32) -> String {
+        match self.labels.get(bit as usize) {
+            Some(label) if !label.is_empty() => format!("GPIO{bit} ({label})"),
+            _ => format!("GPIO{bit}"),
+        }
+    }
+}
+
+impl fmt::Display for ResetSequence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let steps: Vec<String> = self.steps.iter().map(|step| step.to_string()).collect();
+        write!(f, "{}", steps.join("; "))
+    }
+}
+
+/// Parse the step form of a reset sequence, e.g. "GPIO0=0; GPIO1=0 5ms; GPIO1=1 200ms; GPIO0=1".
+///
+/// Steps are separated by ';'. A step assigns pins (`DTR`, `RTS`, `BREAK`,
+/// `GPIO0`..`GPIO15` or a pin label) to 0/1 (or low/high) and may end with
+/// a hold time (`5ms`, `2s`); a step with only a time (or `wait 200ms`) just waits.
+pub fn parse_reset_steps(text: &str, labels: &[String]) -> Result<Vec<ResetStep>, String> {
+    let mut steps = Vec::new();
+
+    for (n, step_text) in text.split(';').enumerate() {
+        let step_text = step_text.trim();
+        if step_text.is_empty() {
+            continue;
+        }
+        let mut step = ResetStep::default();
+        let mut hold: Option<u32> = None;
+        let mut wait = false;
+
+        for token in step_text.split_whitespace() {
+            let err = |msg: String| format!("step {n} '{step_text}': {msg}");
+
+            if token.eq_ignore_ascii_case("wait") {
+                wait = true;
+                continue;
+            }
+            if token.starts_with(|c: char| c.is_ascii_digit()) {
+                if hold.is_some() {
+                    return Err(err("more than one hold time".into()));
+                }
+                hold = Some(parse_duration_ms(token).map_err(err)?);
+                continue;
+            }
+
+            let (pin, level) = token
+                .split_once('=')
+                .ok_or_else(|| err(format!("expected PIN=0|1 or a time, got '{token}'")))?;
+            let level = match level.to_ascii_lowercase().as_str() {
+                "0" | "low" => false,
+                "1" | "high" => true,
+                other => return Err(err(format!("invalid level '{other}' for {pin}, expected 0/1/low/high"))),
+            };
+
+            let line = match pin.to_ascii_uppercase().as_str() {
+                "DTR" => Some(&mut step.dtr),
+                "RTS" => Some(&mut step.rts),
+                "BREAK" | "BRK" => Some(&mut step.brk),
+                _ => None,
+            };
+            if let Some(line) = line {
+                if line.is_some() {
+                    return Err(err(format!("{pin} assigned twice")));
+                }
+                *line = Some(level);
+                continue;
+            }
+
+            let bit = gpio_bit(pin, labels).ok_or_else(|| err(format!("unknown pin '{pin}'")))?;
+            if step.mask & (1 << bit) != 0 {
+                return Err(err(format!("{pin} assigned twice")));
+            }
+            step.mask |= 1 << bit;
+            if level {
+                step.values |= 1 << bit;
+            }
+        }
+
+        if wait && (step.mask != 0 || step.uses_serial_lines()) {
+            return Err(format!("step {n} '{step_text}': 'wait' step cannot drive pins"));
+        }
+        if !wait && hold.is_none() && step.mask == 0 && !step.uses_serial_lines() {
+            return Err(format!("step {n} '{step_text}': nothing to do"));
+        }
+        step.hold_ms = hold.unwrap_or(0);
+        steps.push(step);
+    }
+
+    if steps.is_empty() {
+        return Err("empty reset sequence".into());
+    }
+    Ok(steps)
+}
+
+/// "GPIO5" -> 5, or the index of a matching pin label (case-insensitive).
+fn gpio_bit(pin: &str, labels: &[String]) -> Option<u32> {
+    if let Some(pos) = labels.iter().position(|l| !l.is_empty() && l.eq_ignore_ascii_case(pin)) {
+        return Some(pos as u32);
+    }
+    let upper = pin.to_ascii_uppercase();
+    let num = upper.strip_prefix("GPIO")?;
+    match num.parse::<u32>() {
+        Ok(bit) if bit < 16 => Some(bit),
+        _ => None,
+    }
+}
+
+/// "5ms", "2s" or plain "5" (ms) -> milliseconds.
+pub fn parse_duration_ms(s: &str) -> Result<u32, String> {
+    let lower = s.to_ascii_lowercase();
+    let (num, scale) = if let Some(n) = lower.strip_suffix("ms") {
+        (n, 1)
+    } else if let Some(n) = lower.strip_suffix('s') {
+        (n, 1000)
+    } else {
+        (lower.as_str(), 1)
+    };
+    num.parse::<u32>()
+        .ok()
+        .and_then(|v| v.checked_mul(scale))
+        .ok_or_else(|| format!("invalid time '{s}', expected e.g. 5ms or 2s"))
+}
+
+/// Which mechanism drives the reset sequence.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ResetBackend {
+    /// CP210x latch or FTDI CBUS if the adapter supports it, DTR/RTS otherwise.
+    Auto,
+    Cp210x,
+    DtrRts,
+    Ftdi,
+    GpioChip,
+}
+
+impl ResetBackend {
+    pub const NAMES: &'static str = "auto|cp210x|dtr-rts|ftdi|gpiochip";
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "auto" => Some(ResetBackend::Auto),
+            "cp210x" => Some(ResetBackend::Cp210x),
+            "dtr-rts" => Some(ResetBackend::DtrRts),
+            "ftdi" => Some(ResetBackend::Ftdi),
+            "gpiochip" => Some(ResetBackend::GpioChip),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ResetBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResetBackend::Auto => write!(f, "auto"),
+            ResetBackend::Cp210x => write!(f, "cp210x"),
+            ResetBackend::DtrRts => write!(f, "dtr-rts"),
+            ResetBackend::Ftdi => write!(f, "ftdi"),
+            ResetBackend::GpioChip => write!(f, "gpiochip"),
+        }
+    }
+}
+
+/// Host GPIO lines used by the gpiochip backend: bit N of the sequence drives `offsets[N]`.
+#[derive(Debug, Clone)]
+pub struct GpioChipLines {
+    /// Character device path, e.g. "/dev/gpiochip0".
+    pub chip: String,
+    pub offsets: Vec<u32>,
+}
+
+impl GpioChipLines {
+    /// `chip` as a path, a number ("0") or a name ("gpiochip0"); one to 16 line offsets.
+    pub fn new(chip: &str, offsets: Vec<u32>) -> Result<Self, String> {
+        if offsets.is_empty() {
+            return Err("no GPIO line offsets given".into());
+        }
+        if offsets.len() > 16 {
+            return Err("at most 16 lines can be mapped (reset mask is 16 bits)".into());
+        }
+        Ok(GpioChipLines { chip: normalize_gpiochip(chip), offsets })
+    }
+}
+
+fn normalize_gpiochip(raw: &str) -> String {
+    if raw.contains('/') {
+        raw.to_string()
+    } else if raw.chars().all(|c| c.is_ascii_digit()) {
+        format!("/dev/gpiochip{}", raw)
+    } else {
+        format!("/dev/{}", raw)
+    }
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
@@ -14,6 +294,27 @@ pub enum GpioResetStatus {
     UsedCp210x,
     /// Sequence executed via classic serial DTR/RTS pins.
     UsedDtrRts,
+    /// Sequence executed via FTDI FT-X CBUS bit-bang.
+    #[cfg(unix)]
+    UsedFtdiCbus,
+    /// Sequence executed on host GPIO lines via /dev/gpiochipN.
+    #[cfg(target_os = "linux")]
+    UsedGpioChip,
+}
+
+/// Successful reset: the backend that ran it and, in `auto` mode, why the
+/// backends tried before it were passed over.
+#[derive(Debug, Clone)]
+pub struct GpioResetReport {
+    pub status: GpioResetStatus,
+    pub skipped: Vec<(ResetBackend, String)>,
+}
+
+/// Outcome of a GPIO latch backend that did not fail outright.
+pub(crate) enum LatchAttempt {
+    Used,
+    /// The adapter can't run the sequence this way, with the reason.
+    Unsupported(String),
 }
 
 #[derive(Debug)]
@@ -36,26 +337,282 @@ impl fmt::Display for GpioResetError {
 
 impl std::error::Error for GpioResetError {}
 
-pub fn cp210x_gpio_reset(port: &str, rst_seq: &ResetSequence) -> Result<GpioResetStatus, GpioResetError> {
+/// CP210x part numbers as reported by GET_PARTNUM / CP210xRT_GetPartNumber
+/// (see SILABS_PARTNUM_CPXXXX in dll/include/silabs_defs.h).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Cp210xPart {
+    Cp2101,
+    Cp2102,
+    Cp2103,
+    Cp2104,
+    Cp2105,
+    Cp2108,
+    Cp2109,
+    Cp2102nQfn28,
+    Cp2102nQfn24,
+    Cp2102nQfn20,
+    Unknown(u8),
+}
+
+impl Cp210xPart {
+    pub fn from_partnum(partnum: u8) -> Self {
+        match partnum {
+            0x01 => Cp210xPart::Cp2101,
+            0x02 => Cp210xPart::Cp2102,
+            0x03 => Cp210xPart::Cp2103,
+            0x04 => Cp210xPart::Cp2104,
+            0x05 => Cp210xPart::Cp2105,
+            0x08 => Cp210xPart::Cp2108,
+            0x09 => Cp210xPart::Cp2109,
+            0x20 => Cp210xPart::Cp2102nQfn28,
+            0x21 => Cp210xPart::Cp2102nQfn24,
+            0x22 => Cp210xPart::Cp2102nQfn20,
+            other => Cp210xPart::Unknown(other),
+        }
+    }
+
+    /// Latch bits usable as GPIO on the given interface, 0 = no GPIO latch at all.
+    ///
+    /// CP2105 has separate GPIO sets per interface (ECI: GPIO0..1, SCI: GPIO0..2),
+    /// CP2108 exposes one 16-bit latch covering all four UARTs.
+    pub fn gpio_mask(self, interface: u8) -> u16 {
+        match self {
+            Cp210xPart::Cp2103 | Cp210xPart::Cp2104 => 0x000F,
+            Cp210xPart::Cp2105 => if interface == 0 { 0x0003 } else { 0x0007 },
+            Cp210xPart::Cp2108 => 0xFFFF,
+            Cp210xPart::Cp2102nQfn28 => 0x007F,
+            Cp210xPart::Cp2102nQfn24 | Cp210xPart::Cp2102nQfn20 => 0x000F,
+            Cp210xPart::Cp2101 | Cp210xPart::Cp2102 | Cp210xPart::Cp2109 | Cp210xPart::Unknown(_) => 0x0000,
+        }
+    }
+
+    /// READ_LATCH / WRITE_LATCH go to the interface on CP2105 only; CP2108 and
+    /// the single-UART parts take them as device requests (AN571, Linux cp210x).
+    pub fn latch_per_interface(self) -> bool {
+        self == Cp210xPart::Cp2105
+    }
+
+    /// Check that every bit of `mask` is a GPIO on this part/interface.
+    pub fn check_mask(self, interface: u8, mask: u16) -> Result<(), String> {
+        let available = self.gpio_mask(interface);
+        if available == 0 {
+            return Err(format!("{self} has no GPIO latch"));
+        }
+        if mask & !available != 0 {
+            return Err(format!(
+                "reset mask 0x{mask:04X} uses pins not available on {self} interface {interface} (GPIO mask 0x{available:04X})"
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Cp210xPart {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Cp210xPart::Cp2101 => write!(f, "CP2101"),
+            Cp210xPart::Cp2102 => write!(f, "CP2102"),
+            Cp210xPart::Cp2103 => write!(f, "CP2103"),
+            Cp210xPart::Cp2104 => write!(f, "CP2104"),
+            Cp210xPart::Cp2105 => write!(f, "CP2105"),
+            Cp210xPart::Cp2108 => write!(f, "CP2108"),
+            Cp210xPart::Cp2109 => write!(f, "CP2109"),
+            Cp210xPart::Cp2102nQfn28 => write!(f, "CP2102N-QFN28"),
+            Cp210xPart::Cp2102nQfn24 => write!(f, "CP2102N-QFN24"),
+            Cp210xPart::Cp2102nQfn20 => write!(f, "CP2102N-QFN20"),
+            Cp210xPart::Unknown(v) => write!(f, "unknown CP210x part 0x{v:02X}"),
+        }
+    }
+}
+
+/// Current CP210x GPIO latch as read back from the device.
+#[derive(Debug, Clone)]
+pub struct GpioLatchState {
+    /// Detected part, `None` if the driver did not report it.
+    pub part: Option<Cp210xPart>,
+    /// Latch bits that are GPIO on this part/interface (0xFFFF if unknown).
+    pub gpio_mask: u16,
+    pub latch: u16,
+}
+
+/// Compare latch read-back with the requested state, `Err` names the pins that did not follow.
+fn verify_latch(rst_seq: &ResetSequence, mask: u16, expected: u16, latch: u16) -> Result<(), String> {
+    let wrong = (latch ^ expected) & mask;
+    if wrong == 0 {
+        return Ok(());
+    }
+    let pins: Vec<String> = (0..16)
+        .filter(|bit| wrong & (1 << bit) != 0)
+        .map(|bit| format!("{} reads {}", rst_seq.pin_name(bit), (latch >> bit) & 1))
+        .collect();
+    Err(format!(
+        "latch read-back 0x{latch:04X} != requested 0x{:04X} (mask 0x{mask:04X}): {} - pin not configured as GPIO?",
+        expected & mask,
+        pins.join(", ")
+    ))
+}
+
+/// Execute `rst_seq` step by step, shared by all backends.
+///
+/// `gpio(step, driven_mask, driven_values)` updates the GPIO pins of a step;
+/// `driven_*` is the state of every pin driven so far. When it returns a
+/// read-back, all driven pins are verified against it. `lines(step)` sets
+/// DTR/RTS/BREAK. A BREAK still asserted after the last step is released.
+pub(crate) fn run_steps(
+    rst_seq: &ResetSequence,
+    mut gpio: impl FnMut(&ResetStep, u16, u16) -> Result<Option<u16>, String>,
+    mut lines: impl FnMut(&ResetStep) -> Result<(), String>,
+) -> Result<(), String> {
+    let mut driven_mask = 0u16;
+    let mut driven_values = 0u16;
+    let mut brk = false;
+
+    for (n, step) in rst_seq.steps.iter().enumerate() {
+        if step.mask != 0 {
+            driven_mask |= step.mask;
+            driven_values = (driven_values & !step.mask) | (step.values & step.mask);
+            if let Some(latch) = gpio(step, driven_mask, driven_values).map_err(|msg| format!("step {n}: {msg}"))? {
+                verify_latch(rst_seq, driven_mask, driven_values, latch).map_err(|msg| format!("step {n}: {msg}"))?;
+            }
+        }
+        if step.uses_serial_lines() {
+            lines(step).map_err(|msg| format!("step {n}: {msg}"))?;
+            brk = step.brk.unwrap_or(brk);
+        }
+        if step.hold_ms > 0 {
+            sleep(Duration::from_millis(step.hold_ms as u64));
+        }
+    }
+
+    if brk {
+        lines(&ResetStep { brk: Some(false), ..ResetStep::default() })?;
+    }
+    Ok(())
+}
+
+/// Sequence for adapters without a GPIO latch: GPIO bits 0/1 of each step become
+/// DTR/RTS. A classic `-r` sequence sets both lines from every value, whatever the
+/// mask; a step sequence drives a line only in steps that name its pin.
+pub(crate) fn dtr_rts_sequence(rst_seq: &ResetSequence) -> Result<ResetSequence, String> {
+    if rst_seq.gpio_mask() != 0 && rst_seq.uses_serial_lines() {
+        return Err("sequence drives both GPIO pins and DTR/RTS/BREAK, but no GPIO latch is available".into());
+    }
+    let steps = rst_seq
+        .steps
+        .iter()
+        .map(|step| {
+            let mut out = ResetStep { mask: 0, values: 0, ..step.clone() };
+            if rst_seq.classic {
+                out.dtr = Some(step.values & 0x0001 != 0);
+                out.rts = Some(step.values & 0x0002 != 0);
+                return out;
+            }
+            if step.mask & 0x0001 != 0 {
+                out.dtr = Some(step.values & 0x0001 != 0);
+            }
+            if step.mask & 0x0002 != 0 {
+                out.rts = Some(step.values & 0x0002 != 0);
+            }
+            out
+        })
+        .collect();
+    Ok(ResetSequence { steps, labels: rst_seq.labels.clone(), classic: false })
+}
+
+/// Read the GPIO latch of the CP210x adapter behind `port` without changing it.
+pub fn cp210x_gpio_read(port: &str) -> Result<GpioLatchState, GpioResetError> {
     #[cfg(windows)]
     {
-        platform::cp210x_gpio_reset_windows(port, rst_seq)
+        platform::cp210x_gpio_read_windows(port)
     }
     #[cfg(unix)]
     {
-        platform::cp210x_gpio_reset_unix(port, rst_seq)
+        platform::cp210x_gpio_read_unix(port)
+    }
+}
+
+/// Run reset sequence on host GPIO lines instead of the serial adapter.
+/// DTR/RTS/BREAK steps, if any, still go to the session port.
+pub fn gpiochip_reset(
+    serial: &mut SessionPort,
+    lines: &GpioChipLines,
+    rst_seq: &ResetSequence,
+) -> Result<GpioResetStatus, GpioResetError> {
+    #[cfg(target_os = "linux")]
+    {
+        crate::gpiochip::run_sequence_gpiochip(lines, rst_seq, |step| set_serial_lines(serial, step))
+            .map(|_| GpioResetStatus::UsedGpioChip)
+            .map_err(|msg| GpioResetError::SequenceFailed(format!("gpiochip path failed: {msg}")))
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = (serial, rst_seq);
+        Err(GpioResetError::PortOpenFailed(format!(
+            "{}: GPIO character devices are only supported on Linux",
+            lines.chip
+        )))
+    }
+}
+
+/// Apply the DTR/RTS/BREAK part of a step, lines set to `None` are left alone.
+fn set_serial_lines(serial: &mut SessionPort, step: &ResetStep) -> Result<(), String> {
+    if let Some(on) = step.dtr {
+        serial.write_data_terminal_ready(on).map_err(|e| format!("setting DTR failed: {e}"))?;
+    }
+    if let Some(on) = step.rts {
+        serial.write_request_to_send(on).map_err(|e| format!("setting RTS failed: {e}"))?;
+    }
+    if let Some(on) = step.brk {
+        let res = if on { serial.set_break() } else { serial.clear_break() };
+        res.map_err(|e| format!("setting BREAK failed: {e}"))?;
+    }
+    Ok(())
+}
+
+/// Run reset sequence with the given backend. `Auto` picks the adapter's GPIO
+/// latch when there is one and DTR/RTS otherwise, explicit backends never fall back.
+///
+/// `port` is the path `serial` was opened from, used to find the adapter behind it.
+pub fn gpio_reset(
+    port: &str,
+    serial: &mut SessionPort,
+    rst_seq: &ResetSequence,
+    backend: ResetBackend,
+    gpiochip: Option<&GpioChipLines>,
+) -> Result<GpioResetReport, GpioResetError> {
+    if backend == ResetBackend::GpioChip {
+        return match gpiochip {
+            Some(lines) => gpiochip_reset(serial, lines, rst_seq).map(|status| GpioResetReport {
+                status,
+                skipped: Vec::new(),
+            }),
+            None => Err(GpioResetError::PortOpenFailed(
+                "gpiochip backend requires a chip and line offsets".into(),
+            )),
+        };
+    }
+
+    #[cfg(windows)]
+    {
+        platform::gpio_reset_windows(port, serial, rst_seq, backend)
+    }
+    #[cfg(unix)]
+    {
+        platform::gpio_reset_unix(port, serial, rst_seq, backend)
     }
 }
 
 #[cfg(windows)]
 mod platform {
-    use super::{GpioResetError, GpioResetStatus, ResetSequence};
+    use super::{
+        dtr_rts_sequence, run_steps, Cp210xPart, GpioLatchState, GpioResetError, GpioResetReport, GpioResetStatus,
+        set_serial_lines, LatchAttempt, ResetBackend, ResetSequence, ResetStep, SessionPort,
+    };
     //use std::error::Error;
+    use std::cell::Cell;
     use std::ffi::c_void;
     use std::fs::OpenOptions;
     use std::os::windows::io::AsRawHandle;
-    use std::time::Duration;
-    use std::thread::sleep;
 
     use libloading::Library;
 
@@ -69,17 +626,47 @@ mod platform {
     // const CP210X_DEVICE_NOT_FOUND: CP210x_STATUS = 5;
     // const CP210X_INVALID_ACCESS_TYPE: CP210x_STATUS = 6;
 
-    // EscapeCommFunction codes (WinAPI)
-    const SETRTS: u32 = 3;
-    const CLRRTS: u32 = 4;
-    const SETDTR: u32 = 5;
-    const CLRDTR: u32 = 6;
+    pub fn gpio_reset_windows(
+        port: &str,
+        serial: &mut SessionPort,
+        rst_seq: &ResetSequence,
+        backend: ResetBackend,
+    ) -> Result<GpioResetReport, GpioResetError> {
+        if backend == ResetBackend::Ftdi {
+            return Err(GpioResetError::PortOpenFailed(
+                "FTDI CBUS backend is only supported on Linux/Unix (libusb)".into(),
+            ));
+        }
+
+        let handle = serial.as_raw_handle() as HANDLE;
+        let mut set_lines = |step: &ResetStep| set_serial_lines(serial, step);
+        let mut skipped = Vec::new();
 
-    unsafe extern "system" {
-        fn EscapeCommFunction(h_file: HANDLE, dw_func: u32) -> i32;
+        if backend == ResetBackend::Auto && rst_seq.gpio_mask() == 0 {
+            skipped.push((ResetBackend::Cp210x, "sequence drives no GPIO pins".to_string()));
+        } else if backend != ResetBackend::DtrRts {
+            match run_sequence_cp210x(handle, rst_seq, &mut set_lines) {
+                Ok(LatchAttempt::Used) => {
+                    return Ok(GpioResetReport { status: GpioResetStatus::UsedCp210x, skipped });
+                }
+                Ok(LatchAttempt::Unsupported(reason)) if backend == ResetBackend::Cp210x => {
+                    return Err(GpioResetError::SequenceFailed(format!("{port}: CP210x GPIO latch not available: {reason}")));
+                }
+                Ok(LatchAttempt::Unsupported(reason)) => {
+                    skipped.push((ResetBackend::Cp210x, reason));
+                }
+                Err(msg) => {
+                    return Err(GpioResetError::SequenceFailed(format!("CP210x latch path failed: {msg}")));
+                }
+            }
+        }
+
+        let seq = dtr_rts_sequence(rst_seq).map_err(GpioResetError::SequenceFailed)?;
+        run_steps(&seq, |_, _, _| Ok(None), set_lines).map_err(GpioResetError::SequenceFailed)?;
+        Ok(GpioResetReport { status: GpioResetStatus::UsedDtrRts, skipped })
     }
 
-    pub fn cp210x_gpio_reset_windows(port: &str, rst_seq: &ResetSequence) -> Result<GpioResetStatus, GpioResetError> {
+    pub fn cp210x_gpio_read_windows(port: &str) -> Result<GpioLatchState, GpioResetError> {
         let file = OpenOptions::new()
             .read(true)
             .write(true)
@@ -87,34 +674,61 @@ mod platform {
             .map_err(|e| GpioResetError::PortOpenFailed(format!("{port}: {e}")))?;
 
         let handle = file.as_raw_handle() as HANDLE;
-        let quantum = Duration::from_millis(rst_seq.quantum_ms as u64);
+        let lib_name = "CP210xRuntime.dll";
+        unsafe {
+            let lib = Library::new(lib_name)
+                .map_err(|_| GpioResetError::SequenceFailed(format!("ERROR: {lib_name} not found")))?;
 
-        match run_sequence_cp210x(handle, rst_seq, quantum) {
-            Ok(true) => {
-                return Ok(GpioResetStatus::UsedCp210x);
-            }
-            Ok(false) => {
-                if let Err(msg) = run_sequence_dtr_rts(handle, rst_seq, quantum) {
-                    return Err(GpioResetError::SequenceFailed(msg));
-                } else {
-                    return  Ok(GpioResetStatus::UsedDtrRts)
-                }
+            let part = read_part_number(&lib, handle);
+            let latch = read_latch(&lib, handle).map_err(GpioResetError::SequenceFailed)?;
+            Ok(GpioLatchState {
+                part,
+                gpio_mask: part.map(|p| p.gpio_mask(1)).unwrap_or(0xFFFF),
+                latch,
+            })
+        }
+    }
+
+    unsafe fn read_part_number(lib: &Library, handle: HANDLE) -> Option<Cp210xPart> {
+        type FnPartNum = unsafe extern "system" fn(HANDLE, *mut u8) -> CP210x_STATUS;
+        unsafe {
+            let get_part = lib.get::<FnPartNum>(b"CP210xRT_GetPartNumber\0").ok()?;
+            let mut partnum = 0u8;
+            if get_part(handle, &mut partnum) == CP210X_SUCCESS {
+                Some(Cp210xPart::from_partnum(partnum))
+            } else {
+                None
             }
-            Err(msg) => {
-                eprintln!("CP210x latch path failed: {msg}");
-                return Err(GpioResetError::SequenceFailed(msg));
+        }
+    }
+
+    unsafe fn read_latch(lib: &Library, handle: HANDLE) -> Result<u16, String> {
+        type FnRead = unsafe extern "system" fn(HANDLE, *mut u16) -> CP210x_STATUS;
+        unsafe {
+            let func = lib
+                .get::<FnRead>(b"CP210xRT_ReadLatch\0")
+                .map_err(|_| "ERROR: CP210xRT_ReadLatch not found inside CP210xRuntime.dll".to_string())?;
+            let mut latch = 0u16;
+            let status = func(handle, &mut latch);
+            if status != CP210X_SUCCESS {
+                return Err(format!("CP210xRT_ReadLatch error {status}"));
             }
+            Ok(latch)
         }
-        // file closed by Drop.        
     }
 
-    fn run_sequence_cp210x(handle: HANDLE, rst_seq: &ResetSequence, quantum: Duration) -> Result<bool, String> {
+    fn run_sequence_cp210x(
+        handle: HANDLE,
+        rst_seq: &ResetSequence,
+        set_lines: impl FnMut(&ResetStep) -> Result<(), String>,
+    ) -> Result<LatchAttempt, String> {
         let lib_name = "CP210xRuntime.dll";
         unsafe {
+            // Nothing is written before the first step: setup failures only rule the latch out.
             let lib = match Library::new(lib_name) {
                 Ok(l) => l,
                 Err(_) => {
-                    return Err(format!("ERROR: {lib_name} not found"));
+                    return Ok(LatchAttempt::Unsupported(format!("{lib_name} not found")));
                 }
             };
 
@@ -123,215 +737,406 @@ mod platform {
             let func: libloading::Symbol<FnWrite> = match lib.get(b"CP210xRT_WriteLatch\0") {
                 Ok(f) => f,
                 Err(_) => {
-                    return Err(format!("ERROR: CP210xRT_WriteLatch not found inside {lib_name}"));
+                    return Ok(LatchAttempt::Unsupported(format!("CP210xRT_WriteLatch not found inside {lib_name}")));
                 }
             };
 
-            for &val in &rst_seq.values {
-                let status = func(handle, rst_seq.mask, val);
-                if status != CP210X_SUCCESS {
-                    eprintln!("CP210xRT_WriteLatch error {status}");
-                    return Ok(false);
+            // The DLL picks the latch encoding itself, but reject masks the part can't drive.
+            if let Some(part) = read_part_number(&lib, handle) {
+                if part.gpio_mask(1) == 0 {
+                    return Ok(LatchAttempt::Unsupported(format!("{part} has no GPIO latch")));
                 }
-                if quantum.as_millis() > 0 {
-                    sleep(quantum);
+                // Interface of a COM port is unknown here: use the widest set (CP2105 SCI).
+                if let Err(msg) = part.check_mask(1, rst_seq.gpio_mask()) {
+                    return Ok(LatchAttempt::Unsupported(msg));
                 }
             }
-        }
 
-        Ok(true)
-    }
-
-    fn run_sequence_dtr_rts(handle: HANDLE, rst_seq: &ResetSequence, quantum: Duration) -> Result<(), String> {
-        for &val in &rst_seq.values {
-            let dtr = (val & 0x0001) != 0;
-            let rts = (val & 0x0002) != 0;
-
-            unsafe {
-                let res_dtr = if dtr {
-                    EscapeCommFunction(handle, SETDTR)
-                } else {
-                    EscapeCommFunction(handle, CLRDTR)
-                };
-                if res_dtr == 0 {
-                    return Err("EscapeCommFunction for DTR failed".into());
-                }
-
-                let res_rts = if rts {
-                    EscapeCommFunction(handle, SETRTS)
-                } else {
-                    EscapeCommFunction(handle, CLRRTS)
-                };
-                if res_rts == 0 {
-                    return Err("EscapeCommFunction for RTS failed".into());
-                }
-            }
-
-            if quantum.as_millis() > 0 {
-                sleep(quantum);
+            // A failing first write means the port is not a CP210x at all.
+            let written = Cell::new(false);
+            let not_cp210x = Cell::new(false);
+            let result = run_steps(
+                rst_seq,
+                |step, _, _| {
+                    let status = func(handle, step.mask, step.values);
+                    if status != CP210X_SUCCESS {
+                        not_cp210x.set(!written.get());
+                        return Err(format!("CP210xRT_WriteLatch error {status}"));
+                    }
+                    written.set(true);
+                    read_latch(&lib, handle).map(Some)
+                },
+                set_lines,
+            );
+            if not_cp210x.get() {
+                let msg = result.err().unwrap_or_default();
+                return Ok(LatchAttempt::Unsupported(format!("not a CP210x port ({msg})")));
             }
+            result?;
         }
 
-        Ok(())
+        Ok(LatchAttempt::Used)
     }
 }
 
 #[cfg(unix)]
 mod platform {
-    use super::{GpioResetError, GpioResetStatus, ResetSequence};
-    use std::fs::OpenOptions;
-    use std::io;
-    use std::os::unix::io::AsRawFd;
+    use super::{
+        dtr_rts_sequence, run_steps, Cp210xPart, GpioLatchState, GpioResetError, GpioResetReport, GpioResetStatus,
+        set_serial_lines, LatchAttempt, ResetBackend, ResetSequence, ResetStep, SessionPort,
+    };
     use std::time::Duration;
-    use std::thread::sleep;
 
-    use libc::{self, c_int};
-    use rusb::{Context, DeviceHandle, UsbContext, Direction, RequestType, Recipient};
+    use rusb::{Context, DeviceHandle, Direction, RequestType, Recipient};
+
+    use crate::ftdi;
+    use crate::usbtty::{locate_usb_tty, open_usb_device, UsbTtyLocation};
 
     const VID: u16 = 0x10c4; // Silicon Labs
-    const PID: u16 = 0xea60; // CP2102N (UART bridge)
+    const PIDS: [u16; 3] = [
+        0xea60, // CP2102/CP2102N/CP2103/CP2104
+        0xea70, // CP2105
+        0xea71, // CP2108
+    ];
     const REQ_VENDOR_SPEC: u8 = 0xFF;
-    const WRITE_LATCH: u16 = 0x37E1; // wValue, wIndex = (MASK << 8) | STATE
+    const GET_PARTNUM: u16 = 0x370B;
+    const READ_LATCH: u16 = 0x00C2;
+    const WRITE_LATCH: u16 = 0x37E1;
+    const USB_TIMEOUT: Duration = Duration::from_millis(200);
 
-    pub fn cp210x_gpio_reset_unix(
+    pub fn gpio_reset_unix(
         port: &str,
+        serial: &mut SessionPort,
         rst_seq: &ResetSequence,
-    ) -> Result<GpioResetStatus, GpioResetError> {
-        let file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(port)
-            .map_err(|e| GpioResetError::PortOpenFailed(format!("{port}: {e}")))?;
+        backend: ResetBackend,
+    ) -> Result<GpioResetReport, GpioResetError> {
+        let mut set_lines = |step: &ResetStep| set_serial_lines(serial, step).map_err(|e| format!("{port}: {e}"));
+        let mut skipped: Vec<(ResetBackend, String)> = Vec::new();
 
-        let fd = file.as_raw_fd();
-        let quantum = Duration::from_millis(rst_seq.quantum_ms as u64);
+        if backend != ResetBackend::DtrRts {
+            let latch_backends = [ResetBackend::Cp210x, ResetBackend::Ftdi];
+            let tried: Vec<ResetBackend> = latch_backends
+                .into_iter()
+                .filter(|&b| backend == ResetBackend::Auto || backend == b)
+                .collect();
+            let no_gpio = backend == ResetBackend::Auto && rst_seq.gpio_mask() == 0;
 
-        match run_sequence_cp210x_usb(rst_seq, quantum) {
-            Ok(true) => {
-                return Ok(GpioResetStatus::UsedCp210x);
-            }
-            Ok(false) => {
-            }
-            Err(msg) => {
-                eprintln!("CP2102N USB latch path failed: {msg}");                
+            // 1) Find the USB adapter behind this exact tty. If the mapping fails we
+            //    must not guess: driving another board's reset is worse than failing,
+            //    so no latch is used (`auto` still falls back to DTR/RTS on this tty).
+            let location = if no_gpio {
+                Ok(None)
+            } else {
+                locate_usb_tty(port).map_err(|msg| format!("cannot map {port} to its USB adapter: {msg}"))
+            };
+
+            for b in tried {
+                let attempt = if no_gpio {
+                    LatchAttempt::Unsupported("sequence drives no GPIO pins".into())
+                } else {
+                    match &location {
+                        Err(reason) => LatchAttempt::Unsupported(reason.clone()),
+                        Ok(None) => LatchAttempt::Unsupported(format!("{port} is not a USB adapter")),
+                        Ok(Some(loc)) => try_latch_backend(b, loc, rst_seq, &mut set_lines)?,
+                    }
+                };
+                match attempt {
+                    LatchAttempt::Used => {
+                        let status = if b == ResetBackend::Ftdi {
+                            GpioResetStatus::UsedFtdiCbus
+                        } else {
+                            GpioResetStatus::UsedCp210x
+                        };
+                        return Ok(GpioResetReport { status, skipped });
+                    }
+                    LatchAttempt::Unsupported(reason) if backend == b => {
+                        return Err(GpioResetError::SequenceFailed(format!("{port}: {b} backend not available: {reason}")));
+                    }
+                    LatchAttempt::Unsupported(reason) => skipped.push((b, reason)),
+                }
             }
         }
 
-        // 2) Fallback to DTR/RTS 
-        if let Err(e) = run_sequence_dtr_rts_unix(fd, rst_seq, quantum) {
-            return Err(GpioResetError::SequenceFailed(format!(
-                "DTR/RTS ioctl failed on {port}: {e}"
-            )));
-        }
+        // 2) No GPIO-capable bridge: fall back to DTR/RTS
+        let seq = dtr_rts_sequence(rst_seq).map_err(GpioResetError::SequenceFailed)?;
+        run_steps(&seq, |_, _, _| Ok(None), set_lines).map_err(GpioResetError::SequenceFailed)?;
 
-        Ok(GpioResetStatus::UsedDtrRts)
-        // file closed by drop Drop, with fd 
+        Ok(GpioResetReport { status: GpioResetStatus::UsedDtrRts, skipped })
     }
 
-    fn run_sequence_cp210x_usb(
+    fn try_latch_backend(
+        backend: ResetBackend,
+        loc: &UsbTtyLocation,
         rst_seq: &ResetSequence,
-        quantum: Duration,
-    ) -> Result<bool, String> {
-        let ctx = Context::new()
-            .map_err(|e| format!("rusb Context::new failed: {e}"))?;
-        let devices = ctx
-            .devices()
-            .map_err(|e| format!("rusb devices() failed: {e}"))?;
-    
-        let mut handle_opt: Option<DeviceHandle<Context>> = None;
-
-        for dev in devices.iter() {
-            let desc = dev
-                .device_descriptor()
-                .map_err(|e| format!("device_descriptor() failed: {e}"))?;
-            if desc.vendor_id() == VID && desc.product_id() == PID {
-                let handle = dev
-                    .open()
-                    .map_err(|e| format!("cannot open CP2102N device: {e}"))?;
-                handle_opt = Some(handle);
-                break;
-            }
+        set_lines: impl FnMut(&ResetStep) -> Result<(), String>,
+    ) -> Result<LatchAttempt, GpioResetError> {
+        match backend {
+            ResetBackend::Cp210x if is_cp210x(loc) => run_sequence_cp210x_usb(loc, rst_seq, set_lines).map_err(|msg| {
+                GpioResetError::SequenceFailed(format!("CP210x USB latch path failed on {}: {msg}", loc.describe()))
+            }),
+            ResetBackend::Ftdi if ftdi::is_ftdi(loc) => ftdi::run_sequence_ftdi_cbus(loc, rst_seq, set_lines).map_err(|msg| {
+                GpioResetError::SequenceFailed(format!("FTDI CBUS path failed on {}: {msg}", loc.describe()))
+            }),
+            _ => Ok(LatchAttempt::Unsupported(format!("{} is not a {backend} adapter", loc.describe()))),
         }
+    }
 
-        let handle = match handle_opt {
-            Some(h) => h,
-            None => return Ok(false), 
+    pub fn cp210x_gpio_read_unix(port: &str) -> Result<GpioLatchState, GpioResetError> {
+        let location = locate_usb_tty(port).map_err(|msg| {
+            GpioResetError::PortOpenFailed(format!("cannot map {port} to its USB adapter: {msg}"))
+        })?;
+        let loc = match location {
+            Some(loc) if is_cp210x(&loc) => loc,
+            Some(loc) => {
+                return Err(GpioResetError::SequenceFailed(format!("{} is not a CP210x adapter", loc.describe())));
+            }
+            None => {
+                return Err(GpioResetError::SequenceFailed(format!("{port} is not a USB adapter")));
+            }
         };
 
-        let req_type =
-            rusb::request_type(Direction::Out, RequestType::Vendor, Recipient::Device);
+        let dev = Cp210xUsb::open(&loc).map_err(GpioResetError::SequenceFailed)?;
+        let gpio_mask = dev.part.gpio_mask(dev.interface);
+        if gpio_mask == 0 {
+            return Err(GpioResetError::SequenceFailed(format!("{} has no GPIO latch", dev.part)));
+        }
+        let latch = dev.read_latch().map_err(GpioResetError::SequenceFailed)?;
+        Ok(GpioLatchState {
+            part: Some(dev.part),
+            gpio_mask,
+            latch,
+        })
+    }
+
+    fn is_cp210x(loc: &UsbTtyLocation) -> bool {
+        loc.driver == "cp210x" || (loc.vid == VID && PIDS.contains(&loc.pid))
+    }
+
+    /// One CP210x bridge opened through libusb, with its part-specific latch protocol.
+    struct Cp210xUsb {
+        handle: DeviceHandle<Context>,
+        part: Cp210xPart,
+        interface: u8,
+    }
 
-        for &val in &rst_seq.values {
-            // host-level semantics: mask = какие биты обновлять, val = новое состояние
-            let update_mask = rst_seq.mask & 0x00FF;
-            let new_state_bits = val & rst_seq.mask & 0x00FF;
+    impl Cp210xUsb {
+        fn open(loc: &UsbTtyLocation) -> Result<Self, String> {
+            let handle = open_usb_device(loc)?;
 
-            // CP2102N semantics
-            //  - low byte (state/update mask)  = update_mask
-            //  - high byte (mask/new state)    = new_state_bits
-            let windex: u16 = ((new_state_bits as u16) << 8) | (update_mask as u16);
+            let req_type =
+                rusb::request_type(Direction::In, RequestType::Vendor, Recipient::Device);
+            let mut buf = [0u8; 1];
+            let n = handle
+                .read_control(
+                    req_type,
+                    REQ_VENDOR_SPEC,
+                    GET_PARTNUM,
+                    loc.interface as u16,
+                    &mut buf,
+                    USB_TIMEOUT,
+                )
+                .map_err(|e| format!("GET_PARTNUM failed: {e}"))?;
+            if n != 1 {
+                return Err(format!("GET_PARTNUM returned {n} bytes"));
+            }
+
+            Ok(Cp210xUsb {
+                handle,
+                part: Cp210xPart::from_partnum(buf[0]),
+                interface: loc.interface,
+            })
+        }
+
+        fn latch_recipient(&self) -> Recipient {
+            if self.part.latch_per_interface() { Recipient::Interface } else { Recipient::Device }
+        }
 
-            handle
-                .write_control(
+        /// Read current latch: 16 bits on CP2108, 8 bits on the rest.
+        fn read_latch(&self) -> Result<u16, String> {
+            let len = if self.part == Cp210xPart::Cp2108 { 2 } else { 1 };
+            let req_type = rusb::request_type(Direction::In, RequestType::Vendor, self.latch_recipient());
+            let mut buf = [0u8; 2];
+            let n = self
+                .handle
+                .read_control(
                     req_type,
                     REQ_VENDOR_SPEC,
-                    WRITE_LATCH,
-                    windex,
-                    &[],
-                    Duration::from_millis(200),
+                    READ_LATCH,
+                    self.interface as u16,
+                    &mut buf[..len],
+                    USB_TIMEOUT,
                 )
-                .map_err(|e| format!("write_control failed: {e}"))?;
-        
-            if quantum.as_millis() > 0 {
-                sleep(quantum);
+                .map_err(|e| format!("{} READ_LATCH failed: {e}", self.part))?;
+            if n != len {
+                return Err(format!("{} READ_LATCH returned {n} bytes, expected {len}", self.part));
             }
+            Ok(u16::from_le_bytes(buf))
         }
 
-        Ok(true)
+        /// Update latch bits selected by `mask` to `state`.
+        fn write_latch(&self, mask: u16, state: u16) -> Result<(), String> {
+            let state = state & mask;
+            let req_type = rusb::request_type(Direction::Out, RequestType::Vendor, self.latch_recipient());
+            let result = match self.part {
+                // CP2105: 8-bit latch per interface, [mask, state] in the data stage.
+                Cp210xPart::Cp2105 => {
+                    self.handle.write_control(
+                        req_type,
+                        REQ_VENDOR_SPEC,
+                        WRITE_LATCH,
+                        self.interface as u16,
+                        &[mask as u8, state as u8],
+                        USB_TIMEOUT,
+                    )
+                }
+                // CP2108: one 16-bit latch for the device, [mask LE16, state LE16] in the data stage.
+                Cp210xPart::Cp2108 => {
+                    let mut data = [0u8; 4];
+                    data[0..2].copy_from_slice(&mask.to_le_bytes());
+                    data[2..4].copy_from_slice(&state.to_le_bytes());
+                    self.handle.write_control(
+                        req_type,
+                        REQ_VENDOR_SPEC,
+                        WRITE_LATCH,
+                        self.interface as u16,
+                        &data,
+                        USB_TIMEOUT,
+                    )
+                }
+                // CP2103/CP2104/CP2102N: no data stage, wIndex = (STATE << 8) | MASK
+                _ => {
+                    let windex: u16 = ((state & 0x00FF) << 8) | (mask & 0x00FF);
+                    self.handle.write_control(
+                        req_type,
+                        REQ_VENDOR_SPEC,
+                        WRITE_LATCH,
+                        windex,
+                        &[],
+                        USB_TIMEOUT,
+                    )
+                }
+            };
+            result
+                .map(|_| ())
+                .map_err(|e| format!("{} WRITE_LATCH failed: {e}", self.part))
+        }
     }
 
-    fn run_sequence_dtr_rts_unix(
-        fd: i32,
+    fn run_sequence_cp210x_usb(
+        loc: &UsbTtyLocation,
         rst_seq: &ResetSequence,
-        quantum: Duration,
-    ) -> io::Result<()> {
-        for &val in &rst_seq.values {
-            let dtr = (val & 0x0001) != 0;
-            let rts = (val & 0x0002) != 0;
-            set_dtr_rts(fd, dtr, rts)?;
-
-            if quantum.as_millis() > 0 {
-                sleep(quantum);
-            }
+        set_lines: impl FnMut(&ResetStep) -> Result<(), String>,
+    ) -> Result<LatchAttempt, String> {
+        // Nothing is written yet: failing to open or query the bridge only rules it out.
+        let dev = match Cp210xUsb::open(loc) {
+            Ok(dev) => dev,
+            Err(msg) => return Ok(LatchAttempt::Unsupported(msg)),
+        };
+        if dev.part.gpio_mask(dev.interface) == 0 {
+            return Ok(LatchAttempt::Unsupported(format!("{} has no GPIO latch", dev.part)));
         }
-        Ok(())
+        if let Err(msg) = dev.part.check_mask(dev.interface, rst_seq.gpio_mask()) {
+            return Ok(LatchAttempt::Unsupported(msg));
+        }
+
+        run_steps(
+            rst_seq,
+            |step, _, _| {
+                dev.write_latch(step.mask, step.values)?;
+                dev.read_latch().map(Some)
+            },
+            set_lines,
+        )?;
+
+        Ok(LatchAttempt::Used)
     }
+}
 
-    fn set_dtr_rts(fd: i32, dtr: bool, rts: bool) -> io::Result<()> {
-        unsafe {
-            let mut flags: c_int = 0;
+// ---- Unit tests ----
 
-            // Get current modem control flags.
-            if libc::ioctl(fd, libc::TIOCMGET, &mut flags) == -1 {
-                return Err(io::Error::last_os_error());
-            }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-            if dtr {
-                flags |= libc::TIOCM_DTR;
-            } else {
-                flags &= !libc::TIOCM_DTR;
-            }
+    #[test]
+    fn reset_steps_with_timing_labels_and_lines() {
+        let labels = vec!["BOOT".to_string(), "RESET".to_string()];
+        let steps = parse_reset_steps("boot=0; RESET=low 5ms; wait 1s; RESET=1 DTR=1 BREAK=1 200ms; GPIO0=high;", &labels).unwrap();
+        assert_eq!(steps.len(), 5);
+        assert_eq!((steps[0].mask, steps[0].values, steps[0].hold_ms), (0b01, 0b00, 0));
+        assert_eq!((steps[1].mask, steps[1].values, steps[1].hold_ms), (0b10, 0b00, 5));
+        assert_eq!(steps[2], ResetStep { hold_ms: 1000, ..ResetStep::default() });
+        assert_eq!((steps[3].mask, steps[3].values, steps[3].dtr, steps[3].brk), (0b10, 0b10, Some(true), Some(true)));
+        assert_eq!(steps[3].rts, None);
+        assert_eq!((steps[4].mask, steps[4].values), (0b01, 0b01));
 
-            if rts {
-                flags |= libc::TIOCM_RTS;
-            } else {
-                flags &= !libc::TIOCM_RTS;
-            }
+        let seq = ResetSequence { steps, labels, classic: false };
+        assert_eq!(seq.gpio_mask(), 0b11);
+        assert_eq!(seq.to_string(), "GPIO0=0; GPIO1=0 5ms; wait 1000ms; GPIO1=1 DTR=1 BREAK=1 200ms; GPIO0=1");
+    }
 
-            if libc::ioctl(fd, libc::TIOCMSET, &flags) == -1 {
-                return Err(io::Error::last_os_error());
-            }
+    #[test]
+    fn reset_steps_reject_bad_input() {
+        assert!(parse_reset_steps("", &[]).is_err());
+        assert!(parse_reset_steps("GPIO16=1", &[]).unwrap_err().contains("unknown pin"));
+        assert!(parse_reset_steps("DTR=1 DTR=0", &[]).unwrap_err().contains("twice"));
+        assert!(parse_reset_steps("RTS=2", &[]).unwrap_err().contains("invalid level"));
+        assert!(parse_reset_steps("RTS=1 5ms 6ms", &[]).unwrap_err().contains("more than one"));
+        assert!(parse_reset_steps("wait RTS=1 5ms", &[]).unwrap_err().contains("cannot drive"));
+        assert!(parse_reset_steps("GPIO0=1; 5min", &[]).unwrap_err().contains("invalid time"));
+    }
+
+    #[test]
+    fn dtr_rts_fallback() {
+        let lines = |seq: &ResetSequence| -> Vec<(Option<bool>, Option<bool>)> {
+            let seq = dtr_rts_sequence(seq).unwrap();
+            assert_eq!(seq.gpio_mask(), 0);
+            seq.steps.iter().map(|step| (step.dtr, step.rts)).collect()
+        };
+
+        // `-r`: both lines follow bits 0/1 of every raw value, whatever the mask.
+        let classic = |mask: u16| lines(&ResetSequence::from_values(50, mask, &[0b00, 0b10, 0b01]));
+        let raw = [(Some(false), Some(false)), (Some(false), Some(true)), (Some(true), Some(false))];
+        for mask in [0x0001, 0x0002, 0x0003, 0x000C] {
+            assert_eq!(classic(mask), raw, "mask 0x{mask:04X}");
         }
-        Ok(())
+
+        // Steps: only the named pins.
+        let steps = |text: &str| lines(&ResetSequence { steps: parse_reset_steps(text, &[]).unwrap(), labels: Vec::new(), classic: false });
+        assert_eq!(steps("GPIO0=1; GPIO0=0"), [(Some(true), None), (Some(false), None)]);
+        assert_eq!(steps("GPIO1=1 GPIO0=0; GPIO2=1"), [(Some(false), Some(true)), (None, None)]);
+
+        let mixed = ResetSequence { steps: parse_reset_steps("GPIO0=1 DTR=0", &[]).unwrap(), labels: Vec::new(), classic: false };
+        assert!(dtr_rts_sequence(&mixed).is_err());
+    }
+
+    #[test]
+    fn cp210x_parts_and_gpio_masks() {
+        let parts = [
+            (0x02, Cp210xPart::Cp2102, 0x0000, 0x0000),
+            (0x03, Cp210xPart::Cp2103, 0x000F, 0x000F),
+            (0x05, Cp210xPart::Cp2105, 0x0003, 0x0007),
+            (0x08, Cp210xPart::Cp2108, 0xFFFF, 0xFFFF),
+            (0x20, Cp210xPart::Cp2102nQfn28, 0x007F, 0x007F),
+            (0x22, Cp210xPart::Cp2102nQfn20, 0x000F, 0x000F),
+            (0x42, Cp210xPart::Unknown(0x42), 0x0000, 0x0000),
+        ];
+        for (partnum, part, if0, if1) in parts {
+            assert_eq!(Cp210xPart::from_partnum(partnum), part);
+            assert_eq!((part.gpio_mask(0), part.gpio_mask(1)), (if0, if1), "{part}");
+            assert_eq!(part.latch_per_interface(), part == Cp210xPart::Cp2105, "{part}");
+        }
+        assert_eq!(Cp210xPart::Unknown(0x42).to_string(), "unknown CP210x part 0x42");
+    }
+
+    #[test]
+    fn cp210x_mask_check() {
+        assert!(Cp210xPart::Cp2102nQfn28.check_mask(0, 0x0041).is_ok());
+        assert!(Cp210xPart::Cp2108.check_mask(3, 0x8001).is_ok());
+        assert!(Cp210xPart::Cp2105.check_mask(1, 0x0004).is_ok());
+        let err = Cp210xPart::Cp2105.check_mask(0, 0x0004).unwrap_err();
+        assert!(err.contains("interface 0") && err.contains("0x0003"), "{err}");
+        assert!(Cp210xPart::Cp2102nQfn24.check_mask(0, 0x0010).is_err());
+        assert_eq!(Cp210xPart::Cp2102.check_mask(0, 0x0001).unwrap_err(), "CP2102 has no GPIO latch");
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,32 @@
+//! Best-effort output file.
+//!
+//! The device log and the trace are written next to an upload that may be
+//! halfway through a flash erase. A full disk or a removed USB stick is not
+//! worth aborting that for: the first write error is reported once and the
+//! file is given up, the session carries on without it.
+
+use std::io::{self, Write};
+
+pub struct Sink<W> {
+    /// What and where, for the warning: `device log out.txt`.
+    name: String,
+    /// What stops with the file: `logging stopped`.
+    stops: &'static str,
+    out: Option<W>,
+}
+
+impl<W: Write> Sink<W> {
+    pub fn new(name: String, stops: &'static str, out: W) -> Self {
+        Sink { name, stops, out: Some(out) }
+    }
+
+    /// Run `op` on the file unless an earlier write failed; its error ends the output.
+    pub fn write(&mut self, op: impl FnOnce(&mut W) -> io::Result<()>) {
+        if let Some(out) = &mut self.out
+            && let Err(e) = op(out)
+        {
+            eprintln!("WARNING: {}: {e}, {}", self.name, self.stops);
+            self.out = None;
+        }
+    }
+}
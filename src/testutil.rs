@@ -0,0 +1,37 @@
+//! Fixtures shared by the unit tests of several modules.
+
+use super::packet::{packet_build, PACKET_SIGN_RX};
+use super::trace::{TraceEvent, TraceRecord};
+
+/// First address of the main firmware in `sfu_info_body`.
+pub const MAIN_START: u32 = 0x0800_4000;
+
+/// Command line `sfu <args>`.
+pub fn args(args: &[&str]) -> Vec<String> {
+    std::iter::once("sfu").chain(args.iter().copied()).map(String::from).collect()
+}
+
+/// Device answer: a host frame with the RX signature (the CRC does not cover it).
+pub fn device_frame(code: u8, body: &[u8]) -> Vec<u8> {
+    let mut frame = packet_build(code, body);
+    frame[..4].copy_from_slice(&PACKET_SIGN_RX.to_be_bytes());
+    frame
+}
+
+/// SFU_CMD_INFO answer of a 256 KiB device with a 16 KiB receive buffer.
+/// `sfu_ver` below 0x200 has no SFU_CMD_SPEED.
+pub fn sfu_info_body(sfu_ver: u16) -> Vec<u8> {
+    let mut info = vec![0x41; 12];
+    info.extend_from_slice(&0x410u32.to_le_bytes()); // cpu type
+    info.extend_from_slice(&256u16.to_le_bytes()); // flash KiB
+    info.extend_from_slice(&sfu_ver.to_le_bytes());
+    info.extend_from_slice(&0x4000u32.to_le_bytes()); // receive size
+    info.extend_from_slice(&MAIN_START.to_le_bytes());
+    info.extend_from_slice(&MAIN_START.to_le_bytes());
+    info
+}
+
+/// A recording of `events`, 100 us apart.
+pub fn records(events: impl IntoIterator<Item = TraceEvent>) -> Vec<TraceRecord> {
+    events.into_iter().enumerate().map(|(i, event)| TraceRecord { us: i as u64 * 100, event }).collect()
+}
@@ -0,0 +1,341 @@
+//! Raw wire trace (`--trace`).
+//!
+//! `TracePort` wraps the session port and records every buffer written and
+//! every chunk read, plus baud rate changes, as one text line each:
+//!
+//! ```text
+//! # sfu-trace 1
+//! # port /dev/ttyUSB0
+//! # start 1760000000.123456
+//! 0 B 921600
+//! 1532 > 817EA34597680000D8AFF317
+//! 1841 < 45A37E8197682000...
+//! ```
+//!
+//! Fields are separated by one space: microseconds since `start` (unix time),
+//! the record kind and its data. Kinds: `>` host to device, `<` device to host
+//! (bytes as uppercase hex, no separators), `B` new baud rate (decimal).
+//! Lines starting with `#` are comments. Data is recorded exactly as chunked
+//! by the OS, which keeps the file small and the read timing visible.
+//!
+//! A path ending in `.pcapng` records the same traffic as decoded frames for
+//! Wireshark instead (see `pcapng.rs`).
+
+use std::fs::File;
+use std::io::{self, LineWriter, Read, Write};
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serialport::{ClearBuffer, DataBits, FlowControl, Parity, SerialPort, StopBits};
+
+use super::pcapng::PcapngEncoder;
+use super::sink::Sink;
+
+pub const TRACE_VERSION: u32 = 1;
+
+pub struct TraceWriter {
+    out: Sink<Box<dyn Write + Send>>,
+    start: Instant,
+    /// Unix time of `start`.
+    start_unix: Duration,
+    /// Set when writing pcapng instead of text.
+    pcapng: Option<PcapngEncoder>,
+}
+
+impl TraceWriter {
+    pub fn create(path: &str, port: &str) -> Result<Self, String> {
+        let file = File::create(path).map_err(|e| format!("{path}: {e}"))?;
+        let pcapng = Path::new(path).extension().is_some_and(|ext| ext.eq_ignore_ascii_case("pcapng"));
+        // Whole pcapng blocks go out in one write each, text is flushed per line.
+        let out: Box<dyn Write + Send> = if pcapng { Box::new(file) } else { Box::new(LineWriter::new(file)) };
+        let mut trace = TraceWriter {
+            out: Sink::new(format!("trace {path}"), "recording stopped", out),
+            start: Instant::now(),
+            start_unix: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default(),
+            pcapng: pcapng.then(PcapngEncoder::new),
+        };
+        if pcapng {
+            trace.write(&PcapngEncoder::header(port));
+        } else {
+            let now = trace.start_unix;
+            trace.write(format!("# sfu-trace {TRACE_VERSION}\n# port {port}\n# start {}.{:06}\n", now.as_secs(), now.subsec_micros()).as_bytes());
+        }
+        Ok(trace)
+    }
+
+    pub fn tx(&mut self, data: &[u8]) {
+        let us = self.unix_us();
+        match &mut self.pcapng {
+            Some(pcapng) => {
+                let packets = pcapng.tx(us, data);
+                self.write(&packets);
+            }
+            None => self.record('>', &hex(data)),
+        }
+    }
+
+    pub fn rx(&mut self, data: &[u8]) {
+        let us = self.unix_us();
+        match &mut self.pcapng {
+            Some(pcapng) => {
+                let packets = pcapng.rx(us, data);
+                self.write(&packets);
+            }
+            None => self.record('<', &hex(data)),
+        }
+    }
+
+    pub fn baud(&mut self, baud: u32) {
+        match &self.pcapng {
+            Some(pcapng) => {
+                let packet = pcapng.baud(self.unix_us(), baud);
+                self.write(&packet);
+            }
+            None => self.record('B', &baud.to_string()),
+        }
+    }
+
+    fn record(&mut self, kind: char, data: &str) {
+        let us = self.start.elapsed().as_micros();
+        self.write(format!("{us} {kind} {data}\n").as_bytes());
+    }
+
+    fn unix_us(&self) -> u64 {
+        (self.start_unix + self.start.elapsed()).as_micros() as u64
+    }
+
+    /// A trace that can no longer be written ends short; the port keeps working.
+    fn write(&mut self, data: &[u8]) {
+        self.out.write(|out| out.write_all(data));
+    }
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{b:02X}")).collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TraceEvent {
+    Tx(Vec<u8>),
+    Rx(Vec<u8>),
+    Baud(u32),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceRecord {
+    /// Microseconds since the start of the trace.
+    pub us: u64,
+    pub event: TraceEvent,
+}
+
+/// Parse a trace written by `TraceWriter`.
+pub fn parse_trace(text: &str) -> Result<Vec<TraceRecord>, String> {
+    let mut records = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line_no = n + 1;
+        let line = line.trim();
+        if let Some(comment) = line.strip_prefix('#') {
+            if let Some(version) = comment.trim().strip_prefix("sfu-trace ")
+                && version.trim() != TRACE_VERSION.to_string()
+            {
+                return Err(format!("line {line_no}: unsupported trace version {version}"));
+            }
+            continue;
+        }
+        if line.is_empty() {
+            continue;
+        }
+
+        let mut fields = line.split(' ');
+        let (Some(us), Some(kind), Some(data), None) = (fields.next(), fields.next(), fields.next(), fields.next()) else {
+            return Err(format!("line {line_no}: expected '<us> <kind> <data>'"));
+        };
+        let us = us.parse::<u64>().map_err(|e| format!("line {line_no}: invalid time '{us}': {e}"))?;
+        let event = match kind {
+            ">" => TraceEvent::Tx(unhex(data).ok_or_else(|| format!("line {line_no}: invalid hex data"))?),
+            "<" => TraceEvent::Rx(unhex(data).ok_or_else(|| format!("line {line_no}: invalid hex data"))?),
+            "B" => TraceEvent::Baud(data.parse::<u32>().map_err(|e| format!("line {line_no}: invalid baud rate '{data}': {e}"))?),
+            _ => return Err(format!("line {line_no}: unknown record kind '{kind}'")),
+        };
+        records.push(TraceRecord { us, event });
+    }
+    Ok(records)
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok()).collect()
+}
+
+/// Session port that records its traffic to a `TraceWriter`.
+pub struct TracePort {
+    inner: Box<dyn SerialPort>,
+    trace: TraceWriter,
+}
+
+impl TracePort {
+    pub fn new(inner: Box<dyn SerialPort>, mut trace: TraceWriter) -> Self {
+        if let Ok(baud) = inner.baud_rate() {
+            trace.baud(baud);
+        }
+        TracePort { inner, trace }
+    }
+}
+
+impl Read for TracePort {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n > 0 {
+            self.trace.rx(&buf[..n]);
+        }
+        Ok(n)
+    }
+}
+
+impl Write for TracePort {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        if n > 0 {
+            self.trace.tx(&buf[..n]);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl SerialPort for TracePort {
+    fn name(&self) -> Option<String> {
+        self.inner.name()
+    }
+    fn baud_rate(&self) -> serialport::Result<u32> {
+        self.inner.baud_rate()
+    }
+    fn data_bits(&self) -> serialport::Result<DataBits> {
+        self.inner.data_bits()
+    }
+    fn flow_control(&self) -> serialport::Result<FlowControl> {
+        self.inner.flow_control()
+    }
+    fn parity(&self) -> serialport::Result<Parity> {
+        self.inner.parity()
+    }
+    fn stop_bits(&self) -> serialport::Result<StopBits> {
+        self.inner.stop_bits()
+    }
+    fn timeout(&self) -> Duration {
+        self.inner.timeout()
+    }
+    fn set_baud_rate(&mut self, baud_rate: u32) -> serialport::Result<()> {
+        self.inner.set_baud_rate(baud_rate)?;
+        self.trace.baud(baud_rate);
+        Ok(())
+    }
+    fn set_data_bits(&mut self, data_bits: DataBits) -> serialport::Result<()> {
+        self.inner.set_data_bits(data_bits)
+    }
+    fn set_flow_control(&mut self, flow_control: FlowControl) -> serialport::Result<()> {
+        self.inner.set_flow_control(flow_control)
+    }
+    fn set_parity(&mut self, parity: Parity) -> serialport::Result<()> {
+        self.inner.set_parity(parity)
+    }
+    fn set_stop_bits(&mut self, stop_bits: StopBits) -> serialport::Result<()> {
+        self.inner.set_stop_bits(stop_bits)
+    }
+    fn set_timeout(&mut self, timeout: Duration) -> serialport::Result<()> {
+        self.inner.set_timeout(timeout)
+    }
+    fn write_request_to_send(&mut self, level: bool) -> serialport::Result<()> {
+        self.inner.write_request_to_send(level)
+    }
+    fn write_data_terminal_ready(&mut self, level: bool) -> serialport::Result<()> {
+        self.inner.write_data_terminal_ready(level)
+    }
+    fn read_clear_to_send(&mut self) -> serialport::Result<bool> {
+        self.inner.read_clear_to_send()
+    }
+    fn read_data_set_ready(&mut self) -> serialport::Result<bool> {
+        self.inner.read_data_set_ready()
+    }
+    fn read_ring_indicator(&mut self) -> serialport::Result<bool> {
+        self.inner.read_ring_indicator()
+    }
+    fn read_carrier_detect(&mut self) -> serialport::Result<bool> {
+        self.inner.read_carrier_detect()
+    }
+    fn bytes_to_read(&self) -> serialport::Result<u32> {
+        self.inner.bytes_to_read()
+    }
+    fn bytes_to_write(&self) -> serialport::Result<u32> {
+        self.inner.bytes_to_write()
+    }
+    fn clear(&self, buffer_to_clear: ClearBuffer) -> serialport::Result<()> {
+        self.inner.clear(buffer_to_clear)
+    }
+    fn try_clone(&self) -> serialport::Result<Box<dyn SerialPort>> {
+        // A clone would bypass the trace.
+        self.inner.try_clone()
+    }
+    fn set_break(&self) -> serialport::Result<()> {
+        self.inner.set_break()
+    }
+    fn clear_break(&self) -> serialport::Result<()> {
+        self.inner.clear_break()
+    }
+}
+
+// ---- Unit tests ----
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trace_lines() {
+        let path = std::env::temp_dir().join(format!("sfu-trace-test-{}.txt", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut trace = TraceWriter::create(path, "/dev/ttyUSB0").unwrap();
+        trace.baud(921600);
+        trace.tx(&[0x45, 0xA3, 0x7E, 0x81]);
+        trace.rx(&[0x0A]);
+        drop(trace);
+
+        let text = std::fs::read_to_string(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[..2], ["# sfu-trace 1", "# port /dev/ttyUSB0"]);
+        assert!(lines[2].starts_with("# start "));
+        let records: Vec<(&str, &str)> = lines[3..].iter().map(|l| l.split_once(' ').unwrap().1.split_once(' ').unwrap()).collect();
+        assert_eq!(records, [("B", "921600"), (">", "45A37E81"), ("<", "0A")]);
+
+        let events: Vec<TraceEvent> = parse_trace(&text).unwrap().into_iter().map(|r| r.event).collect();
+        assert_eq!(events, [TraceEvent::Baud(921600), TraceEvent::Tx(vec![0x45, 0xA3, 0x7E, 0x81]), TraceEvent::Rx(vec![0x0A])]);
+    }
+
+    #[test]
+    fn pcapng_extension_selects_pcapng() {
+        let path = std::env::temp_dir().join(format!("sfu-trace-test-{}.PCAPNG", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut trace = TraceWriter::create(path, "COM5").unwrap();
+        trace.tx(&crate::packet::packet_build(0x97, &[]));
+        drop(trace);
+
+        let data = std::fs::read(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(data[..4], [0x0A, 0x0D, 0x0D, 0x0A]);
+        assert!(String::from_utf8_lossy(&data).contains("SFU_CMD_INFO"));
+    }
+
+    #[test]
+    fn parse_trace_rejects_bad_lines() {
+        assert!(parse_trace("# sfu-trace 2\n").unwrap_err().contains("version"));
+        assert!(parse_trace("10 > 4").unwrap_err().contains("line 1: invalid hex"));
+        assert!(parse_trace("# c\n10 X 00").unwrap_err().contains("line 2: unknown record kind"));
+        assert!(parse_trace("10 <").is_err());
+    }
+}
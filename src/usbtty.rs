@@ -0,0 +1,266 @@
+//! Mapping of a serial tty (e.g. /dev/ttyUSB0) to the USB adapter behind it.
+//!
+//! Linux exposes the full device tree in sysfs:
+//!   /sys/class/tty/ttyUSB0/device -> .../usb1/1-1/1-1.2/1-1.2:1.0/ttyUSB0
+//! The first ancestor with `bInterfaceNumber` is the USB interface, the first
+//! ancestor with `busnum`/`devnum` is the USB device itself. With bus number and
+//! device address the exact adapter can be opened through libusb, so a reset
+//! never drives a different board that happens to use the same bridge chip.
+//!
+//! Other Unix systems have no such tree. There a CP210x or FTDI bridge is used
+//! only when it is the only one attached and has a single serial interface.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use rusb::{Context, DeviceHandle, UsbContext};
+
+#[derive(Debug, Clone)]
+pub struct UsbTtyLocation {
+    /// tty name without /dev prefix, e.g. "ttyUSB0".
+    pub tty: String,
+    /// USB bus number (sysfs `busnum`).
+    pub bus: u8,
+    /// USB device address on the bus (sysfs `devnum`).
+    pub address: u8,
+    /// Topology path, e.g. "1-1.2" (bus 1, hub port 1, port 2).
+    pub devpath: String,
+    pub vid: u16,
+    pub pid: u16,
+    /// Interface number the tty is bound to (multi-port bridges have several).
+    pub interface: u8,
+    /// Kernel driver bound to the interface, e.g. "cp210x", "ftdi_sio"; "no sysfs" off Linux.
+    pub driver: String,
+}
+
+impl UsbTtyLocation {
+    pub fn describe(&self) -> String {
+        format!(
+            "{} -> USB {:04x}:{:04x} bus {} addr {} port {} if {} ({})",
+            self.tty, self.vid, self.pid, self.bus, self.address, self.devpath, self.interface, self.driver
+        )
+    }
+}
+
+/// Resolve serial port path to its USB adapter.
+///
+/// - `Ok(Some(..))` - tty belongs to a USB device and sysfs describes it completely.
+/// - `Ok(None)` - tty exists but is not USB-backed (e.g. on-board ttyS0).
+/// - `Err(..)` - the tty could not be resolved; callers must not guess.
+pub fn locate_usb_tty(port: &str) -> Result<Option<UsbTtyLocation>, String> {
+    #[cfg(target_os = "linux")]
+    {
+        // Resolve /dev/serial/by-id/... style symlinks down to the tty node name.
+        let dev = fs::canonicalize(port).map_err(|e| format!("{port}: {e}"))?;
+        let tty = dev
+            .file_name()
+            .and_then(|n| n.to_str())
+            .ok_or_else(|| format!("{port}: bad tty path {}", dev.display()))?
+            .to_string();
+        locate_in_sysfs(Path::new("/sys"), &tty)
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        single_usb_bridge(port)
+    }
+}
+
+/// Vendors of the bridges with a GPIO backend: Silicon Labs (CP210x) and FTDI.
+#[cfg(not(target_os = "linux"))]
+const BRIDGE_VIDS: [u16; 2] = [0x10c4, 0x0403];
+
+/// Without sysfs the tty can't be tied to its adapter, so a bridge is only assumed
+/// when exactly one is attached; otherwise the reset could drive another board.
+#[cfg(not(target_os = "linux"))]
+fn single_usb_bridge(port: &str) -> Result<Option<UsbTtyLocation>, String> {
+    let unmapped = "tty to USB mapping is only implemented on Linux";
+    let ctx = Context::new().map_err(|e| format!("rusb Context::new failed: {e}"))?;
+    let devices = ctx.devices().map_err(|e| format!("rusb devices() failed: {e}"))?;
+
+    let mut bridges = Vec::new();
+    for dev in devices.iter() {
+        if let Ok(desc) = dev.device_descriptor()
+            && BRIDGE_VIDS.contains(&desc.vendor_id())
+        {
+            bridges.push((dev, desc));
+        }
+    }
+    let (dev, desc) = match bridges.len() {
+        0 => return Err(format!("{unmapped}, and no CP210x or FTDI adapter is attached")),
+        1 => bridges.remove(0),
+        n => return Err(format!("{unmapped}, and {n} CP210x/FTDI adapters are attached")),
+    };
+    let interfaces = dev.active_config_descriptor().map(|c| c.num_interfaces()).unwrap_or(1);
+    if interfaces > 1 {
+        return Err(format!(
+            "{unmapped}, and {:04x}:{:04x} has {interfaces} interfaces, the one behind {port} is unknown",
+            desc.vendor_id(),
+            desc.product_id()
+        ));
+    }
+
+    let ports: Vec<String> = dev.port_numbers().unwrap_or_default().iter().map(|p| p.to_string()).collect();
+    Ok(Some(UsbTtyLocation {
+        tty: Path::new(port).file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_else(|| port.to_string()),
+        bus: dev.bus_number(),
+        address: dev.address(),
+        devpath: format!("{}-{}", dev.bus_number(), ports.join(".")),
+        vid: desc.vendor_id(),
+        pid: desc.product_id(),
+        interface: 0,
+        driver: "no sysfs".to_string(),
+    }))
+}
+
+fn locate_in_sysfs(sysfs: &Path, tty: &str) -> Result<Option<UsbTtyLocation>, String> {
+    let class_dir = sysfs.join("class").join("tty").join(tty);
+    if !class_dir.exists() {
+        return Err(format!("{tty}: not found in {}", class_dir.display()));
+    }
+
+    let device_link = class_dir.join("device");
+    if !device_link.exists() {
+        // Virtual tty (pty, console...) - no hardware behind it.
+        return Ok(None);
+    }
+    let device = fs::canonicalize(&device_link)
+        .map_err(|e| format!("{}: {e}", device_link.display()))?;
+
+    let mut interface_dir: Option<PathBuf> = None;
+    let mut usb_dir: Option<PathBuf> = None;
+    for dir in device.ancestors() {
+        if interface_dir.is_none() && dir.join("bInterfaceNumber").is_file() {
+            interface_dir = Some(dir.to_path_buf());
+        }
+        if dir.join("busnum").is_file() && dir.join("devnum").is_file() {
+            usb_dir = Some(dir.to_path_buf());
+            break;
+        }
+    }
+
+    let usb_dir = match usb_dir {
+        Some(d) => d,
+        None => return Ok(None),
+    };
+    let interface_dir = interface_dir
+        .ok_or_else(|| format!("{tty}: USB device {} has no interface above the tty", usb_dir.display()))?;
+
+    let bus = read_attr(&usb_dir, "busnum")?
+        .parse::<u8>()
+        .map_err(|e| format!("{tty}: bad busnum: {e}"))?;
+    let address = read_attr(&usb_dir, "devnum")?
+        .parse::<u8>()
+        .map_err(|e| format!("{tty}: bad devnum: {e}"))?;
+    let vid = u16::from_str_radix(&read_attr(&usb_dir, "idVendor")?, 16)
+        .map_err(|e| format!("{tty}: bad idVendor: {e}"))?;
+    let pid = u16::from_str_radix(&read_attr(&usb_dir, "idProduct")?, 16)
+        .map_err(|e| format!("{tty}: bad idProduct: {e}"))?;
+    let interface = u8::from_str_radix(&read_attr(&interface_dir, "bInterfaceNumber")?, 16)
+        .map_err(|e| format!("{tty}: bad bInterfaceNumber: {e}"))?;
+
+    let devpath = usb_dir
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or_default()
+        .to_string();
+
+    let driver = fs::read_link(interface_dir.join("driver"))
+        .ok()
+        .and_then(|p| p.file_name().and_then(|n| n.to_str()).map(|s| s.to_string()))
+        .unwrap_or_default();
+
+    Ok(Some(UsbTtyLocation {
+        tty: tty.to_string(),
+        bus,
+        address,
+        devpath,
+        vid,
+        pid,
+        interface,
+        driver,
+    }))
+}
+
+fn read_attr(dir: &Path, name: &str) -> Result<String, String> {
+    let path = dir.join(name);
+    fs::read_to_string(&path)
+        .map(|s| s.trim().to_string())
+        .map_err(|e| format!("{}: {e}", path.display()))
+}
+
+/// Open exactly the USB device described by `loc` (matched by bus number and address).
+pub fn open_usb_device(loc: &UsbTtyLocation) -> Result<DeviceHandle<Context>, String> {
+    let ctx = Context::new()
+        .map_err(|e| format!("rusb Context::new failed: {e}"))?;
+    let devices = ctx
+        .devices()
+        .map_err(|e| format!("rusb devices() failed: {e}"))?;
+
+    for dev in devices.iter() {
+        if dev.bus_number() != loc.bus || dev.address() != loc.address {
+            continue;
+        }
+        let desc = dev
+            .device_descriptor()
+            .map_err(|e| format!("device_descriptor() failed: {e}"))?;
+        if desc.vendor_id() != loc.vid || desc.product_id() != loc.pid {
+            return Err(format!(
+                "USB bus {} addr {} is {:04x}:{:04x}, sysfs says {:04x}:{:04x} (device re-enumerated?)",
+                loc.bus, loc.address, desc.vendor_id(), desc.product_id(), loc.vid, loc.pid
+            ));
+        }
+        return dev
+            .open()
+            .map_err(|e| format!("cannot open USB device {}: {e}", loc.describe()));
+    }
+
+    Err(format!("USB device {} not visible to libusb", loc.describe()))
+}
+
+// ---- Unit tests ----
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    fn write(path: &Path, text: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, text).unwrap();
+    }
+
+    #[test]
+    fn locate_usb_serial_tty_in_fake_sysfs() {
+        let root = std::env::temp_dir().join(format!("sfu-usbtty-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+
+        let usb = root.join("devices/pci0000:00/usb1/1-1/1-1.2");
+        let iface = usb.join("1-1.2:1.0");
+        let port = iface.join("ttyUSB3");
+        write(&usb.join("busnum"), "1\n");
+        write(&usb.join("devnum"), "7\n");
+        write(&usb.join("idVendor"), "10c4\n");
+        write(&usb.join("idProduct"), "ea60\n");
+        write(&iface.join("bInterfaceNumber"), "00\n");
+        fs::create_dir_all(&port).unwrap();
+        fs::create_dir_all(root.join("bus/usb-serial/drivers/cp210x")).unwrap();
+        symlink(root.join("bus/usb-serial/drivers/cp210x"), iface.join("driver")).unwrap();
+
+        let class = root.join("class/tty/ttyUSB3");
+        fs::create_dir_all(&class).unwrap();
+        symlink(&port, class.join("device")).unwrap();
+
+        let loc = locate_in_sysfs(&root, "ttyUSB3").unwrap().unwrap();
+        assert_eq!((loc.bus, loc.address), (1, 7));
+        assert_eq!((loc.vid, loc.pid), (0x10c4, 0xea60));
+        assert_eq!(loc.devpath, "1-1.2");
+        assert_eq!(loc.interface, 0);
+        assert_eq!(loc.driver, "cp210x");
+
+        fs::create_dir_all(root.join("class/tty/ttyS0")).unwrap();
+        assert!(locate_in_sysfs(&root, "ttyS0").unwrap().is_none());
+        assert!(locate_in_sysfs(&root, "ttyUSB9").is_err());
+
+        let _ = fs::remove_dir_all(&root);
+    }
+}
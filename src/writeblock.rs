@@ -0,0 +1,129 @@
+//! Size of the firmware data in each SFU_CMD_WRITE block.
+//!
+//! Blocks are multiples of 256 bytes. The upper bound comes from the device:
+//! the frame must fit `MAX_PACKET_SIZE`, and two frames must fit the
+//! `receive_size` it reported, as the write loop keeps that much headroom. The
+//! size starts at `WR_BLOCK_SIZE` and grows by 256 bytes after a run of clean
+//! acks. A resend or a parser error halves it, once per resend: the acks of the
+//! frames still in flight repeat the corrected address and don't halve it again.
+
+use super::packet::MAX_PACKET_SIZE;
+
+pub const BLOCK_ALIGN: usize = 0x100;
+/// Signature, code, ~code, size, write address and CRC around the data.
+pub const FRAME_OVERHEAD: usize = 16;
+/// Clean acks in a row before the block grows.
+const GROW_AFTER: u32 = 8;
+
+#[derive(Debug, Clone)]
+pub struct WriteBlock {
+    size: usize,
+    max: usize,
+    clean_acks: u32,
+    /// Write address of the ack that last shrank the block.
+    shrunk_at: Option<u32>,
+}
+
+impl WriteBlock {
+    pub fn new(start: usize) -> Self {
+        let max = align_down(MAX_PACKET_SIZE - FRAME_OVERHEAD);
+        WriteBlock { size: start.clamp(BLOCK_ALIGN, max), max, clean_acks: 0, shrunk_at: None }
+    }
+
+    /// Bound the size by the device's receive buffer (SFU INFO `receive_size`).
+    pub fn limit(&mut self, receive_size: usize) {
+        let fits = align_down((receive_size / 2).saturating_sub(FRAME_OVERHEAD + 1));
+        self.max = fits.clamp(BLOCK_ALIGN, align_down(MAX_PACKET_SIZE - FRAME_OVERHEAD));
+        self.size = self.size.min(self.max);
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn frame_len(&self) -> usize {
+        self.size + FRAME_OVERHEAD
+    }
+
+    /// A block was acked without resend or parser errors; `true` when the size grew.
+    pub fn clean_ack(&mut self) -> bool {
+        self.clean_acks += 1;
+        if self.clean_acks < GROW_AFTER || self.size >= self.max {
+            return false;
+        }
+        self.clean_acks = 0;
+        self.size += BLOCK_ALIGN;
+        true
+    }
+
+    /// Resend or parser error in the ack for `mcu_addr`; `true` when the size shrank.
+    pub fn trouble(&mut self, mcu_addr: u32) -> bool {
+        self.clean_acks = 0;
+        if self.shrunk_at == Some(mcu_addr) {
+            return false;
+        }
+        self.shrunk_at = Some(mcu_addr);
+        let smaller = align_down(self.size / 2).max(BLOCK_ALIGN);
+        let shrank = smaller < self.size;
+        self.size = smaller;
+        shrank
+    }
+}
+
+fn align_down(n: usize) -> usize {
+    n / BLOCK_ALIGN * BLOCK_ALIGN
+}
+
+// ---- Unit tests ----
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_from_packet_and_receive_size() {
+        let mut block = WriteBlock::new(0x800);
+        assert_eq!((block.size(), block.max), (0x800, 0xF00));
+        block.limit(0x4000);
+        assert_eq!(block.max, 0xF00);
+        block.limit(0x1000);
+        assert_eq!((block.size(), block.max), (0x700, 0x700));
+        assert!(2 * block.frame_len() < 0x1000);
+        block.limit(0x100);
+        assert_eq!(block.size(), BLOCK_ALIGN);
+    }
+
+    #[test]
+    fn grows_when_clean_and_halves_on_trouble() {
+        let mut block = WriteBlock::new(0x800);
+        assert!(!(1..GROW_AFTER).any(|_| block.clean_ack()));
+        assert!(block.clean_ack());
+        assert_eq!(block.size(), 0x900);
+        block.clean_ack();
+        assert!(block.trouble(0x1000));
+        assert_eq!(block.size(), 0x400);
+        assert!(!(0..GROW_AFTER - 1).any(|_| block.clean_ack()), "trouble restarts the count");
+        let mut addr = 0x2000;
+        while block.trouble(addr) {
+            addr += 0x100;
+        }
+        assert_eq!(block.size(), BLOCK_ALIGN);
+        for _ in 0..100 * GROW_AFTER {
+            block.clean_ack();
+        }
+        assert_eq!(block.size(), 0xF00);
+    }
+
+    #[test]
+    fn one_lost_block_shrinks_once() {
+        let mut block = WriteBlock::new(0x800);
+        // A CRC error at 0x1800, then one ack per frame still in flight, all at 0x1800.
+        assert!(block.trouble(0x1800));
+        assert!(!(0..6).any(|_| block.trouble(0x1800)));
+        assert_eq!(block.size(), 0x400);
+
+        assert!(!block.clean_ack());
+        assert!(block.trouble(0x2000), "a later resend shrinks again");
+        assert_eq!(block.size(), 0x200);
+    }
+}